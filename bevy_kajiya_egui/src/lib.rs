@@ -0,0 +1,218 @@
+use bevy::{
+    input::{
+        keyboard::KeyboardInput,
+        mouse::{MouseButtonInput, MouseScrollUnit, MouseWheel},
+        ButtonState,
+    },
+    prelude::*,
+    window::{CursorMoved, ReceivedCharacter},
+};
+use bevy_kajiya_render::{
+    plugin::{KajiyaRenderAppExt, KajiyaRenderStage, RenderWorld},
+    render_resources::{KajiyaRenderBackend, RenderContext},
+};
+
+mod paint;
+mod renderer;
+
+pub use paint::{EguiCanvas, EguiPaintJobs, EguiUserTexture, EguiUserTextures};
+
+use crate::renderer::{render_egui, EguiRenderer};
+
+/// Draws egui over the kajiya rendered scene.
+///
+/// Build the UI through the [`EguiContext`] resource in any system running during
+/// `CoreStage::Update`.
+#[derive(Default)]
+pub struct KajiyaEguiPlugin;
+
+impl Plugin for KajiyaEguiPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<EguiContext>()
+            .init_resource::<EguiPaintJobs>()
+            .init_resource::<EguiUserTextures>()
+            .add_system_to_stage(CoreStage::PreUpdate, begin_egui_frame)
+            .add_system_to_stage(CoreStage::PostUpdate, end_egui_frame);
+
+        let render_app = app.kajiya_render_app();
+
+        let device = render_app
+            .world
+            .get_resource::<KajiyaRenderBackend>()
+            .unwrap()
+            .render_backend
+            .device
+            .clone();
+        let swapchain_extent = render_app
+            .world
+            .get_resource::<RenderContext>()
+            .unwrap()
+            .swapchain_extent;
+
+        render_app
+            .init_resource::<EguiPaintJobs>()
+            .insert_non_send_resource(EguiRenderer::new(device, swapchain_extent));
+
+        app.add_kajiya_render_system(KajiyaRenderStage::Extract, extract_egui_paint_jobs)
+            .add_kajiya_render_system(KajiyaRenderStage::Prepare, render_egui);
+    }
+}
+
+#[derive(Default)]
+pub struct EguiContext {
+    ctx: egui::CtxRef,
+    pointer_pos: egui::Pos2,
+}
+
+impl EguiContext {
+    pub fn ctx(&self) -> &egui::CtxRef {
+        &self.ctx
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn begin_egui_frame(
+    mut egui_context: ResMut<EguiContext>,
+    windows: Res<Windows>,
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    mut ev_cursor_moved: EventReader<CursorMoved>,
+    mut ev_mouse_button: EventReader<MouseButtonInput>,
+    mut ev_mouse_wheel: EventReader<MouseWheel>,
+    mut ev_received_character: EventReader<ReceivedCharacter>,
+    mut ev_keyboard: EventReader<KeyboardInput>,
+) {
+    let window = windows.get_primary().unwrap();
+
+    let modifiers = egui::Modifiers {
+        alt: keys.any_pressed([KeyCode::LAlt, KeyCode::RAlt]),
+        ctrl: keys.any_pressed([KeyCode::LControl, KeyCode::RControl]),
+        shift: keys.any_pressed([KeyCode::LShift, KeyCode::RShift]),
+        mac_cmd: cfg!(target_os = "macos") && keys.any_pressed([KeyCode::LWin, KeyCode::RWin]),
+        command: if cfg!(target_os = "macos") {
+            keys.any_pressed([KeyCode::LWin, KeyCode::RWin])
+        } else {
+            keys.any_pressed([KeyCode::LControl, KeyCode::RControl])
+        },
+    };
+
+    let mut raw_input = egui::RawInput {
+        screen_rect: Some(egui::Rect::from_min_size(
+            egui::Pos2::default(),
+            egui::vec2(window.width(), window.height()),
+        )),
+        pixels_per_point: Some(window.scale_factor() as f32),
+        time: Some(time.seconds_since_startup()),
+        modifiers,
+        ..Default::default()
+    };
+
+    for ev in ev_cursor_moved.iter() {
+        // Bevy's cursor origin is the bottom left corner of the window, egui's is the top left
+        egui_context.pointer_pos = egui::pos2(ev.position.x, window.height() - ev.position.y);
+        raw_input
+            .events
+            .push(egui::Event::PointerMoved(egui_context.pointer_pos));
+    }
+
+    for ev in ev_mouse_button.iter() {
+        let button = match ev.button {
+            MouseButton::Left => egui::PointerButton::Primary,
+            MouseButton::Right => egui::PointerButton::Secondary,
+            MouseButton::Middle => egui::PointerButton::Middle,
+            MouseButton::Other(_) => continue,
+        };
+
+        raw_input.events.push(egui::Event::PointerButton {
+            pos: egui_context.pointer_pos,
+            button,
+            pressed: ev.state == ButtonState::Pressed,
+            modifiers,
+        });
+    }
+
+    for ev in ev_mouse_wheel.iter() {
+        let line_height = match ev.unit {
+            MouseScrollUnit::Line => 24.0,
+            MouseScrollUnit::Pixel => 1.0,
+        };
+        raw_input.scroll_delta += egui::vec2(ev.x, ev.y) * line_height;
+    }
+
+    for ev in ev_received_character.iter() {
+        if !ev.char.is_control() {
+            raw_input
+                .events
+                .push(egui::Event::Text(ev.char.to_string()));
+        }
+    }
+
+    for ev in ev_keyboard.iter() {
+        if let Some(key) = ev.key_code.and_then(egui_key) {
+            raw_input.events.push(egui::Event::Key {
+                key,
+                pressed: ev.state == ButtonState::Pressed,
+                modifiers,
+            });
+        }
+    }
+
+    egui_context.ctx.begin_frame(raw_input);
+}
+
+fn end_egui_frame(
+    egui_context: Res<EguiContext>,
+    user_textures: Res<EguiUserTextures>,
+    windows: Res<Windows>,
+    mut paint_jobs: ResMut<EguiPaintJobs>,
+) {
+    let (_output, shapes) = egui_context.ctx.end_frame();
+    let window = windows.get_primary().unwrap();
+
+    *paint_jobs = EguiPaintJobs {
+        clipped_meshes: egui_context.ctx.tessellate(shapes),
+        font_texture: Some(egui_context.ctx.texture()),
+        user_textures: user_textures.clone(),
+        pixels_per_point: egui_context.ctx.pixels_per_point(),
+        extent: [window.physical_width(), window.physical_height()],
+    };
+}
+
+fn extract_egui_paint_jobs(
+    mut paint_jobs: ResMut<EguiPaintJobs>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    let mut extracted_paint_jobs = render_world.get_resource_mut::<EguiPaintJobs>().unwrap();
+    *extracted_paint_jobs = std::mem::take(&mut *paint_jobs);
+}
+
+fn egui_key(key_code: KeyCode) -> Option<egui::Key> {
+    let key = match key_code {
+        KeyCode::Down => egui::Key::ArrowDown,
+        KeyCode::Left => egui::Key::ArrowLeft,
+        KeyCode::Right => egui::Key::ArrowRight,
+        KeyCode::Up => egui::Key::ArrowUp,
+        KeyCode::Escape => egui::Key::Escape,
+        KeyCode::Tab => egui::Key::Tab,
+        KeyCode::Back => egui::Key::Backspace,
+        KeyCode::Return => egui::Key::Enter,
+        KeyCode::Space => egui::Key::Space,
+        KeyCode::Insert => egui::Key::Insert,
+        KeyCode::Delete => egui::Key::Delete,
+        KeyCode::Home => egui::Key::Home,
+        KeyCode::End => egui::Key::End,
+        KeyCode::PageUp => egui::Key::PageUp,
+        KeyCode::PageDown => egui::Key::PageDown,
+        KeyCode::A => egui::Key::A,
+        KeyCode::C => egui::Key::C,
+        KeyCode::K => egui::Key::K,
+        KeyCode::U => egui::Key::U,
+        KeyCode::V => egui::Key::V,
+        KeyCode::W => egui::Key::W,
+        KeyCode::X => egui::Key::X,
+        KeyCode::Z => egui::Key::Z,
+        _ => return None,
+    };
+
+    Some(key)
+}
@@ -0,0 +1,465 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use egui::{epaint::Vertex, ClippedMesh, Texture, TextureId};
+
+/// An image drawn by meshes with a [`TextureId::User`], registered in [`EguiUserTextures`].
+#[derive(Clone, Debug)]
+pub struct EguiUserTexture {
+    pub width: usize,
+    pub height: usize,
+    /// sRGBA, not premultiplied, row by row from the top.
+    pub pixels: Vec<[u8; 4]>,
+}
+
+/// The user textures egui meshes can be drawn with, by the id of their [`TextureId::User`].
+#[derive(Clone, Default)]
+pub struct EguiUserTextures(pub HashMap<u64, Arc<EguiUserTexture>>);
+
+/// Tessellated egui output of one frame, ready to be painted.
+#[derive(Clone)]
+pub struct EguiPaintJobs {
+    pub clipped_meshes: Vec<ClippedMesh>,
+    pub font_texture: Option<Arc<Texture>>,
+    pub user_textures: EguiUserTextures,
+    pub pixels_per_point: f32,
+    /// Size of the window in physical pixels.
+    pub extent: [u32; 2],
+}
+
+impl Default for EguiPaintJobs {
+    fn default() -> Self {
+        Self {
+            clipped_meshes: Vec::default(),
+            font_texture: None,
+            user_textures: EguiUserTextures::default(),
+            pixels_per_point: 1.0,
+            extent: [0, 0],
+        }
+    }
+}
+
+impl EguiPaintJobs {
+    pub fn is_empty(&self) -> bool {
+        self.clipped_meshes
+            .iter()
+            .all(|ClippedMesh(_, mesh)| mesh.is_empty())
+    }
+
+    /// Whether the paint jobs draw the same image as `other`, so painting them can be skipped.
+    /// User textures are compared by pointer, modifying one makes a new [`Arc`].
+    pub fn paints_like(&self, other: &Self) -> bool {
+        let font_version = |jobs: &Self| jobs.font_texture.as_ref().map(|texture| texture.version);
+        let same_user_textures = self.user_textures.0.len() == other.user_textures.0.len()
+            && self.user_textures.0.iter().all(|(id, texture)| {
+                other
+                    .user_textures
+                    .0
+                    .get(id)
+                    .map_or(false, |other_texture| Arc::ptr_eq(texture, other_texture))
+            });
+
+        self.extent == other.extent
+            && self.pixels_per_point == other.pixels_per_point
+            && font_version(self) == font_version(other)
+            && same_user_textures
+            && self.clipped_meshes.len() == other.clipped_meshes.len()
+            && self
+                .clipped_meshes
+                .iter()
+                .zip(other.clipped_meshes.iter())
+                .all(
+                    |(ClippedMesh(clip_rect, mesh), ClippedMesh(other_clip_rect, other_mesh))| {
+                        clip_rect == other_clip_rect && mesh == other_mesh
+                    },
+                )
+    }
+}
+
+/// Meshes drawn with user textures missing from [`EguiUserTextures`], which aren't painted.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnknownUserTextures(pub Vec<u64>);
+
+impl fmt::Display for UnknownUserTextures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown egui user textures {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownUserTextures {}
+
+enum Sampler<'a> {
+    /// Meshes of the font texture without the texture, covering everything.
+    Solid,
+    Font(&'a Texture),
+    User(&'a EguiUserTexture),
+}
+
+/// A CPU-side RGBA8 (premultiplied alpha) image the paint jobs are rasterized into.
+pub struct EguiCanvas {
+    pub extent: [u32; 2],
+    pub pixels: Vec<u8>,
+}
+
+impl EguiCanvas {
+    pub fn new(extent: [u32; 2]) -> Self {
+        Self {
+            extent,
+            pixels: vec![0; (extent[0] * extent[1] * 4) as usize],
+        }
+    }
+
+    /// Reallocates the pixels for a new size, cleared.
+    pub fn resize(&mut self, extent: [u32; 2]) {
+        *self = Self::new(extent);
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels.fill(0);
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let idx = ((y * self.extent[0] + x) * 4) as usize;
+        [
+            self.pixels[idx],
+            self.pixels[idx + 1],
+            self.pixels[idx + 2],
+            self.pixels[idx + 3],
+        ]
+    }
+
+    /// Clears the canvas and rasterizes all the paint jobs, in order, on top of each other.
+    ///
+    /// Meshes drawn with an unregistered user texture are skipped, and reported in the error.
+    pub fn paint(&mut self, paint_jobs: &EguiPaintJobs) -> Result<(), UnknownUserTextures> {
+        self.clear();
+
+        let ppp = paint_jobs.pixels_per_point;
+        let mut unknown_textures = Vec::new();
+
+        for ClippedMesh(clip_rect, mesh) in paint_jobs.clipped_meshes.iter() {
+            let sampler = match mesh.texture_id {
+                TextureId::Egui => match paint_jobs.font_texture.as_deref() {
+                    Some(texture) => Sampler::Font(texture),
+                    None => Sampler::Solid,
+                },
+                TextureId::User(id) => match paint_jobs.user_textures.0.get(&id) {
+                    Some(texture) => Sampler::User(texture),
+                    None => {
+                        if !unknown_textures.contains(&id) {
+                            unknown_textures.push(id);
+                        }
+                        continue;
+                    }
+                },
+            };
+
+            let clip = [
+                (clip_rect.min.x * ppp).max(0.0),
+                (clip_rect.min.y * ppp).max(0.0),
+                (clip_rect.max.x * ppp).min(self.extent[0] as f32),
+                (clip_rect.max.y * ppp).min(self.extent[1] as f32),
+            ];
+
+            for triangle in mesh.indices.chunks_exact(3) {
+                let vertices = [
+                    &mesh.vertices[triangle[0] as usize],
+                    &mesh.vertices[triangle[1] as usize],
+                    &mesh.vertices[triangle[2] as usize],
+                ];
+                self.paint_triangle(vertices, ppp, clip, &sampler);
+            }
+        }
+
+        if unknown_textures.is_empty() {
+            Ok(())
+        } else {
+            Err(UnknownUserTextures(unknown_textures))
+        }
+    }
+
+    fn paint_triangle(
+        &mut self,
+        vertices: [&Vertex; 3],
+        ppp: f32,
+        clip: [f32; 4],
+        sampler: &Sampler,
+    ) {
+        fn edge(a: [f32; 2], b: [f32; 2], c: [f32; 2]) -> f32 {
+            (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0])
+        }
+
+        let p = vertices.map(|v| [v.pos.x * ppp, v.pos.y * ppp]);
+
+        // egui doesn't use a consistent winding order, so the signed area normalizes both
+        let area = edge(p[0], p[1], p[2]);
+        if area.abs() < f32::EPSILON {
+            return;
+        }
+
+        let min_x = p[0][0].min(p[1][0]).min(p[2][0]).max(clip[0]).floor() as u32;
+        let min_y = p[0][1].min(p[1][1]).min(p[2][1]).max(clip[1]).floor() as u32;
+        let max_x = p[0][0].max(p[1][0]).max(p[2][0]).min(clip[2]).ceil() as u32;
+        let max_y = p[0][1].max(p[1][1]).max(p[2][1]).min(clip[3]).ceil() as u32;
+
+        let colors = vertices.map(|v| v.color.to_array().map(|c| c as f32 / 255.0));
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                let c = [x as f32 + 0.5, y as f32 + 0.5];
+                let w0 = edge(p[1], p[2], c) / area;
+                let w1 = edge(p[2], p[0], c) / area;
+                let w2 = 1.0 - w0 - w1;
+
+                if w0 < 0.0 || w1 < 0.0 || w2 < 0.0 {
+                    continue;
+                }
+
+                let uv = [w0, w1, w2]
+                    .iter()
+                    .zip(vertices.iter())
+                    .fold(egui::Vec2::ZERO, |uv, (w, v)| uv + *w * v.uv.to_vec2());
+                let texel = match sampler {
+                    Sampler::Solid => [1.0; 4],
+                    Sampler::Font(texture) => [sample_alpha(texture, uv.x, uv.y); 4],
+                    Sampler::User(texture) => sample_rgba(texture, uv.x, uv.y),
+                };
+
+                let mut src = [0.0; 4];
+                for (channel, value) in src.iter_mut().enumerate() {
+                    *value = (w0 * colors[0][channel]
+                        + w1 * colors[1][channel]
+                        + w2 * colors[2][channel])
+                        * texel[channel];
+                }
+
+                self.blend(x, y, src);
+            }
+        }
+    }
+
+    /// Premultiplied "over" blending.
+    fn blend(&mut self, x: u32, y: u32, src: [f32; 4]) {
+        let idx = ((y * self.extent[0] + x) * 4) as usize;
+        let dst = &mut self.pixels[idx..idx + 4];
+
+        for (channel, value) in dst.iter_mut().enumerate() {
+            let blended = src[channel] + (*value as f32 / 255.0) * (1.0 - src[3]);
+            *value = (blended.clamp(0.0, 1.0) * 255.0 + 0.5) as u8;
+        }
+    }
+}
+
+fn sample_alpha(texture: &Texture, u: f32, v: f32) -> f32 {
+    let tx = ((u * texture.width as f32) as usize).min(texture.width - 1);
+    let ty = ((v * texture.height as f32) as usize).min(texture.height - 1);
+
+    texture.pixels[ty * texture.width + tx] as f32 / 255.0
+}
+
+/// Premultiplied color of the texel at `u`, `v`.
+fn sample_rgba(texture: &EguiUserTexture, u: f32, v: f32) -> [f32; 4] {
+    if texture.width == 0 || texture.height == 0 {
+        return [0.0; 4];
+    }
+
+    let tx = ((u * texture.width as f32) as usize).min(texture.width - 1);
+    let ty = ((v * texture.height as f32) as usize).min(texture.height - 1);
+
+    let [r, g, b, a] = texture.pixels[ty * texture.width + tx].map(|c| c as f32 / 255.0);
+    [r * a, g * a, b * a, a]
+}
+
+#[cfg(test)]
+mod tests {
+    use egui::{epaint::Mesh, pos2, Color32, Rect};
+
+    use super::*;
+
+    fn rect(min: [f32; 2], max: [f32; 2]) -> Rect {
+        Rect::from_min_max(pos2(min[0], min[1]), pos2(max[0], max[1]))
+    }
+
+    fn colored_rect(rect: Rect, color: Color32) -> Mesh {
+        let mut mesh = Mesh::default();
+        mesh.add_colored_rect(rect, color);
+        mesh
+    }
+
+    fn paint_jobs(clipped_meshes: Vec<ClippedMesh>) -> EguiPaintJobs {
+        EguiPaintJobs {
+            clipped_meshes,
+            extent: [8, 8],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fills_the_pixels_covered_by_a_mesh() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let mesh = colored_rect(rect([2.0, 2.0], [6.0, 4.0]), Color32::RED);
+        let jobs = paint_jobs(vec![ClippedMesh(rect([0.0, 0.0], [8.0, 8.0]), mesh)]);
+
+        canvas.paint(&jobs).unwrap();
+
+        assert_eq!(canvas.pixel(2, 2), [255, 0, 0, 255]);
+        assert_eq!(canvas.pixel(5, 3), [255, 0, 0, 255]);
+        assert_eq!(canvas.pixel(1, 2), [0; 4]);
+        assert_eq!(canvas.pixel(6, 3), [0; 4]);
+        assert_eq!(canvas.pixel(3, 4), [0; 4]);
+    }
+
+    #[test]
+    fn clips_meshes_to_their_rect() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let mesh = colored_rect(rect([0.0, 0.0], [8.0, 8.0]), Color32::WHITE);
+        let jobs = paint_jobs(vec![ClippedMesh(rect([1.0, 1.0], [3.0, 3.0]), mesh)]);
+
+        canvas.paint(&jobs).unwrap();
+
+        assert_eq!(canvas.pixel(1, 1), [255; 4]);
+        assert_eq!(canvas.pixel(2, 2), [255; 4]);
+        assert_eq!(canvas.pixel(0, 0), [0; 4]);
+        assert_eq!(canvas.pixel(3, 3), [0; 4]);
+    }
+
+    #[test]
+    fn scales_points_to_pixels() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let mesh = colored_rect(rect([0.0, 0.0], [2.0, 2.0]), Color32::WHITE);
+        let jobs = EguiPaintJobs {
+            pixels_per_point: 2.0,
+            ..paint_jobs(vec![ClippedMesh(rect([0.0, 0.0], [4.0, 4.0]), mesh)])
+        };
+
+        canvas.paint(&jobs).unwrap();
+
+        assert_eq!(canvas.pixel(3, 3), [255; 4]);
+        assert_eq!(canvas.pixel(4, 4), [0; 4]);
+    }
+
+    #[test]
+    fn blends_premultiplied_colors_over_each_other() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let clip = rect([0.0, 0.0], [8.0, 8.0]);
+        let bottom = colored_rect(clip, Color32::from_rgba_premultiplied(0, 0, 255, 255));
+        let top = colored_rect(clip, Color32::from_rgba_premultiplied(128, 0, 0, 128));
+        let jobs = paint_jobs(vec![ClippedMesh(clip, bottom), ClippedMesh(clip, top)]);
+
+        canvas.paint(&jobs).unwrap();
+
+        let [r, g, b, a] = canvas.pixel(4, 4);
+        assert_eq!((r, g, a), (128, 0, 255));
+        assert!((126..=128).contains(&b), "{}", b);
+    }
+
+    #[test]
+    fn samples_the_font_texture_coverage() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let mut mesh = Mesh::default();
+        // Left half of the texture is transparent, the right half opaque
+        mesh.add_rect_with_uv(
+            rect([0.0, 0.0], [8.0, 8.0]),
+            rect([0.0, 0.0], [1.0, 1.0]),
+            Color32::WHITE,
+        );
+        let jobs = EguiPaintJobs {
+            font_texture: Some(Arc::new(Texture {
+                version: 0,
+                width: 2,
+                height: 1,
+                pixels: vec![0, 255],
+            })),
+            ..paint_jobs(vec![ClippedMesh(rect([0.0, 0.0], [8.0, 8.0]), mesh)])
+        };
+
+        canvas.paint(&jobs).unwrap();
+
+        assert_eq!(canvas.pixel(1, 4), [0; 4]);
+        assert_eq!(canvas.pixel(6, 4), [255; 4]);
+    }
+
+    #[test]
+    fn draws_user_textures() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let mut mesh = Mesh::with_texture(TextureId::User(7));
+        mesh.add_rect_with_uv(
+            rect([0.0, 0.0], [8.0, 8.0]),
+            rect([0.0, 0.0], [1.0, 1.0]),
+            Color32::WHITE,
+        );
+        let mut jobs = paint_jobs(vec![ClippedMesh(rect([0.0, 0.0], [8.0, 8.0]), mesh)]);
+        jobs.user_textures.0.insert(
+            7,
+            Arc::new(EguiUserTexture {
+                width: 1,
+                height: 2,
+                pixels: vec![[0, 255, 0, 255], [255, 255, 255, 0]],
+            }),
+        );
+
+        canvas.paint(&jobs).unwrap();
+
+        assert_eq!(canvas.pixel(4, 1), [0, 255, 0, 255]);
+        assert_eq!(canvas.pixel(4, 6), [0; 4]);
+    }
+
+    #[test]
+    fn rejects_unknown_user_textures() {
+        let mut canvas = EguiCanvas::new([8, 8]);
+        let clip = rect([0.0, 0.0], [8.0, 8.0]);
+        let mut unknown = Mesh::with_texture(TextureId::User(3));
+        unknown.add_rect_with_uv(clip, rect([0.0, 0.0], [1.0, 1.0]), Color32::WHITE);
+        let solid = colored_rect(rect([0.0, 0.0], [1.0, 1.0]), Color32::WHITE);
+        let jobs = paint_jobs(vec![ClippedMesh(clip, unknown), ClippedMesh(clip, solid)]);
+
+        assert_eq!(canvas.paint(&jobs), Err(UnknownUserTextures(vec![3])));
+        // The other meshes are still painted
+        assert_eq!(canvas.pixel(0, 0), [255; 4]);
+        assert_eq!(canvas.pixel(4, 4), [0; 4]);
+    }
+
+    #[test]
+    fn compares_what_paint_jobs_draw() {
+        let clip = rect([0.0, 0.0], [8.0, 8.0]);
+        let jobs = paint_jobs(vec![ClippedMesh(clip, colored_rect(clip, Color32::RED))]);
+        assert!(jobs.paints_like(&jobs.clone()));
+
+        let recolored = paint_jobs(vec![ClippedMesh(clip, colored_rect(clip, Color32::BLUE))]);
+        assert!(!jobs.paints_like(&recolored));
+
+        let resized = EguiPaintJobs {
+            extent: [16, 8],
+            ..jobs.clone()
+        };
+        assert!(!jobs.paints_like(&resized));
+
+        let texture = Arc::new(EguiUserTexture {
+            width: 1,
+            height: 1,
+            pixels: vec![[255; 4]],
+        });
+        let mut textured = jobs.clone();
+        textured.user_textures.0.insert(1, texture.clone());
+        assert!(!jobs.paints_like(&textured));
+        assert!(textured.paints_like(&textured.clone()));
+
+        let mut modified = textured.clone();
+        modified
+            .user_textures
+            .0
+            .insert(1, Arc::new(EguiUserTexture::clone(&texture)));
+        assert!(!textured.paints_like(&modified));
+    }
+
+    #[test]
+    fn resizing_clears_the_canvas() {
+        let mut canvas = EguiCanvas::new([2, 2]);
+        canvas.pixels.fill(255);
+
+        canvas.resize([4, 3]);
+
+        assert_eq!(canvas.extent, [4, 3]);
+        assert_eq!(canvas.pixels.len(), 4 * 3 * 4);
+        assert!(canvas.pixels.iter().all(|&c| c == 0));
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bevy_kajiya_render::render_resources::KajiyaRenderers;
+use kajiya::{
+    backend::{
+        ash::vk,
+        vulkan::{
+            buffer::{Buffer, BufferDesc},
+            device::Device,
+            image::{Image, ImageDesc},
+        },
+    },
+    ui_renderer::UiRenderCallback,
+};
+
+use crate::paint::{EguiCanvas, EguiPaintJobs};
+
+/// Number of staging buffers, one per frame in flight.
+const STAGING_BUFFER_COUNT: usize = 2;
+
+/// Uploads the rasterized egui canvas into the image composited by kajiya's "final blit".
+///
+/// The canvas is only painted again, and written to the staging buffers, when the paint jobs
+/// draw something new.
+pub struct EguiRenderer {
+    device: Arc<Device>,
+    image: Arc<Image>,
+    staging_buffers: Vec<Buffer>,
+    /// Whether each staging buffer holds the current canvas.
+    staging_up_to_date: [bool; STAGING_BUFFER_COUNT],
+    frame_index: usize,
+    canvas: EguiCanvas,
+    painted: Option<EguiPaintJobs>,
+}
+
+impl EguiRenderer {
+    pub fn new(device: Arc<Device>, extent: [u32; 2]) -> Self {
+        Self {
+            image: Arc::new(create_image(&device, extent)),
+            staging_buffers: create_staging_buffers(&device, extent),
+            staging_up_to_date: [false; STAGING_BUFFER_COUNT],
+            device,
+            frame_index: 0,
+            canvas: EguiCanvas::new(extent),
+            painted: None,
+        }
+    }
+
+    /// Recreates the image and staging buffers for a new window size.
+    fn resize(&mut self, extent: [u32; 2]) {
+        // The previous frames may still be reading the old resources
+        unsafe { self.device.raw.device_wait_idle() }.expect("Failed to wait for the GPU");
+
+        for buffer in self.staging_buffers.drain(..) {
+            self.device.immediate_destroy_buffer(buffer);
+        }
+        self.staging_buffers = create_staging_buffers(&self.device, extent);
+        self.image = Arc::new(create_image(&self.device, extent));
+        self.staging_up_to_date = [false; STAGING_BUFFER_COUNT];
+        self.canvas.resize(extent);
+        self.painted = None;
+        self.frame_index = 0;
+    }
+
+    fn prepare_frame(&mut self, paint_jobs: &EguiPaintJobs) -> (UiRenderCallback, Arc<Image>) {
+        if paint_jobs.extent != self.canvas.extent && !paint_jobs.extent.contains(&0) {
+            self.resize(paint_jobs.extent);
+        }
+
+        let unchanged = self
+            .painted
+            .as_ref()
+            .map_or(false, |painted| painted.paints_like(paint_jobs));
+        if !unchanged {
+            if let Err(e) = self.canvas.paint(paint_jobs) {
+                warn!("Couldn't draw egui meshes: {}", e);
+            }
+            self.painted = Some(paint_jobs.clone());
+            self.staging_up_to_date = [false; STAGING_BUFFER_COUNT];
+        }
+
+        let staging_index = self.frame_index;
+        let staging_buffer = &mut self.staging_buffers[staging_index];
+        self.frame_index = (self.frame_index + 1) % STAGING_BUFFER_COUNT;
+
+        if !self.staging_up_to_date[staging_index] {
+            staging_buffer
+                .allocation
+                .mapped_slice_mut()
+                .unwrap()
+                .copy_from_slice(&self.canvas.pixels);
+            self.staging_up_to_date[staging_index] = true;
+        }
+
+        let raw_device = self.device.raw.clone();
+        let buffer = staging_buffer.raw;
+        let image = self.image.raw;
+        let [width, height] = self.canvas.extent;
+
+        let callback: UiRenderCallback = Box::new(move |cb| unsafe {
+            let subresource_range = vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            };
+
+            // The whole image is overwritten, so its previous contents can be discarded. It is
+            // copied every frame, from a staging buffer only written when the canvas changes
+            raw_device.cmd_pipeline_barrier(
+                cb,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .image(image)
+                    .subresource_range(subresource_range)
+                    .build()],
+            );
+
+            raw_device.cmd_copy_buffer_to_image(
+                cb,
+                buffer,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[vk::BufferImageCopy::builder()
+                    .image_subresource(vk::ImageSubresourceLayers {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: 1,
+                    })
+                    .image_extent(vk::Extent3D {
+                        width,
+                        height,
+                        depth: 1,
+                    })
+                    .build()],
+            );
+
+            // Hand the image back in the layout the render graph expects after the "ui" pass
+            raw_device.cmd_pipeline_barrier(
+                cb,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .image(image)
+                    .subresource_range(subresource_range)
+                    .build()],
+            );
+        });
+
+        (callback, self.image.clone())
+    }
+}
+
+fn create_image(device: &Device, extent: [u32; 2]) -> Image {
+    device
+        .create_image(
+            ImageDesc::new_2d(vk::Format::R8G8B8A8_UNORM, extent).usage(
+                vk::ImageUsageFlags::SAMPLED
+                    | vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+            ),
+            vec![],
+        )
+        .expect("Failed to create egui image")
+}
+
+fn create_staging_buffers(device: &Device, extent: [u32; 2]) -> Vec<Buffer> {
+    (0..STAGING_BUFFER_COUNT)
+        .map(|_| {
+            device
+                .create_buffer(
+                    BufferDesc::new_cpu_to_gpu(
+                        (extent[0] * extent[1] * 4) as usize,
+                        vk::BufferUsageFlags::TRANSFER_SRC,
+                    ),
+                    "egui staging",
+                    None,
+                )
+                .expect("Failed to create egui staging buffer")
+        })
+        .collect()
+}
+
+pub fn render_egui(
+    wr_res: NonSendMut<KajiyaRenderers>,
+    mut egui_renderer: NonSendMut<EguiRenderer>,
+    paint_jobs: Res<EguiPaintJobs>,
+) {
+    if paint_jobs.is_empty() {
+        return;
+    }
+
+    let mut ui_renderer = wr_res.ui_renderer.lock().unwrap();
+    ui_renderer.ui_frame = Some(egui_renderer.prepare_frame(&paint_jobs));
+}
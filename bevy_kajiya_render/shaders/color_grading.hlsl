@@ -0,0 +1,49 @@
+#include "/shaders/inc/samplers.hlsl"
+
+[[vk::binding(0)]] Texture2D<float4> input_tex;
+[[vk::binding(1)]] Texture3D<float4> lut_tex;
+[[vk::binding(2)]] RWTexture2D<float4> output_tex;
+[[vk::binding(3)]] cbuffer _ {
+    float4 input_tex_size;
+    // x: saturation, y: tonemapper, z: LUT strength, w: LUT size
+    float4 grading_params;
+};
+
+#define TONEMAPPER_CONTRAST_CURVE 1
+
+float3 linear_to_srgb(float3 v) {
+    return select(v <= 0.0031308, v * 12.92, 1.055 * pow(max(v, 0.0), 1.0 / 2.4) - 0.055);
+}
+
+float3 srgb_to_linear(float3 v) {
+    return select(v <= 0.04045, v / 12.92, pow(max((v + 0.055) / 1.055, 0.0), 2.4));
+}
+
+// Smooth S-curve with a toe and shoulder, applied to the already tonemapped image.
+float3 contrast_curve(float3 v) {
+    v = saturate(v);
+    return v * v * (3.0 - 2.0 * v);
+}
+
+[numthreads(8, 8, 1)]
+void main(uint2 px: SV_DispatchThreadID) {
+    float4 input = input_tex[px];
+    float3 col = input.rgb;
+
+    const float luminance = dot(col, float3(0.2126, 0.7152, 0.0722));
+    col = max(0.0, lerp(luminance.xxx, col, grading_params.x));
+
+    if (grading_params.y == TONEMAPPER_CONTRAST_CURVE) {
+        col = srgb_to_linear(contrast_curve(linear_to_srgb(col)));
+    }
+
+    if (grading_params.z > 0.0) {
+        // LUTs are authored for sRGB encoded values; sample texel centers
+        const float lut_size = grading_params.w;
+        const float3 uvw = saturate(linear_to_srgb(col)) * ((lut_size - 1.0) / lut_size) + 0.5 / lut_size;
+        const float3 graded = srgb_to_linear(lut_tex.SampleLevel(sampler_lnc, uvw, 0).rgb);
+        col = lerp(col, graded, grading_params.z);
+    }
+
+    output_tex[px] = float4(col, input.a);
+}
@@ -0,0 +1,886 @@
+use std::{
+    ops::{Add, Mul},
+    path::Path,
+    sync::Arc,
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+    dynamic_mesh::KajiyaDynamicMesh,
+    gltf_nodes::KajiyaGltfNode,
+    mesh::{mesh_source_path, KajiyaMeshInstance},
+    runtime_mesh::{KajiyaMeshData, KajiyaMeshMaterial},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KajiyaInterpolation {
+    Step,
+    Linear,
+    /// Values hold an in-tangent, value and out-tangent per keyframe.
+    CubicSpline,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum KajiyaAnimationValues {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>),
+}
+
+/// Animated property of a node, see [`KajiyaAnimationChannel::sample`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KajiyaAnimationValue {
+    Translation(Vec3),
+    Rotation(Quat),
+    Scale(Vec3),
+}
+
+impl KajiyaAnimationValue {
+    pub fn apply(&self, transform: &mut Transform) {
+        match *self {
+            KajiyaAnimationValue::Translation(translation) => transform.translation = translation,
+            KajiyaAnimationValue::Rotation(rotation) => transform.rotation = rotation,
+            KajiyaAnimationValue::Scale(scale) => transform.scale = scale,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaAnimationChannel {
+    /// Index of the animated glTF node.
+    pub node: usize,
+    pub interpolation: KajiyaInterpolation,
+    /// Keyframe times in seconds, in increasing order.
+    pub times: Vec<f32>,
+    pub values: KajiyaAnimationValues,
+}
+
+/// The keyframes around `time`, and the position of `time` between them.
+fn keyframe_segment(times: &[f32], time: f32) -> (usize, usize, f32) {
+    let next = times.partition_point(|key_time| *key_time <= time);
+    if next == 0 {
+        return (0, 0, 0.0);
+    }
+    if next == times.len() {
+        return (next - 1, next - 1, 0.0);
+    }
+
+    let previous = next - 1;
+    let duration = times[next] - times[previous];
+    let factor = if duration > 0.0 {
+        (time - times[previous]) / duration
+    } else {
+        0.0
+    };
+
+    (previous, next, factor)
+}
+
+/// Cubic Hermite spline between `value0` and `value1`, as specified by glTF.
+pub fn cubic_spline<T>(value0: T, out_tangent0: T, in_tangent1: T, value1: T, dt: f32, t: f32) -> T
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let t2 = t * t;
+    let t3 = t2 * t;
+
+    value0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+        + out_tangent0 * ((t3 - 2.0 * t2 + t) * dt)
+        + value1 * (-2.0 * t3 + 3.0 * t2)
+        + in_tangent1 * ((t3 - t2) * dt)
+}
+
+fn sample_values<T>(
+    times: &[f32],
+    values: &[T],
+    interpolation: KajiyaInterpolation,
+    time: f32,
+    linear: impl Fn(T, T, f32) -> T,
+) -> Option<T>
+where
+    T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+{
+    let (previous, next, factor) = keyframe_segment(times, time);
+
+    match interpolation {
+        KajiyaInterpolation::Step => values.get(previous).copied(),
+        KajiyaInterpolation::Linear => {
+            Some(linear(*values.get(previous)?, *values.get(next)?, factor))
+        }
+        KajiyaInterpolation::CubicSpline => {
+            let value0 = *values.get(previous * 3 + 1)?;
+            if previous == next {
+                return Some(value0);
+            }
+
+            Some(cubic_spline(
+                value0,
+                *values.get(previous * 3 + 2)?,
+                *values.get(next * 3)?,
+                *values.get(next * 3 + 1)?,
+                times[next] - times[previous],
+                factor,
+            ))
+        }
+    }
+}
+
+impl KajiyaAnimationChannel {
+    /// The value of the property at `time`, clamped to the keyframes.
+    pub fn sample(&self, time: f32) -> Option<KajiyaAnimationValue> {
+        let (times, interpolation) = (self.times.as_slice(), self.interpolation);
+
+        match &self.values {
+            KajiyaAnimationValues::Translation(values) => {
+                sample_values(times, values, interpolation, time, Vec3::lerp)
+                    .map(KajiyaAnimationValue::Translation)
+            }
+            KajiyaAnimationValues::Rotation(values) => {
+                sample_values(times, values, interpolation, time, Quat::slerp)
+                    .map(|rotation| KajiyaAnimationValue::Rotation(rotation.normalize()))
+            }
+            KajiyaAnimationValues::Scale(values) => {
+                sample_values(times, values, interpolation, time, Vec3::lerp)
+                    .map(KajiyaAnimationValue::Scale)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaAnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<KajiyaAnimationChannel>,
+    /// Time of the last keyframe, in seconds.
+    pub duration: f32,
+}
+
+/// Skinning data of a glTF mesh.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KajiyaSkin {
+    /// glTF node index of each joint.
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<Mat4>,
+    /// Four joint indices and weights per vertex.
+    pub vertex_joints: Vec<[u16; 4]>,
+    pub vertex_weights: Vec<[f32; 4]>,
+}
+
+impl KajiyaSkin {
+    /// Linear blend skinning of `mesh`'s vertices, with the `joint_matrices` of the current
+    /// pose (joint transform times inverse bind matrix). Returns the positions and normals.
+    pub fn skin_vertices(
+        &self,
+        mesh: &KajiyaMeshData,
+        joint_matrices: &[Mat4],
+    ) -> (Vec<[f32; 3]>, Vec<[f32; 3]>) {
+        let mut positions = Vec::with_capacity(mesh.positions.len());
+        let mut normals = Vec::with_capacity(mesh.normals.len());
+
+        for (vertex, position) in mesh.positions.iter().enumerate() {
+            let (joints, weights) = (self.vertex_joints[vertex], self.vertex_weights[vertex]);
+
+            let mut skin_matrix = Mat4::ZERO;
+            for (joint, weight) in joints.into_iter().zip(weights) {
+                if weight != 0.0 {
+                    let joint_matrix = joint_matrices
+                        .get(joint as usize)
+                        .copied()
+                        .unwrap_or(Mat4::IDENTITY);
+                    skin_matrix += joint_matrix * weight;
+                }
+            }
+
+            positions.push(
+                skin_matrix
+                    .transform_point3(Vec3::from(*position))
+                    .to_array(),
+            );
+            if let Some(normal) = mesh.normals.get(vertex) {
+                // Skinning matrices are rigid in practice, so the inverse transpose isn't needed
+                let normal = skin_matrix.transform_vector3(Vec3::from(*normal));
+                normals.push(normal.normalize_or_zero().to_array());
+            }
+        }
+
+        (positions, normals)
+    }
+}
+
+/// Rest pose of a glTF node.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaSkinnedNode {
+    pub name: Option<String>,
+    pub transform: Transform,
+    pub children: Vec<usize>,
+}
+
+/// A skinned glTF mesh with its node hierarchy and animations.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaSkinnedGltfData {
+    pub mesh: KajiyaMeshData,
+    pub skin: KajiyaSkin,
+    pub nodes: Vec<KajiyaSkinnedNode>,
+    /// Nodes at the root of the scene.
+    pub roots: Vec<usize>,
+    pub clips: Vec<KajiyaAnimationClip>,
+}
+
+fn node_transform(node: &gltf::Node) -> Transform {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    Transform {
+        translation: Vec3::from(translation),
+        rotation: Quat::from_array(rotation),
+        scale: Vec3::from(scale),
+    }
+}
+
+/// Reads the first skinned mesh of a glTF scene, its skin and the animations of the file.
+///
+/// Runtime meshes have a single constant material, so meshes whose primitives use different
+/// materials are rejected, and the textures of the material are left out.
+pub fn load_skinned_gltf(path: &Path) -> anyhow::Result<KajiyaSkinnedGltfData> {
+    let gltf = gltf::Gltf::open(path)?;
+    let buffers = gltf::import_buffers(&gltf.document, path.parent(), gltf.blob.clone())?;
+    let get_buffer = |buffer: gltf::Buffer| buffers.get(buffer.index()).map(|data| &data.0[..]);
+
+    let skinned_node = gltf
+        .nodes()
+        .find(|node| node.mesh().is_some() && node.skin().is_some())
+        .ok_or_else(|| anyhow::anyhow!("No skinned mesh in {:?}", path))?;
+    let (gltf_mesh, gltf_skin) = (skinned_node.mesh().unwrap(), skinned_node.skin().unwrap());
+
+    let material = match gltf_mesh.primitives().next() {
+        Some(primitive) => primitive.material(),
+        None => anyhow::bail!("The skinned mesh of {:?} has no primitives", path),
+    };
+    if gltf_mesh
+        .primitives()
+        .any(|primitive| primitive.material().index() != material.index())
+    {
+        anyhow::bail!(
+            "The skinned mesh of {:?} uses several materials, split it into one mesh per material",
+            path
+        );
+    }
+
+    let pbr = material.pbr_metallic_roughness();
+    if pbr.base_color_texture().is_some()
+        || pbr.metallic_roughness_texture().is_some()
+        || material.normal_texture().is_some()
+        || material.emissive_texture().is_some()
+    {
+        log::warn!(
+            "The skinned mesh of {:?} is rendered without the textures of its material",
+            path
+        );
+    }
+
+    let mut mesh = KajiyaMeshData {
+        material: KajiyaMeshMaterial {
+            base_color: pbr.base_color_factor(),
+            roughness: pbr.roughness_factor(),
+            metalness: pbr.metallic_factor(),
+            emissive: material.emissive_factor(),
+        },
+        ..Default::default()
+    };
+    let mut skin = KajiyaSkin {
+        joints: gltf_skin.joints().map(|joint| joint.index()).collect(),
+        inverse_bind_matrices: match gltf_skin.reader(get_buffer).read_inverse_bind_matrices() {
+            Some(matrices) => matrices
+                .map(|matrix| Mat4::from_cols_array_2d(&matrix))
+                .collect(),
+            None => vec![Mat4::IDENTITY; gltf_skin.joints().count()],
+        },
+        ..Default::default()
+    };
+
+    for primitive in gltf_mesh.primitives() {
+        let reader = primitive.reader(get_buffer);
+        let base_vertex = mesh.positions.len() as u32;
+
+        let positions: Vec<[f32; 3]> = reader
+            .read_positions()
+            .ok_or_else(|| anyhow::anyhow!("A primitive of {:?} has no positions", path))?
+            .collect();
+        let vertex_count = positions.len();
+
+        mesh.normals.extend(match reader.read_normals() {
+            Some(normals) => normals.collect(),
+            None => vec![[0.0, 1.0, 0.0]; vertex_count],
+        });
+        mesh.uvs.extend(match reader.read_tex_coords(0) {
+            Some(uvs) => uvs.into_f32().collect(),
+            None => vec![[0.0; 2]; vertex_count],
+        });
+        mesh.colors.extend(match reader.read_colors(0) {
+            Some(colors) => colors.into_rgba_f32().collect(),
+            None => vec![[1.0; 4]; vertex_count],
+        });
+        skin.vertex_joints.extend(match reader.read_joints(0) {
+            Some(joints) => joints.into_u16().collect(),
+            None => vec![[0; 4]; vertex_count],
+        });
+        skin.vertex_weights.extend(match reader.read_weights(0) {
+            Some(weights) => weights.into_f32().collect(),
+            None => vec![[1.0, 0.0, 0.0, 0.0]; vertex_count],
+        });
+        match reader.read_indices() {
+            Some(indices) => mesh
+                .indices
+                .extend(indices.into_u32().map(|index| base_vertex + index)),
+            None => mesh
+                .indices
+                .extend(base_vertex..base_vertex + vertex_count as u32),
+        }
+        mesh.positions.extend(positions);
+    }
+
+    let nodes = gltf
+        .nodes()
+        .map(|node| KajiyaSkinnedNode {
+            name: node.name().map(str::to_string),
+            transform: node_transform(&node),
+            children: node.children().map(|child| child.index()).collect(),
+        })
+        .collect();
+
+    let roots = match gltf.default_scene().or_else(|| gltf.scenes().next()) {
+        Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+        None => Vec::new(),
+    };
+
+    let clips = gltf
+        .animations()
+        .map(|animation| {
+            let channels: Vec<KajiyaAnimationChannel> = animation
+                .channels()
+                .filter_map(|channel| read_animation_channel(&channel, &get_buffer))
+                .collect();
+            let duration = channels
+                .iter()
+                .filter_map(|channel| channel.times.last().copied())
+                .fold(0.0, f32::max);
+
+            KajiyaAnimationClip {
+                name: animation.name().map(str::to_string),
+                channels,
+                duration,
+            }
+        })
+        .collect();
+
+    Ok(KajiyaSkinnedGltfData {
+        mesh,
+        skin,
+        nodes,
+        roots,
+        clips,
+    })
+}
+
+fn read_animation_channel<'a, 's, F>(
+    channel: &gltf::animation::Channel<'a>,
+    get_buffer: &F,
+) -> Option<KajiyaAnimationChannel>
+where
+    F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+{
+    use gltf::animation::{util::ReadOutputs, Interpolation};
+
+    let reader = channel.reader(get_buffer.clone());
+    let times = reader.read_inputs()?.collect();
+    let values = match reader.read_outputs()? {
+        ReadOutputs::Translations(values) => {
+            KajiyaAnimationValues::Translation(values.map(Vec3::from).collect())
+        }
+        ReadOutputs::Rotations(values) => {
+            KajiyaAnimationValues::Rotation(values.into_f32().map(Quat::from_array).collect())
+        }
+        ReadOutputs::Scales(values) => {
+            KajiyaAnimationValues::Scale(values.map(Vec3::from).collect())
+        }
+        // Morph targets aren't supported
+        ReadOutputs::MorphTargetWeights(_) => return None,
+    };
+
+    Some(KajiyaAnimationChannel {
+        node: channel.target().node().index(),
+        interpolation: match channel.sampler().interpolation() {
+            Interpolation::Step => KajiyaInterpolation::Step,
+            Interpolation::Linear => KajiyaInterpolation::Linear,
+            Interpolation::CubicSpline => KajiyaInterpolation::CubicSpline,
+        },
+        times,
+        values,
+    })
+}
+
+/// Spawns a skinned glTF mesh: its nodes become child entities (with a [`KajiyaGltfNode`]),
+/// moved by the [`KajiyaAnimationPlayer`] inserted on this entity, and the mesh is skinned on
+/// the CPU and rendered as a [`KajiyaDynamicMesh`].
+///
+/// The mesh is skinned again when its joints move, and each new pose is uploaded to the
+/// [`KajiyaDynamicMesh`], see it for the cost. Nodes outside of the glTF scene aren't spawned:
+/// their animation channels are skipped and their joints stay in the bind pose.
+#[derive(Component, Clone, Debug, Default)]
+pub struct KajiyaSkinnedGltf {
+    pub mesh: String,
+}
+
+#[derive(Bundle, Default)]
+pub struct KajiyaSkinnedGltfBundle {
+    pub skinned_gltf: KajiyaSkinnedGltf,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+/// Skin of a spawned [`KajiyaSkinnedGltf`], with the entity of each joint, if it was spawned.
+#[derive(Component, Clone, Debug)]
+pub struct KajiyaSkinnedMesh {
+    pub rest_mesh: Arc<KajiyaMeshData>,
+    pub skin: Arc<KajiyaSkin>,
+    pub joints: Vec<Option<Entity>>,
+}
+
+/// Plays the animation clips of a [`KajiyaSkinnedGltf`] on its node entities.
+#[derive(Component, Clone, Debug)]
+pub struct KajiyaAnimationPlayer {
+    pub clips: Arc<Vec<KajiyaAnimationClip>>,
+    /// Entity of each glTF node, if it is part of the scene.
+    pub nodes: Vec<Option<Entity>>,
+    pub playing: Option<usize>,
+    /// Playback position in the current clip, in seconds.
+    pub time: f32,
+    pub speed: f32,
+    pub repeat: bool,
+    pub paused: bool,
+}
+
+impl KajiyaAnimationPlayer {
+    pub fn new(clips: Arc<Vec<KajiyaAnimationClip>>, nodes: Vec<Option<Entity>>) -> Self {
+        Self {
+            clips,
+            nodes,
+            playing: None,
+            time: 0.0,
+            speed: 1.0,
+            repeat: true,
+            paused: false,
+        }
+    }
+
+    /// Plays the clip named `name` from its start. Returns false if there's no such clip.
+    pub fn play(&mut self, name: &str) -> bool {
+        match self
+            .clips
+            .iter()
+            .position(|clip| clip.name.as_deref() == Some(name))
+        {
+            Some(index) => {
+                self.play_index(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn play_index(&mut self, index: usize) {
+        self.playing = Some(index);
+        self.time = 0.0;
+        self.paused = false;
+    }
+
+    pub fn clip(&self) -> Option<&KajiyaAnimationClip> {
+        self.playing.and_then(|index| self.clips.get(index))
+    }
+}
+
+fn spawn_skinned_node(
+    commands: &mut Commands,
+    data: &KajiyaSkinnedGltfData,
+    node: usize,
+    scene: Entity,
+    parent: Entity,
+    node_entities: &mut HashMap<usize, Entity>,
+) {
+    let node_desc = &data.nodes[node];
+
+    let mut entity = commands.spawn_bundle(TransformBundle::from_transform(node_desc.transform));
+    entity.insert(KajiyaGltfNode { scene, index: node });
+    if let Some(name) = node_desc.name.as_ref() {
+        entity.insert(Name::new(name.clone()));
+    }
+    let entity = entity.id();
+
+    commands.entity(parent).add_child(entity);
+    node_entities.insert(node, entity);
+
+    for child in node_desc.children.iter() {
+        spawn_skinned_node(commands, data, *child, scene, entity, node_entities);
+    }
+}
+
+/// Spawns the nodes, mesh and animation player of new or modified [`KajiyaSkinnedGltf`]s,
+/// replacing the previous ones.
+pub fn spawn_skinned_gltfs(
+    mut commands: Commands,
+    query: Query<(Entity, &KajiyaSkinnedGltf, Option<&Children>), Changed<KajiyaSkinnedGltf>>,
+    node_query: Query<&KajiyaGltfNode>,
+    mut meshes: ResMut<Assets<KajiyaMeshData>>,
+) {
+    for (scene_entity, skinned_gltf, children) in query.iter() {
+        for child in children.into_iter().flat_map(|children| children.iter()) {
+            if let Ok(node) = node_query.get(*child) {
+                if node.scene == scene_entity {
+                    commands.entity(*child).despawn_recursive();
+                }
+            }
+        }
+
+        let path = mesh_source_path(&skinned_gltf.mesh);
+        let data = match load_skinned_gltf(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                log::warn!("Couldn't load the skinned mesh {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        let mut node_entities = HashMap::default();
+        for root in data.roots.iter() {
+            spawn_skinned_node(
+                &mut commands,
+                &data,
+                *root,
+                scene_entity,
+                scene_entity,
+                &mut node_entities,
+            );
+        }
+
+        let nodes: Vec<Option<Entity>> = (0..data.nodes.len())
+            .map(|node| node_entities.get(&node).copied())
+            .collect();
+        let joints = data
+            .skin
+            .joints
+            .iter()
+            .map(|joint| nodes.get(*joint).copied().flatten())
+            .collect();
+
+        let rest_mesh = meshes.add(data.mesh.clone());
+
+        commands
+            .entity(scene_entity)
+            .insert(KajiyaMeshInstance::default())
+            .insert(KajiyaDynamicMesh::new(rest_mesh))
+            .insert(KajiyaSkinnedMesh {
+                rest_mesh: Arc::new(data.mesh),
+                skin: Arc::new(data.skin),
+                joints,
+            })
+            .insert(KajiyaAnimationPlayer::new(Arc::new(data.clips), nodes));
+    }
+}
+
+/// Advances the [`KajiyaAnimationPlayer`]s and poses their nodes.
+pub fn advance_animations(
+    time: Res<Time>,
+    mut players: Query<&mut KajiyaAnimationPlayer>,
+    mut transforms: Query<&mut Transform>,
+) {
+    for mut player in players.iter_mut() {
+        let duration = match player.clip() {
+            Some(clip) => clip.duration,
+            None => continue,
+        };
+
+        if !player.paused {
+            let mut clip_time = player.time + time.delta_seconds() * player.speed;
+            if player.repeat && duration > 0.0 {
+                clip_time = clip_time.rem_euclid(duration);
+            } else {
+                clip_time = clip_time.clamp(0.0, duration);
+            }
+            player.time = clip_time;
+        }
+
+        let clip = player.clip().unwrap();
+        for channel in clip.channels.iter() {
+            // Nodes outside of the scene weren't spawned
+            let entity = match player.nodes.get(channel.node) {
+                Some(Some(entity)) => *entity,
+                _ => continue,
+            };
+
+            if let (Some(value), Ok(mut transform)) =
+                (channel.sample(player.time), transforms.get_mut(entity))
+            {
+                // Unchanged poses, ex: when paused, don't skin the mesh again
+                let mut posed = *transform;
+                value.apply(&mut posed);
+                if posed != *transform {
+                    *transform = posed;
+                }
+            }
+        }
+    }
+}
+
+/// Skins the meshes of [`KajiyaSkinnedMesh`]es with the current pose of their joints, and
+/// writes them to their [`KajiyaDynamicMesh`] when the pose changed. Meshes whose joints and
+/// instance didn't move aren't skinned again.
+pub fn skin_meshes(
+    mut query: Query<(
+        &GlobalTransform,
+        ChangeTrackers<GlobalTransform>,
+        &KajiyaSkinnedMesh,
+        &mut KajiyaDynamicMesh,
+    )>,
+    joint_transforms: Query<(&GlobalTransform, ChangeTrackers<GlobalTransform>)>,
+) {
+    for (transform, tracker, skinned_mesh, mut dynamic_mesh) in query.iter_mut() {
+        let joints_moved = skinned_mesh.joints.iter().flatten().any(|joint| {
+            joint_transforms
+                .get(*joint)
+                .map_or(false, |(_, tracker)| tracker.is_changed())
+        });
+        if !tracker.is_changed() && !joints_moved && !dynamic_mesh.positions.is_empty() {
+            continue;
+        }
+
+        // Vertices are skinned in the space of the instance
+        let world_to_mesh = transform.compute_matrix().inverse();
+
+        let joint_matrices: Vec<Mat4> = skinned_mesh
+            .joints
+            .iter()
+            .zip(skinned_mesh.skin.inverse_bind_matrices.iter())
+            .map(|(joint, inverse_bind_matrix)| {
+                // Joints outside of the scene stay in the bind pose
+                match joint.and_then(|joint| joint_transforms.get(joint).ok()) {
+                    Some((joint_transform, _)) => {
+                        world_to_mesh * joint_transform.compute_matrix() * *inverse_bind_matrix
+                    }
+                    None => Mat4::IDENTITY,
+                }
+            })
+            .collect();
+
+        let (positions, normals) = skinned_mesh
+            .skin
+            .skin_vertices(&skinned_mesh.rest_mesh, &joint_matrices);
+
+        if dynamic_mesh.positions != positions {
+            dynamic_mesh.positions = positions;
+            dynamic_mesh.normals = normals;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translation_channel(
+        interpolation: KajiyaInterpolation,
+        times: Vec<f32>,
+        values: Vec<Vec3>,
+    ) -> KajiyaAnimationChannel {
+        KajiyaAnimationChannel {
+            node: 0,
+            interpolation,
+            times,
+            values: KajiyaAnimationValues::Translation(values),
+        }
+    }
+
+    fn sample_translation(channel: &KajiyaAnimationChannel, time: f32) -> Vec3 {
+        match channel.sample(time) {
+            Some(KajiyaAnimationValue::Translation(translation)) => translation,
+            value => panic!("Unexpected sample {:?}", value),
+        }
+    }
+
+    #[test]
+    fn step_sampling_holds_the_previous_keyframe() {
+        let channel = translation_channel(
+            KajiyaInterpolation::Step,
+            vec![0.0, 1.0, 2.0],
+            vec![Vec3::ZERO, Vec3::X, 2.0 * Vec3::X],
+        );
+
+        assert_eq!(sample_translation(&channel, -1.0), Vec3::ZERO);
+        assert_eq!(sample_translation(&channel, 0.5), Vec3::ZERO);
+        assert_eq!(sample_translation(&channel, 1.0), Vec3::X);
+        assert_eq!(sample_translation(&channel, 1.99), Vec3::X);
+        assert_eq!(sample_translation(&channel, 5.0), 2.0 * Vec3::X);
+    }
+
+    #[test]
+    fn linear_sampling_interpolates_and_clamps() {
+        let channel = translation_channel(
+            KajiyaInterpolation::Linear,
+            vec![0.0, 1.0, 3.0],
+            vec![Vec3::ZERO, Vec3::X, 3.0 * Vec3::X],
+        );
+
+        assert_eq!(sample_translation(&channel, -1.0), Vec3::ZERO);
+        assert_eq!(sample_translation(&channel, 0.25), 0.25 * Vec3::X);
+        assert_eq!(sample_translation(&channel, 2.0), 2.0 * Vec3::X);
+        assert_eq!(sample_translation(&channel, 5.0), 3.0 * Vec3::X);
+
+        let rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let channel = KajiyaAnimationChannel {
+            node: 0,
+            interpolation: KajiyaInterpolation::Linear,
+            times: vec![0.0, 1.0],
+            values: KajiyaAnimationValues::Rotation(vec![Quat::IDENTITY, rotation]),
+        };
+        match channel.sample(0.5) {
+            Some(KajiyaAnimationValue::Rotation(sampled)) => {
+                let half = Quat::from_rotation_y(std::f32::consts::FRAC_PI_4);
+                assert!(sampled.abs_diff_eq(half, 1e-5));
+            }
+            value => panic!("Unexpected sample {:?}", value),
+        }
+    }
+
+    #[test]
+    fn cubic_spline_sampling_follows_the_tangents() {
+        // In-tangent, value and out-tangent per keyframe. Tangents matching the slope between
+        // the values reproduce a straight line
+        let channel = translation_channel(
+            KajiyaInterpolation::CubicSpline,
+            vec![0.0, 2.0],
+            vec![
+                0.5 * Vec3::X,
+                Vec3::ZERO,
+                0.5 * Vec3::X,
+                0.5 * Vec3::X,
+                Vec3::X,
+                0.5 * Vec3::X,
+            ],
+        );
+        assert!(sample_translation(&channel, 0.5).abs_diff_eq(0.25 * Vec3::X, 1e-6));
+        assert_eq!(sample_translation(&channel, 0.0), Vec3::ZERO);
+        assert_eq!(sample_translation(&channel, 3.0), Vec3::X);
+
+        // Flat tangents ease in and out, through the middle of the values halfway
+        let channel = translation_channel(
+            KajiyaInterpolation::CubicSpline,
+            vec![0.0, 1.0],
+            vec![
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::ZERO,
+                Vec3::X,
+                Vec3::ZERO,
+            ],
+        );
+        assert!(sample_translation(&channel, 0.5).abs_diff_eq(0.5 * Vec3::X, 1e-6));
+        assert!(sample_translation(&channel, 0.25).x < 0.25);
+    }
+
+    #[test]
+    fn skinning_blends_the_joint_matrices() {
+        let mesh = KajiyaMeshData {
+            positions: vec![[0.0, 1.0, 0.0], [0.0, 2.0, 0.0]],
+            normals: vec![[0.0, 0.0, 1.0], [0.0, 0.0, 1.0]],
+            ..Default::default()
+        };
+        let skin = KajiyaSkin {
+            joints: vec![0, 1],
+            inverse_bind_matrices: vec![Mat4::IDENTITY; 2],
+            vertex_joints: vec![[0, 0, 0, 0], [0, 1, 0, 0]],
+            vertex_weights: vec![[1.0, 0.0, 0.0, 0.0], [0.5, 0.5, 0.0, 0.0]],
+        };
+        let joint_matrices = [
+            Mat4::from_rotation_y(std::f32::consts::FRAC_PI_2),
+            Mat4::from_translation(Vec3::new(0.0, 0.0, 4.0)),
+        ];
+
+        let (positions, normals) = skin.skin_vertices(&mesh, &joint_matrices);
+
+        // Fully bound to the rotating joint
+        assert!(Vec3::from(positions[0]).abs_diff_eq(Vec3::Y, 1e-6));
+        assert!(Vec3::from(normals[0]).abs_diff_eq(Vec3::X, 1e-6));
+
+        // Halfway between the rotated and the translated vertex
+        assert!(Vec3::from(positions[1]).abs_diff_eq(Vec3::new(0.0, 2.0, 2.0), 1e-6));
+        let normal = Vec3::from(normals[1]);
+        assert!(normal.abs_diff_eq(Vec3::new(1.0, 0.0, 1.0).normalize(), 1e-6));
+    }
+
+    /// A triangle skinned to its own node, as two primitives using the `materials` indices.
+    fn write_skinned_gltf(name: &str, materials: [usize; 2]) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("bevy_kajiya_skin_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let bytes: Vec<u8> = positions
+            .iter()
+            .flatten()
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        std::fs::write(dir.join("skin.bin"), &bytes).unwrap();
+
+        let path = dir.join("skin.gltf");
+        std::fs::write(
+            &path,
+            format!(
+                r#"{{
+                    "asset": {{"version": "2.0"}},
+                    "buffers": [{{"byteLength": 36, "uri": "skin.bin"}}],
+                    "bufferViews": [{{"buffer": 0, "byteLength": 36}}],
+                    "accessors": [
+                        {{"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                         "min": [0, 0, 0], "max": [1, 1, 0]}}
+                    ],
+                    "materials": [
+                        {{"pbrMetallicRoughness": {{"baseColorFactor": [1, 0, 0, 1]}}}},
+                        {{"pbrMetallicRoughness": {{"baseColorFactor": [0, 1, 0, 1]}}}}
+                    ],
+                    "meshes": [{{"primitives": [
+                        {{"attributes": {{"POSITION": 0}}, "material": {}}},
+                        {{"attributes": {{"POSITION": 0}}, "material": {}}}
+                    ]}}],
+                    "skins": [{{"joints": [0]}}],
+                    "nodes": [{{"mesh": 0, "skin": 0}}],
+                    "scenes": [{{"nodes": [0]}}],
+                    "scene": 0
+                }}"#,
+                materials[0], materials[1]
+            ),
+        )
+        .unwrap();
+
+        path
+    }
+
+    #[test]
+    fn skinned_gltf_keeps_its_material() {
+        let path = write_skinned_gltf("one_material", [1, 1]);
+
+        let skinned = load_skinned_gltf(&path).unwrap();
+
+        assert_eq!(skinned.mesh.positions.len(), 6);
+        assert_eq!(skinned.mesh.indices, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(skinned.mesh.material.base_color, [0.0, 1.0, 0.0, 1.0]);
+        assert_eq!(skinned.skin.joints, [0]);
+    }
+
+    #[test]
+    fn skinned_gltf_with_several_materials_is_rejected() {
+        let path = write_skinned_gltf("two_materials", [0, 1]);
+
+        let error = load_skinned_gltf(&path).unwrap_err();
+
+        assert!(error.to_string().contains("several materials"));
+    }
+}
@@ -6,7 +6,7 @@ use bevy::{
 };
 use serde::Deserialize;
 
-use crate::plugin::RenderWorld;
+use crate::{mesh::parse_mesh_variant, plugin::RenderWorld};
 
 #[derive(Clone, Debug, Deserialize, TypeUuid, Hash, PartialEq, Eq)]
 #[uuid = "39cadc56-aa9c-4543-8640-a018b74b5052"]
@@ -71,6 +71,7 @@ pub fn register_unique_gltf_asset(
     name: &String,
 ) {
     let mut state = render_world.get_resource_mut::<MeshAssetsState>().unwrap();
+    let (name, _, _) = parse_mesh_variant(name);
 
     if !state.unique_gltf_assets.contains(name) {
         let _handle: Handle<GltfMeshAsset>;
@@ -0,0 +1,234 @@
+use std::{
+    borrow::Cow,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    lod::KajiyaLod,
+    mesh::{Aabb, KajiyaMeshInstance},
+    runtime_mesh::{runtime_mesh_name, KajiyaMeshData},
+};
+
+/// Local-space bounds of a glTF scene, from the position accessor bounds of its primitives and
+/// the node transforms, as baked by kajiya.
+pub fn gltf_scene_bounds(path: &Path) -> anyhow::Result<Aabb> {
+    let gltf = gltf::Gltf::open(path)?;
+    let scene = gltf
+        .default_scene()
+        .or_else(|| gltf.scenes().next())
+        .ok_or_else(|| anyhow::anyhow!("No scene in {:?}", path))?;
+
+    fn visit_node(node: gltf::Node, parent_transform: Mat4, min: &mut Vec3, max: &mut Vec3) {
+        let transform = parent_transform * Mat4::from_cols_array_2d(&node.transform().matrix());
+
+        if let Some(mesh) = node.mesh() {
+            for primitive in mesh.primitives() {
+                let bounds = primitive.bounding_box();
+                let (bounds_min, bounds_max) = (Vec3::from(bounds.min), Vec3::from(bounds.max));
+
+                for corner in 0..8 {
+                    let corner = Vec3::select(
+                        BVec3::new(corner & 1 != 0, corner & 2 != 0, corner & 4 != 0),
+                        bounds_max,
+                        bounds_min,
+                    );
+                    let corner = transform.transform_point3(corner);
+                    *min = min.min(corner);
+                    *max = max.max(corner);
+                }
+            }
+        }
+
+        for child in node.children() {
+            visit_node(child, transform, min, max);
+        }
+    }
+
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    for node in scene.nodes() {
+        visit_node(node, Mat4::IDENTITY, &mut min, &mut max);
+    }
+
+    if min.cmpgt(max).any() {
+        anyhow::bail!("No mesh in {:?}", path);
+    }
+
+    Ok(Aabb::from_min_max(min, max))
+}
+
+#[derive(Default)]
+pub struct MeshBounds {
+    pub bounds: HashMap<String, Aabb>,
+    /// Meshes whose bounds changed since they were last attached to instances.
+    pub updated: HashSet<String>,
+}
+
+/// Bounds of the loaded meshes, by mesh name, shared between the render world which computes
+/// them when baking and the app world which attaches them to instances.
+#[derive(Clone, Default)]
+pub struct SharedMeshBounds(pub Arc<Mutex<MeshBounds>>);
+
+impl SharedMeshBounds {
+    pub fn get(&self, mesh: &str) -> Option<Aabb> {
+        self.0.lock().unwrap().bounds.get(mesh).cloned()
+    }
+
+    pub fn insert(&self, mesh: String, bounds: Aabb) {
+        let mut mesh_bounds = self.0.lock().unwrap();
+        mesh_bounds.updated.insert(mesh.clone());
+        mesh_bounds.bounds.insert(mesh, bounds);
+    }
+}
+
+/// The meshes an instance may render: every level of its [`KajiyaLod`], its runtime mesh or its
+/// mesh.
+fn instance_meshes<'a>(
+    mesh_instance: &'a KajiyaMeshInstance,
+    runtime_mesh: Option<&Handle<KajiyaMeshData>>,
+    lod: Option<&'a KajiyaLod>,
+) -> Vec<Cow<'a, str>> {
+    match (lod, runtime_mesh) {
+        (Some(lod), _) if !lod.levels.is_empty() => lod
+            .levels
+            .iter()
+            .map(|level| Cow::Borrowed(level.mesh.as_str()))
+            .collect(),
+        (_, Some(runtime_mesh)) => vec![Cow::Owned(runtime_mesh_name(runtime_mesh))],
+        _ => vec![Cow::Borrowed(mesh_instance.mesh.as_str())],
+    }
+}
+
+/// Inserts the bounds of their mesh as an [`Aabb`] on [`KajiyaMeshInstance`] entities, once the
+/// mesh is loaded, and keeps it up to date when the mesh changes or is hot reloaded. Only new and
+/// changed instances, and the instances of meshes whose bounds changed, are updated.
+///
+/// Instances with a [`KajiyaLod`] get the bounds enclosing every loaded level, so they don't
+/// depend on the level picked by the render world.
+pub fn update_instance_bounds(
+    mut commands: Commands,
+    mesh_bounds: Res<SharedMeshBounds>,
+    query: Query<(
+        Entity,
+        &KajiyaMeshInstance,
+        Option<&Aabb>,
+        Option<&Handle<KajiyaMeshData>>,
+        Option<&KajiyaLod>,
+    )>,
+    changed_instances: Query<
+        Entity,
+        (
+            With<KajiyaMeshInstance>,
+            Or<(
+                Changed<KajiyaMeshInstance>,
+                Changed<Handle<KajiyaMeshData>>,
+                Changed<KajiyaLod>,
+            )>,
+        ),
+    >,
+) {
+    let mut mesh_bounds = mesh_bounds.0.lock().unwrap();
+    let updated = std::mem::take(&mut mesh_bounds.updated);
+
+    let mut update_bounds = |entity: Entity, meshes: &[Cow<str>], aabb: Option<&Aabb>| {
+        let bounds = meshes
+            .iter()
+            .filter_map(|mesh| mesh_bounds.bounds.get(mesh.as_ref()))
+            .fold(None, |union: Option<Aabb>, bounds| match union {
+                Some(union) => Some(Aabb::from_min_max(
+                    union.min().min(bounds.min()),
+                    union.max().max(bounds.max()),
+                )),
+                None => Some(bounds.clone()),
+            });
+
+        if let Some(bounds) = bounds {
+            if aabb != Some(&bounds) {
+                commands.entity(entity).insert(bounds);
+            }
+        }
+    };
+
+    if updated.is_empty() {
+        for entity in changed_instances.iter() {
+            if let Ok((entity, mesh_instance, aabb, runtime_mesh, lod)) = query.get(entity) {
+                let meshes = instance_meshes(mesh_instance, runtime_mesh, lod);
+                update_bounds(entity, &meshes, aabb);
+            }
+        }
+    } else {
+        for (entity, mesh_instance, aabb, runtime_mesh, lod) in query.iter() {
+            let meshes = instance_meshes(mesh_instance, runtime_mesh, lod);
+            let mesh_updated = meshes.iter().any(|mesh| updated.contains(mesh.as_ref()));
+            if mesh_updated || changed_instances.get(entity).is_ok() {
+                update_bounds(entity, &meshes, aabb);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scene_bounds_follow_the_node_transforms() {
+        let dir = std::env::temp_dir().join(format!("bevy_kajiya_bounds_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("scene.bin"), [0u8; 36]).unwrap();
+
+        // A triangle in a child node translated by (10, 0, 0) and scaled by 2, under a root
+        // translated by (0, 5, 0), and a node outside the scene
+        let path = dir.join("scene.gltf");
+        std::fs::write(
+            &path,
+            r#"{
+                "asset": {"version": "2.0"},
+                "buffers": [{"byteLength": 36, "uri": "scene.bin"}],
+                "bufferViews": [{"buffer": 0, "byteLength": 36}],
+                "accessors": [
+                    {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                     "min": [0, 0, -1], "max": [1, 1, 0]}
+                ],
+                "meshes": [{"primitives": [{"attributes": {"POSITION": 0}}]}],
+                "nodes": [
+                    {"translation": [0, 5, 0], "children": [1]},
+                    {"mesh": 0, "translation": [10, 0, 0], "scale": [2, 2, 2]},
+                    {"mesh": 0, "translation": [100, 0, 0]}
+                ],
+                "scenes": [{"nodes": [0]}],
+                "scene": 0
+            }"#,
+        )
+        .unwrap();
+
+        let bounds = gltf_scene_bounds(&path).unwrap();
+
+        assert_eq!(bounds.min(), Vec3::new(10.0, 5.0, -2.0));
+        assert_eq!(bounds.max(), Vec3::new(12.0, 7.0, 0.0));
+    }
+
+    #[test]
+    fn scenes_without_meshes_have_no_bounds() {
+        let dir = std::env::temp_dir().join(format!("bevy_kajiya_empty_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("scene.gltf");
+        std::fs::write(
+            &path,
+            r#"{
+                "asset": {"version": "2.0"},
+                "nodes": [{"translation": [1, 0, 0]}],
+                "scenes": [{"nodes": [0]}]
+            }"#,
+        )
+        .unwrap();
+
+        assert!(gltf_scene_bounds(&path).is_err());
+    }
+}
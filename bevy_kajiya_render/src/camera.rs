@@ -1,10 +1,47 @@
 use bevy::prelude::*;
 use glam::{Quat, Vec3};
-use kajiya::camera::{CameraBodyMatrices, CameraLens, CameraLensMatrices, IntoCameraBodyMatrices};
+use kajiya::camera::{
+    CameraBodyMatrices, CameraLensMatrices, CameraMatrices, IntoCameraBodyMatrices,
+};
 
 use kajiya::math;
 
-use crate::{plugin::RenderWorld, world_renderer::SunState};
+use crate::{
+    environment_map::KajiyaEnvironmentMap,
+    plugin::RenderWorld,
+    post_process::KajiyaPostProcess,
+    sun::{KajiyaSun, SUN_ANGULAR_DIAMETER},
+    world_renderer::SunState,
+};
+
+/// How the view is projected onto the screen.
+///
+/// kajiya's renderer is built around perspective views. An orthographic projection rasterizes
+/// the geometry as expected, but kajiya's ray-traced and screen-space passes are written for a
+/// perspective eye and haven't been checked against it, so shadows, reflections and global
+/// illumination may be off. Temporal reprojection also ghosts while the view moves or switches
+/// projection; setting [`crate::KajiyaDescriptor::temporal_upsampling`] to `1.0` limits it to a
+/// few frames.
+#[derive(Reflect, Copy, Clone, Debug, PartialEq)]
+#[reflect_value(PartialEq)]
+pub enum KajiyaProjection {
+    /// Infinite reverse-Z perspective, using the camera's `vertical_fov` and
+    /// `near_plane_distance`.
+    Perspective,
+    /// Reverse-Z orthographic projection.
+    Orthographic {
+        /// Height of the view volume, in world units.
+        vertical_size: f32,
+        near: f32,
+        far: f32,
+    },
+}
+
+impl Default for KajiyaProjection {
+    fn default() -> Self {
+        KajiyaProjection::Perspective
+    }
+}
 
 #[derive(Component, Reflect, Copy, Clone)]
 #[reflect(Component)]
@@ -12,6 +49,7 @@ pub struct KajiyaCamera {
     pub vertical_fov: f32,
     pub near_plane_distance: f32,
     pub aspect_ratio: f32,
+    pub projection: KajiyaProjection,
 }
 
 impl KajiyaCamera {
@@ -20,6 +58,7 @@ impl KajiyaCamera {
             near_plane_distance: 0.01,
             aspect_ratio: 1.0,
             vertical_fov: 52.0,
+            projection: KajiyaProjection::Perspective,
         }
     }
 }
@@ -33,40 +72,105 @@ impl FromWorld for KajiyaCamera {
             near_plane_distance: 0.01,
             aspect_ratio: window.requested_width() / window.requested_height(),
             vertical_fov: 52.0,
+            projection: KajiyaProjection::Perspective,
         }
     }
 }
 
-impl KajiyaCamera {
-    fn calc_matrices(self) -> CameraLensMatrices {
-        let cam_lens = CameraLens {
-            aspect_ratio: self.aspect_ratio,
-            vertical_fov: self.vertical_fov,
-            near_plane_distance: self.near_plane_distance,
-        };
-        let fov = cam_lens.vertical_fov.to_radians();
-        let znear = cam_lens.near_plane_distance;
+/// Infinite reverse-Z perspective projection, as built by kajiya's `CameraLens`.
+pub fn perspective_matrices(
+    vertical_fov: f32,
+    aspect_ratio: f32,
+    znear: f32,
+) -> CameraLensMatrices {
+    let fov = vertical_fov.to_radians();
 
-        let h = (0.5 * fov).cos() / (0.5 * fov).sin();
-        let w = h / cam_lens.aspect_ratio;
+    let h = (0.5 * fov).cos() / (0.5 * fov).sin();
+    let w = h / aspect_ratio;
 
-        let view_to_clip = math::Mat4::from_cols(
-            math::Vec4::new(w, 0.0, 0.0, 0.0),
-            math::Vec4::new(0.0, h, 0.0, 0.0),
-            math::Vec4::new(0.0, 0.0, 0.0, -1.0),
-            math::Vec4::new(0.0, 0.0, znear, 0.0),
-        );
+    let view_to_clip = math::Mat4::from_cols(
+        math::Vec4::new(w, 0.0, 0.0, 0.0),
+        math::Vec4::new(0.0, h, 0.0, 0.0),
+        math::Vec4::new(0.0, 0.0, 0.0, -1.0),
+        math::Vec4::new(0.0, 0.0, znear, 0.0),
+    );
 
-        let clip_to_view = math::Mat4::from_cols(
-            math::Vec4::new(1.0 / w, 0.0, 0.0, 0.0),
-            math::Vec4::new(0.0, 1.0 / h, 0.0, 0.0),
-            math::Vec4::new(0.0, 0.0, 0.0, 1.0 / znear),
-            math::Vec4::new(0.0, 0.0, -1.0, 0.0),
-        );
+    let clip_to_view = math::Mat4::from_cols(
+        math::Vec4::new(1.0 / w, 0.0, 0.0, 0.0),
+        math::Vec4::new(0.0, 1.0 / h, 0.0, 0.0),
+        math::Vec4::new(0.0, 0.0, 0.0, 1.0 / znear),
+        math::Vec4::new(0.0, 0.0, -1.0, 0.0),
+    );
 
-        CameraLensMatrices {
+    CameraLensMatrices {
+        view_to_clip,
+        clip_to_view,
+    }
+}
+
+/// Reverse-Z orthographic projection: `near` maps to a depth of 1 and `far` to 0.
+pub fn orthographic_matrices(
+    vertical_size: f32,
+    aspect_ratio: f32,
+    near: f32,
+    far: f32,
+) -> CameraLensMatrices {
+    let half_height = 0.5 * vertical_size;
+    let half_width = half_height * aspect_ratio;
+    let depth_range = far - near;
+
+    let view_to_clip = math::Mat4::from_cols(
+        math::Vec4::new(1.0 / half_width, 0.0, 0.0, 0.0),
+        math::Vec4::new(0.0, 1.0 / half_height, 0.0, 0.0),
+        math::Vec4::new(0.0, 0.0, 1.0 / depth_range, 0.0),
+        math::Vec4::new(0.0, 0.0, far / depth_range, 1.0),
+    );
+
+    let clip_to_view = math::Mat4::from_cols(
+        math::Vec4::new(half_width, 0.0, 0.0, 0.0),
+        math::Vec4::new(0.0, half_height, 0.0, 0.0),
+        math::Vec4::new(0.0, 0.0, depth_range, 0.0),
+        math::Vec4::new(0.0, 0.0, -far, 1.0),
+    );
+
+    CameraLensMatrices {
+        view_to_clip,
+        clip_to_view,
+    }
+}
+
+impl KajiyaCamera {
+    fn calc_matrices(self) -> CameraLensMatrices {
+        match self.projection {
+            KajiyaProjection::Perspective => perspective_matrices(
+                self.vertical_fov,
+                self.aspect_ratio,
+                self.near_plane_distance,
+            ),
+            KajiyaProjection::Orthographic {
+                vertical_size,
+                near,
+                far,
+            } => orthographic_matrices(vertical_size, self.aspect_ratio, near, far),
+        }
+    }
+
+    /// Builds the full set of camera matrices for the given camera position and rotation.
+    pub fn camera_matrices(&self, transform: (Vec3, Quat)) -> CameraMatrices {
+        let CameraLensMatrices {
+            view_to_clip,
+            clip_to_view,
+        } = self.calc_matrices();
+        let CameraBodyMatrices {
+            world_to_view,
+            view_to_world,
+        } = transform.into_camera_body_matrices();
+
+        CameraMatrices {
             view_to_clip,
             clip_to_view,
+            world_to_view,
+            view_to_world,
         }
     }
 
@@ -99,16 +203,105 @@ impl KajiyaCamera {
     }
 }
 
-#[derive(Component, Reflect, Copy, Clone)]
+/// Height of a full frame (35mm) sensor, in millimeters.
+pub const FULL_FRAME_SENSOR_HEIGHT: f32 = 24.0;
+
+/// Vertical field of view, in degrees, of a lens of the given focal length.
+pub fn focal_length_to_vertical_fov(focal_length: f32, sensor_height: f32) -> f32 {
+    (2.0 * (0.5 * sensor_height / focal_length).atan()).to_degrees()
+}
+
+/// Focal length giving the vertical field of view, in degrees, on the given sensor.
+pub fn vertical_fov_to_focal_length(vertical_fov: f32, sensor_height: f32) -> f32 {
+    0.5 * sensor_height / (0.5 * vertical_fov.to_radians()).tan()
+}
+
+/// Exposure value at ISO 100 of the given camera settings.
+pub fn exposure_settings_to_ev100(aperture: f32, shutter_speed: f32, iso: f32) -> f32 {
+    (aperture * aperture / shutter_speed * 100.0 / iso).log2()
+}
+
+/// Physical camera parameters, as an alternative to setting up [`KajiyaCamera`] directly.
+///
+/// When added to the camera entity, the focal length and sensor size drive
+/// [`KajiyaCamera::vertical_fov`], and the aperture, shutter speed and ISO drive the exposure,
+/// overriding [`KajiyaPostProcess::exposure`].
+///
+/// kajiya has no depth of field or motion blur, so `focus_distance` and `shutter_speed` don't
+/// blur the image; they are kept for exposure and for tools reading the camera.
+#[derive(Component, Reflect, Copy, Clone, Debug)]
+#[reflect(Component)]
+pub struct KajiyaPhysicalCamera {
+    /// Focal length, in millimeters.
+    pub focal_length: f32,
+    /// Sensor height, in millimeters.
+    pub sensor_height: f32,
+    /// Aperture, in f-stops.
+    pub aperture: f32,
+    /// Distance to the focal plane, in meters.
+    pub focus_distance: f32,
+    /// Shutter speed, in seconds.
+    pub shutter_speed: f32,
+    pub iso: f32,
+}
+
+impl Default for KajiyaPhysicalCamera {
+    fn default() -> Self {
+        // "Sunny 16" settings
+        Self {
+            focal_length: 50.0,
+            sensor_height: FULL_FRAME_SENSOR_HEIGHT,
+            aperture: 16.0,
+            focus_distance: 10.0,
+            shutter_speed: 1.0 / 100.0,
+            iso: 100.0,
+        }
+    }
+}
+
+impl KajiyaPhysicalCamera {
+    pub fn vertical_fov(&self) -> f32 {
+        focal_length_to_vertical_fov(self.focal_length, self.sensor_height)
+    }
+
+    pub fn set_vertical_fov(&mut self, vertical_fov: f32) {
+        self.focal_length = vertical_fov_to_focal_length(vertical_fov, self.sensor_height);
+    }
+
+    pub fn ev100(&self) -> f32 {
+        exposure_settings_to_ev100(self.aperture, self.shutter_speed, self.iso)
+    }
+}
+
+pub fn apply_physical_camera(
+    mut query: Query<(&mut KajiyaCamera, &KajiyaPhysicalCamera), Changed<KajiyaPhysicalCamera>>,
+) {
+    for (mut camera, physical_camera) in query.iter_mut() {
+        camera.vertical_fov = physical_camera.vertical_fov();
+    }
+}
+
+#[derive(Component, Reflect, Clone)]
 #[reflect(Component)]
 pub struct EnvironmentSettings {
+    /// Direction towards the sun in degrees, see [`SunState`]. Overridden by a [`KajiyaSun`].
     pub sun_theta_phi: (f32, f32),
+    /// Equirectangular image lighting the scene instead of the procedural sky.
+    #[reflect(ignore)]
+    pub environment_map: Option<Handle<KajiyaEnvironmentMap>>,
+    /// Rotation of the environment map around the vertical axis, in degrees.
+    pub environment_rotation: f32,
+    /// Multiplier of the environment map radiance.
+    pub environment_intensity: f32,
 }
 
 impl Default for EnvironmentSettings {
     fn default() -> Self {
         EnvironmentSettings {
             sun_theta_phi: (90.0, 0.0),
+            environment_map: None,
+            environment_rotation: 0.0,
+            environment_intensity: 1.0,
         }
     }
 }
@@ -132,15 +325,28 @@ impl Default for KajiyaCameraBundle {
     }
 }
 
-#[derive(Default)]
 pub struct ExtractedEnvironment {
     pub sun_theta_phi: SunState,
+    pub sun_color_multiplier: Vec3,
+    pub sun_size_multiplier: f32,
+}
+
+impl Default for ExtractedEnvironment {
+    fn default() -> Self {
+        Self {
+            sun_theta_phi: SunState::default(),
+            sun_color_multiplier: Vec3::ONE,
+            sun_size_multiplier: 1.0,
+        }
+    }
 }
 
 pub struct ExtractedCamera {
     pub camera: KajiyaCamera,
     pub transform: (Vec3, Quat),
     pub environment: ExtractedEnvironment,
+    pub post_process: Option<KajiyaPostProcess>,
+    pub physical_camera: Option<KajiyaPhysicalCamera>,
 }
 
 impl Default for ExtractedCamera {
@@ -149,17 +355,28 @@ impl Default for ExtractedCamera {
             camera: KajiyaCamera::default(),
             transform: Default::default(),
             environment: Default::default(),
+            post_process: None,
+            physical_camera: None,
         }
     }
 }
 
 pub fn extract_camera(
-    query: Query<(&KajiyaCamera, &Transform, &EnvironmentSettings)>,
+    query: Query<(
+        &KajiyaCamera,
+        &Transform,
+        &EnvironmentSettings,
+        Option<&KajiyaPostProcess>,
+        Option<&KajiyaPhysicalCamera>,
+    )>,
+    sun_query: Query<(&KajiyaSun, Option<&GlobalTransform>)>,
     mut render_world: ResMut<RenderWorld>,
 ) {
     let mut extracted_camera = render_world.get_resource_mut::<ExtractedCamera>().unwrap();
 
-    if let Some((camera, transform, environment_settings)) = query.iter().next() {
+    if let Some((camera, transform, environment_settings, post_process, physical_camera)) =
+        query.iter().next()
+    {
         let pos = transform.translation;
         let rot = transform.rotation;
 
@@ -169,8 +386,86 @@ pub fn extract_camera(
         extracted_camera.camera = *camera;
         extracted_camera.transform = (extracted_pos, extracted_rot);
         let (theta, phi) = environment_settings.sun_theta_phi;
-        extracted_camera.environment = ExtractedEnvironment {
-            sun_theta_phi: SunState { theta, phi },
+        extracted_camera.environment = match sun_query.iter().next() {
+            Some((sun, transform)) => {
+                let color = sun.color * sun.intensity;
+                ExtractedEnvironment {
+                    sun_theta_phi: SunState::from_direction(Vec3::from(
+                        sun.sun_direction(transform).to_array(),
+                    )),
+                    sun_color_multiplier: Vec3::from(color.to_array()),
+                    sun_size_multiplier: sun.angular_diameter / SUN_ANGULAR_DIAMETER,
+                }
+            }
+            None => ExtractedEnvironment {
+                sun_theta_phi: SunState { theta, phi },
+                ..Default::default()
+            },
         };
+        extracted_camera.post_process = post_process.cloned();
+        extracted_camera.physical_camera = physical_camera.copied();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn focal_length_round_trips_through_the_fov() {
+        for focal_length in [14.0, 35.0, 50.0, 85.0, 200.0] {
+            let fov = focal_length_to_vertical_fov(focal_length, FULL_FRAME_SENSOR_HEIGHT);
+            let round_trip = vertical_fov_to_focal_length(fov, FULL_FRAME_SENSOR_HEIGHT);
+            assert!((round_trip - focal_length).abs() < 1e-3 * focal_length);
+        }
+
+        // A lens as long as twice the sensor height sees atan(1/4) either way
+        let fov = focal_length_to_vertical_fov(48.0, FULL_FRAME_SENSOR_HEIGHT);
+        assert!((fov - 2.0 * 0.25f32.atan().to_degrees()).abs() < 1e-4);
+    }
+
+    fn assert_inverses(matrices: &CameraLensMatrices) {
+        let identity = matrices.view_to_clip * matrices.clip_to_view;
+        assert!(
+            identity.abs_diff_eq(math::Mat4::IDENTITY, 1e-5),
+            "{:?} is not the identity",
+            identity
+        );
+    }
+
+    #[test]
+    fn perspective_inverse() {
+        let matrices = perspective_matrices(52.0, 16.0 / 9.0, 0.01);
+        assert_inverses(&matrices);
+
+        // Reverse-Z: the near plane at a depth of 1, infinity at 0
+        let near = matrices.view_to_clip * math::Vec4::new(0.0, 0.0, -0.01, 1.0);
+        assert!((near.z / near.w - 1.0).abs() < 1e-6);
+        let far = matrices.view_to_clip * math::Vec4::new(0.0, 0.0, -1e6, 1.0);
+        assert!((far.z / far.w).abs() < 1e-6);
+    }
+
+    #[test]
+    fn orthographic_inverse() {
+        let matrices = orthographic_matrices(10.0, 16.0 / 9.0, 0.5, 100.0);
+        assert_inverses(&matrices);
+
+        let near = matrices.view_to_clip * math::Vec4::new(0.0, 5.0, -0.5, 1.0);
+        assert!((near.z - 1.0).abs() < 1e-6 && (near.y - 1.0).abs() < 1e-6);
+        let far = matrices.view_to_clip * math::Vec4::new(0.0, 0.0, -100.0, 1.0);
+        assert!(far.z.abs() < 1e-6);
+    }
+
+    #[test]
+    fn ev100_of_known_settings() {
+        // f/1, 1 s, ISO 100 is the definition of EV 0
+        assert_eq!(exposure_settings_to_ev100(1.0, 1.0, 100.0), 0.0);
+        // Each doubling of the ISO lowers the EV100 by a stop
+        assert!((exposure_settings_to_ev100(1.0, 1.0, 200.0) + 1.0).abs() < 1e-6);
+        // f/2 lets in a quarter of the light of f/1
+        assert!((exposure_settings_to_ev100(2.0, 1.0, 100.0) - 2.0).abs() < 1e-6);
+        // "Sunny 16": f/16, 1/100 s at ISO 100 is about EV 15
+        let sunny_16 = KajiyaPhysicalCamera::default().ev100();
+        assert!((sunny_16 - 14.64).abs() < 0.01);
     }
 }
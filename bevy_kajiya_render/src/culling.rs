@@ -0,0 +1,249 @@
+use bevy::{
+    math::{Mat4, Vec3, Vec3A, Vec4},
+    prelude::*,
+    utils::HashSet,
+};
+
+use crate::{
+    camera::ExtractedCamera,
+    diagnostics::KajiyaRenderStats,
+    mesh::{Aabb, MeshInstanceExtracted, MeshTransform},
+    plugin::RenderWorld,
+};
+
+/// Which instances are kept in kajiya's scene.
+///
+/// Off-screen geometry still casts shadows and contributes to GI and reflections, so pure
+/// frustum culling makes lighting pop as the camera turns.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KajiyaCullingPolicy {
+    /// Every instance is rendered.
+    Disabled,
+    /// Instances further than their [`KajiyaMeshInstance::max_draw_distance`] are removed.
+    ///
+    /// [`KajiyaMeshInstance::max_draw_distance`]: crate::KajiyaMeshInstance::max_draw_distance
+    Distance,
+    /// Instances outside the view frustum are removed too, unless they are within `keep_radius`
+    /// of the camera.
+    Frustum { keep_radius: f32 },
+}
+
+impl Default for KajiyaCullingPolicy {
+    fn default() -> Self {
+        KajiyaCullingPolicy::Disabled
+    }
+}
+
+/// Culling settings, extracted every frame.
+#[derive(Clone, Debug, Default)]
+pub struct KajiyaCulling {
+    pub policy: KajiyaCullingPolicy,
+}
+
+#[derive(Default)]
+pub struct ExtractedCulling(pub KajiyaCulling);
+
+pub fn extract_culling(culling: Res<KajiyaCulling>, mut render_world: ResMut<RenderWorld>) {
+    render_world
+        .get_resource_mut::<ExtractedCulling>()
+        .unwrap()
+        .0 = culling.clone();
+}
+
+/// Instances removed from kajiya's scene this frame.
+#[derive(Default)]
+pub struct CulledInstances(pub HashSet<Entity>);
+
+/// Planes of a view frustum, with their normals pointing inside.
+#[derive(Clone, Debug)]
+pub struct KajiyaFrustum {
+    pub planes: Vec<Vec4>,
+}
+
+impl KajiyaFrustum {
+    /// Extracts the planes of a reverse-Z `view_to_clip * world_to_view` matrix. Degenerate
+    /// planes, like the far plane of an infinite projection, are skipped.
+    pub fn from_clip_from_world(clip_from_world: &Mat4) -> Self {
+        let row = |idx| clip_from_world.row(idx);
+        let candidates = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            // Reverse-Z: the near plane is at a depth of 1 and the far plane at 0
+            row(3) - row(2),
+            row(2),
+        ];
+
+        let planes = candidates
+            .into_iter()
+            .filter_map(|plane| {
+                let normal_length = plane.truncate().length();
+                (normal_length > f32::EPSILON).then(|| plane / normal_length)
+            })
+            .collect();
+
+        Self { planes }
+    }
+
+    /// Tests a box, given in the local space of `model`, against the frustum.
+    pub fn intersects_obb(&self, aabb: &Aabb, model: &Mat4) -> bool {
+        let center = model.transform_point3a(aabb.center.into());
+        let axes = [
+            Vec3A::from(model.x_axis.truncate()),
+            Vec3A::from(model.y_axis.truncate()),
+            Vec3A::from(model.z_axis.truncate()),
+        ];
+
+        self.planes.iter().all(|plane| {
+            let normal = Vec3A::from(plane.truncate());
+            normal.dot(center) + plane.w + aabb.relative_radius(&normal, &axes) > 0.0
+        })
+    }
+}
+
+fn mesh_transform_matrix(transform: &MeshTransform) -> Mat4 {
+    Mat4::from_cols_array(&glam::Mat4::from(transform.affine).to_cols_array())
+}
+
+/// Decides which extracted instances are removed from kajiya's scene, see [`KajiyaCulling`].
+pub fn cull_instances(
+    query_extracted_instances: Query<&MeshInstanceExtracted>,
+    extracted_camera: Res<ExtractedCamera>,
+    culling: Res<ExtractedCulling>,
+    mut culled_instances: ResMut<CulledInstances>,
+    mut stats: ResMut<KajiyaRenderStats>,
+) {
+    kajiya_profile_scope!("cull_instances");
+
+    culled_instances.0.clear();
+
+    let keep_radius = match culling.0.policy {
+        KajiyaCullingPolicy::Disabled => {
+            stats.culled_instances = 0;
+            return;
+        }
+        KajiyaCullingPolicy::Distance => None,
+        KajiyaCullingPolicy::Frustum { keep_radius } => Some(keep_radius),
+    };
+
+    let matrices = extracted_camera
+        .camera
+        .camera_matrices(extracted_camera.transform);
+    let clip_from_world =
+        Mat4::from_cols_array(&(matrices.view_to_clip * matrices.world_to_view).to_cols_array());
+    let frustum = KajiyaFrustum::from_clip_from_world(&clip_from_world);
+    let camera_position = Vec3::from(extracted_camera.transform.0.to_array());
+
+    for extracted_instance in query_extracted_instances.iter() {
+        let model = mesh_transform_matrix(&extracted_instance.transform);
+
+        // Without bounds, the instance is treated as a point at its origin for the distance,
+        // and isn't frustum culled
+        let (center, radius) = match extracted_instance.aabb.as_ref() {
+            Some(aabb) => {
+                let half_extents = aabb.half_extents;
+                let radius = (model.x_axis.truncate() * half_extents.x).length()
+                    + (model.y_axis.truncate() * half_extents.y).length()
+                    + (model.z_axis.truncate() * half_extents.z).length();
+                (model.transform_point3(aabb.center), radius)
+            }
+            None => (model.transform_point3(Vec3::ZERO), 0.0),
+        };
+        let distance = (center.distance(camera_position) - radius).max(0.0);
+
+        let too_far = distance > extracted_instance.max_draw_distance;
+        let outside_frustum = match (keep_radius, extracted_instance.aabb.as_ref()) {
+            (Some(keep_radius), Some(aabb)) => {
+                distance > keep_radius && !frustum.intersects_obb(aabb, &model)
+            }
+            _ => false,
+        };
+
+        if too_far || outside_frustum {
+            culled_instances
+                .0
+                .insert(extracted_instance.instance_entity);
+        }
+    }
+
+    stats.culled_instances = culled_instances.0.len();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::FRAC_PI_2;
+
+    use super::*;
+
+    /// Looking down -Z from the origin, with a 90 degree field of view.
+    fn infinite_frustum() -> KajiyaFrustum {
+        KajiyaFrustum::from_clip_from_world(&Mat4::perspective_infinite_reverse_rh(
+            FRAC_PI_2, 1.0, 0.1,
+        ))
+    }
+
+    fn unit_box() -> Aabb {
+        Aabb::from_min_max(Vec3::splat(-0.5), Vec3::splat(0.5))
+    }
+
+    #[test]
+    fn infinite_projections_have_no_far_plane() {
+        let frustum = infinite_frustum();
+
+        assert_eq!(frustum.planes.len(), 5);
+        for plane in &frustum.planes {
+            assert!((plane.truncate().length() - 1.0).abs() < 1e-6);
+            // Normals point inside
+            assert!(plane.truncate().dot(Vec3::new(0.0, 0.0, -10.0)) + plane.w > 0.0);
+        }
+    }
+
+    #[test]
+    fn reverse_z_far_plane_is_kept() {
+        // Reverse-Z: the near and far distances are swapped
+        let frustum =
+            KajiyaFrustum::from_clip_from_world(&Mat4::perspective_rh(FRAC_PI_2, 1.0, 100.0, 0.1));
+
+        assert_eq!(frustum.planes.len(), 6);
+        let inside = Mat4::from_translation(Vec3::new(0.0, 0.0, -50.0));
+        assert!(frustum.intersects_obb(&unit_box(), &inside));
+        let beyond = Mat4::from_translation(Vec3::new(0.0, 0.0, -150.0));
+        assert!(!frustum.intersects_obb(&unit_box(), &beyond));
+    }
+
+    #[test]
+    fn boxes_outside_the_frustum_are_rejected() {
+        let frustum = infinite_frustum();
+        let at = |x, z| Mat4::from_translation(Vec3::new(x, 0.0, z));
+
+        assert!(frustum.intersects_obb(&unit_box(), &at(0.0, -10.0)));
+        assert!(!frustum.intersects_obb(&unit_box(), &at(0.0, 10.0)));
+        // The frustum is 20 units wide at a distance of 10
+        assert!(frustum.intersects_obb(&unit_box(), &at(10.4, -10.0)));
+        assert!(!frustum.intersects_obb(&unit_box(), &at(20.0, -10.0)));
+    }
+
+    #[test]
+    fn transformed_boxes_use_their_oriented_extents() {
+        let frustum = infinite_frustum();
+
+        // Stretched along X until it reaches into the frustum
+        let stretched = Mat4::from_translation(Vec3::new(20.0, 0.0, -10.0))
+            * Mat4::from_scale(Vec3::new(25.0, 1.0, 1.0));
+        assert!(frustum.intersects_obb(&unit_box(), &stretched));
+
+        // The same box turned upright stays outside
+        let turned = Mat4::from_translation(Vec3::new(20.0, 0.0, -10.0))
+            * Mat4::from_rotation_z(FRAC_PI_2)
+            * Mat4::from_scale(Vec3::new(25.0, 1.0, 1.0));
+        assert!(!frustum.intersects_obb(&unit_box(), &turned));
+
+        // An offset center is transformed with the box
+        let offset = Aabb::from_min_max(Vec3::new(-20.5, -0.5, -0.5), Vec3::new(-19.5, 0.5, 0.5));
+        assert!(frustum.intersects_obb(
+            &offset,
+            &Mat4::from_translation(Vec3::new(20.0, 0.0, -10.0))
+        ));
+    }
+}
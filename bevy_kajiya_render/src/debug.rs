@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use kajiya::{rg::GraphDebugHook, world_renderer::RenderDebugMode};
+
+use crate::{plugin::RenderWorld, render_resources::KajiyaRenderers};
+
+/// Debug visualizations supported by kajiya's world renderer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KajiyaDebugMode {
+    /// Regular shading.
+    None,
+    /// Lighting without the base color (albedo) applied.
+    NoBaseColor,
+    /// Diffuse global illumination only.
+    DiffuseGi,
+    /// Specular reflections only.
+    Reflections,
+    /// Rasterized shading without any ray-traced effects.
+    RtxOff,
+    /// Contents of the irradiance cache.
+    IrradianceCache,
+    /// Scene geometry shaded with the world radiance cache.
+    WorldRadianceCache,
+}
+
+impl Default for KajiyaDebugMode {
+    fn default() -> Self {
+        KajiyaDebugMode::None
+    }
+}
+
+impl KajiyaDebugMode {
+    /// Index into kajiya's `debug_shading_mode` list.
+    pub fn shading_mode(self) -> usize {
+        match self {
+            KajiyaDebugMode::None | KajiyaDebugMode::WorldRadianceCache => 0,
+            KajiyaDebugMode::NoBaseColor => 1,
+            KajiyaDebugMode::DiffuseGi => 2,
+            KajiyaDebugMode::Reflections => 3,
+            KajiyaDebugMode::RtxOff => 4,
+            KajiyaDebugMode::IrradianceCache => 5,
+        }
+    }
+
+    fn render_debug_mode(self) -> RenderDebugMode {
+        match self {
+            KajiyaDebugMode::WorldRadianceCache => RenderDebugMode::WorldRadianceCache,
+            _ => RenderDebugMode::None,
+        }
+    }
+}
+
+/// Selects what the renderer displays instead of the final image.
+///
+/// Insert or modify this resource in the app world; it is extracted every frame.
+#[derive(Debug, Clone, Default)]
+pub struct KajiyaDebugView {
+    pub mode: KajiyaDebugMode,
+    /// Name of a render graph pass (ex: "final blit") whose output is shown on screen.
+    /// The available passes are listed in [`crate::KajiyaRenderStats`].
+    pub pass: Option<String>,
+}
+
+#[derive(Default)]
+pub struct ExtractedDebugView(pub KajiyaDebugView);
+
+pub fn extract_debug_view(debug_view: Res<KajiyaDebugView>, mut render_world: ResMut<RenderWorld>) {
+    let mut extracted_debug_view = render_world
+        .get_resource_mut::<ExtractedDebugView>()
+        .unwrap();
+
+    extracted_debug_view.0 = debug_view.clone();
+}
+
+pub fn apply_debug_view(
+    wr_res: NonSendMut<KajiyaRenderers>,
+    extracted_debug_view: Res<ExtractedDebugView>,
+) {
+    let mut world_renderer = wr_res.world_renderer.lock().unwrap();
+    let debug_view = &extracted_debug_view.0;
+
+    world_renderer.debug_mode = debug_view.mode.render_debug_mode();
+    world_renderer.debug_shading_mode = debug_view.mode.shading_mode();
+
+    // `render_frame` takes the hook every frame, so it has to be set again each frame
+    world_renderer.rg_debug_hook = debug_view
+        .pass
+        .as_ref()
+        .map(|name| GraphDebugHook { name: name.clone() });
+}
@@ -0,0 +1,235 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::{
+    diagnostic::{Diagnostic, DiagnosticId, Diagnostics},
+    prelude::*,
+};
+use kajiya::backend::gpu_profiler;
+
+use crate::{
+    render_instances::{LoadedMeshesMap, RenderInstancesMap, RenderMesh, WRInstance},
+    render_resources::RenderContext,
+};
+
+/// Statistics gathered by the render sub-app over the last frame.
+#[derive(Clone, Debug, Default)]
+pub struct KajiyaRenderStats {
+    /// Filtered render loop frame time, in seconds.
+    pub frame_time: f32,
+    /// Render graph passes and their GPU time in milliseconds, in execution order.
+    pub gpu_passes: Vec<(String, f64)>,
+    pub instance_count: usize,
+    /// Instances removed from the scene by [`KajiyaCulling`](crate::KajiyaCulling).
+    pub culled_instances: usize,
+    pub loaded_mesh_count: usize,
+    pub pending_bakes: usize,
+    pub command_queue_len: usize,
+    pub cache_hits: usize,
+    pub cache_misses: usize,
+}
+
+impl KajiyaRenderStats {
+    /// Sum of the GPU times of the profiled render graph passes, in milliseconds.
+    ///
+    /// This isn't the GPU frame time: work outside of the profiled passes, and the idle time
+    /// between them, isn't counted.
+    pub fn gpu_passes_time(&self) -> f64 {
+        self.gpu_passes.iter().map(|(_, ms)| ms).sum()
+    }
+
+    /// Ratio of mesh loads served from the baked mesh cache, or `None` before the first load.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let total = self.cache_hits + self.cache_misses;
+        if total == 0 {
+            None
+        } else {
+            Some(self.cache_hits as f64 / total as f64)
+        }
+    }
+}
+
+/// [`KajiyaRenderStats`] shared between the app world and the render world.
+#[derive(Clone, Default)]
+pub struct SharedKajiyaRenderStats(pub Arc<Mutex<KajiyaRenderStats>>);
+
+impl SharedKajiyaRenderStats {
+    pub fn get(&self) -> KajiyaRenderStats {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+pub fn collect_render_stats(
+    mut stats: ResMut<KajiyaRenderStats>,
+    shared_stats: Res<SharedKajiyaRenderStats>,
+    render_ctx: Res<RenderContext>,
+    ri_map: Res<RenderInstancesMap>,
+    lm_map: Res<LoadedMeshesMap>,
+) {
+    stats.frame_time = render_ctx.delta_seconds;
+    stats.gpu_passes = gpu_profiler::get_stats().get_ordered_name_ms();
+    stats.instance_count = ri_map
+        .values()
+        .filter(|ri| matches!(ri.instance, WRInstance::Ready(_)))
+        .count();
+    stats.loaded_mesh_count = lm_map
+        .values()
+        .filter(|mesh| matches!(mesh, RenderMesh::Ready(_)))
+        .count();
+    stats.pending_bakes = lm_map
+        .values()
+        .filter(|mesh| matches!(mesh, RenderMesh::Empty | RenderMesh::Queued(..)))
+        .count();
+
+    *shared_stats.0.lock().unwrap() = stats.clone();
+}
+
+/// Adds renderer statistics to [`Diagnostics`].
+#[derive(Default)]
+pub struct KajiyaDiagnosticsPlugin;
+
+impl Plugin for KajiyaDiagnosticsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(Self::setup_system)
+            .add_system(Self::diagnostic_system);
+    }
+}
+
+/// Diagnostic ids are UUIDs of the form `6b61_6a69_7961_xxxx_xxxx_xxxx_xxxx_xxxx`, the first 48
+/// bits spelling "kajiya" in ASCII. The next 16 bits are the kind of diagnostic, and the last 64
+/// bits its index: the position of the statistic for [`DIAGNOSTIC_KIND_STAT`], or the FNV-1a hash
+/// of the pass name for [`DIAGNOSTIC_KIND_PASS`].
+const DIAGNOSTIC_NAMESPACE: u128 = 0x6b61_6a69_7961 << 80;
+pub const DIAGNOSTIC_KIND_STAT: u16 = 0;
+pub const DIAGNOSTIC_KIND_PASS: u16 = 1;
+
+pub const fn kajiya_diagnostic_id(kind: u16, index: u64) -> DiagnosticId {
+    DiagnosticId::from_u128(DIAGNOSTIC_NAMESPACE | (kind as u128) << 64 | index as u128)
+}
+
+/// 64-bit FNV-1a, which unlike `DefaultHasher` is stable across builds.
+const fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+        i += 1;
+    }
+    hash
+}
+
+impl KajiyaDiagnosticsPlugin {
+    pub const FRAME_TIME: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 0);
+    /// See [`KajiyaRenderStats::gpu_passes_time`].
+    pub const GPU_PASSES_TIME: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 1);
+    pub const INSTANCE_COUNT: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 2);
+    pub const CULLED_INSTANCES: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 3);
+    pub const LOADED_MESH_COUNT: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 4);
+    pub const PENDING_BAKES: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 5);
+    pub const COMMAND_QUEUE_LEN: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 6);
+    pub const CACHE_HIT_RATE: DiagnosticId = kajiya_diagnostic_id(DIAGNOSTIC_KIND_STAT, 7);
+
+    const HISTORY_LENGTH: usize = 20;
+
+    /// The id of the diagnostic tracking the GPU time of the render graph pass `name`.
+    pub fn pass_diagnostic_id(name: &str) -> DiagnosticId {
+        kajiya_diagnostic_id(DIAGNOSTIC_KIND_PASS, fnv1a(name.as_bytes()))
+    }
+
+    fn setup_system(mut diagnostics: ResMut<Diagnostics>) {
+        let mut add = |id, name: &'static str, suffix: &'static str| {
+            diagnostics.add(Diagnostic::new(id, name, Self::HISTORY_LENGTH).with_suffix(suffix));
+        };
+
+        add(Self::FRAME_TIME, "kajiya_frame_time", "ms");
+        add(Self::GPU_PASSES_TIME, "kajiya_gpu_passes_time", "ms");
+        add(Self::INSTANCE_COUNT, "kajiya_instance_count", "");
+        add(Self::CULLED_INSTANCES, "kajiya_culled_instances", "");
+        add(Self::LOADED_MESH_COUNT, "kajiya_loaded_mesh_count", "");
+        add(Self::PENDING_BAKES, "kajiya_pending_bakes", "");
+        add(Self::COMMAND_QUEUE_LEN, "kajiya_command_queue_len", "");
+        add(Self::CACHE_HIT_RATE, "kajiya_cache_hit_rate", "%");
+    }
+
+    fn diagnostic_system(
+        mut diagnostics: ResMut<Diagnostics>,
+        shared_stats: Res<SharedKajiyaRenderStats>,
+    ) {
+        let stats = shared_stats.get();
+
+        diagnostics.add_measurement(Self::FRAME_TIME, stats.frame_time as f64 * 1000.0);
+        diagnostics.add_measurement(Self::GPU_PASSES_TIME, stats.gpu_passes_time());
+        diagnostics.add_measurement(Self::INSTANCE_COUNT, stats.instance_count as f64);
+        diagnostics.add_measurement(Self::CULLED_INSTANCES, stats.culled_instances as f64);
+        diagnostics.add_measurement(Self::LOADED_MESH_COUNT, stats.loaded_mesh_count as f64);
+        diagnostics.add_measurement(Self::PENDING_BAKES, stats.pending_bakes as f64);
+        diagnostics.add_measurement(Self::COMMAND_QUEUE_LEN, stats.command_queue_len as f64);
+        if let Some(hit_rate) = stats.cache_hit_rate() {
+            diagnostics.add_measurement(Self::CACHE_HIT_RATE, hit_rate * 100.0);
+        }
+
+        for (name, ms) in stats.gpu_passes.iter() {
+            let id = Self::pass_diagnostic_id(name);
+            if diagnostics.get(id).is_none() {
+                diagnostics.add(
+                    Diagnostic::new(id, format!("kajiya_pass/{}", name), Self::HISTORY_LENGTH)
+                        .with_suffix("ms"),
+                );
+            }
+            diagnostics.add_measurement(id, *ms);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diagnostic_ids_follow_the_namespace() {
+        let id = kajiya_diagnostic_id(DIAGNOSTIC_KIND_PASS, 0x1234);
+        assert_eq!(id.0.as_u128(), 0x6b61_6a69_7961_0001_0000_0000_0000_1234);
+    }
+
+    #[test]
+    fn diagnostic_ids_are_unique() {
+        let ids = [
+            KajiyaDiagnosticsPlugin::FRAME_TIME,
+            KajiyaDiagnosticsPlugin::GPU_PASSES_TIME,
+            KajiyaDiagnosticsPlugin::INSTANCE_COUNT,
+            KajiyaDiagnosticsPlugin::CULLED_INSTANCES,
+            KajiyaDiagnosticsPlugin::LOADED_MESH_COUNT,
+            KajiyaDiagnosticsPlugin::PENDING_BAKES,
+            KajiyaDiagnosticsPlugin::COMMAND_QUEUE_LEN,
+            KajiyaDiagnosticsPlugin::CACHE_HIT_RATE,
+            KajiyaDiagnosticsPlugin::pass_diagnostic_id("final blit"),
+            KajiyaDiagnosticsPlugin::pass_diagnostic_id("taa"),
+        ];
+
+        for (i, a) in ids.iter().enumerate() {
+            for b in ids[i + 1..].iter() {
+                assert_ne!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn pass_diagnostic_ids_are_stable() {
+        // FNV-1a test vector
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(
+            KajiyaDiagnosticsPlugin::pass_diagnostic_id("a"),
+            kajiya_diagnostic_id(DIAGNOSTIC_KIND_PASS, 0xaf63_dc4c_8601_ec8c)
+        );
+    }
+
+    #[test]
+    fn cache_hit_rate() {
+        let mut stats = KajiyaRenderStats::default();
+        assert_eq!(stats.cache_hit_rate(), None);
+
+        stats.cache_hits = 3;
+        stats.cache_misses = 1;
+        assert_eq!(stats.cache_hit_rate(), Some(0.75));
+    }
+}
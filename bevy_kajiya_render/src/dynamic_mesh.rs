@@ -0,0 +1,326 @@
+#[cfg(feature = "mesh_updates")]
+use std::sync::Arc;
+
+use bevy::prelude::*;
+#[cfg(feature = "mesh_updates")]
+use bevy::utils::HashMap;
+
+#[cfg(feature = "mesh_updates")]
+use crate::{
+    bounds::SharedMeshBounds,
+    mesh::{parse_mesh_key, parse_mesh_variant},
+    plugin::RenderWorld,
+    render_instances::{LoadedMeshesMap, RenderMesh},
+    runtime_mesh::{parse_runtime_mesh_name, runtime_mesh_id, runtime_mesh_name, RuntimeMeshes},
+    world_renderer::{WRCommandQueue, WorldRendererCommand},
+};
+use crate::{
+    mesh::Aabb,
+    runtime_mesh::{compute_normals, positions_bounds, KajiyaMeshData},
+};
+
+/// Deforming geometry, like cloth or skinned characters: the app writes the vertex positions of
+/// a runtime mesh, and the mesh is updated when they change. Untouched meshes aren't updated.
+///
+/// The rendered [`KajiyaMeshData`] handle of the entity is managed by [`update_dynamic_meshes`]
+/// and shouldn't be inserted by the app: each dynamic mesh owns one, uploaded once. With the
+/// `mesh_updates` feature, later changes rewrite its vertices in place and refit its BLAS.
+/// Without it, kajiya can't update meshes, so each change uploads the whole mesh again and the
+/// previous upload is never freed, which only suits meshes deforming now and then.
+#[derive(Component, Clone, Debug, Default)]
+pub struct KajiyaDynamicMesh {
+    /// Triangles, uvs and material of the mesh.
+    pub mesh: Handle<KajiyaMeshData>,
+    /// One position per vertex of `mesh`, or empty to render `mesh` as is.
+    pub positions: Vec<[f32; 3]>,
+    /// Computed from the triangles when empty.
+    pub normals: Vec<[f32; 3]>,
+}
+
+/// Deformed vertices of a mesh, see [`KajiyaDynamicMesh::vertices`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KajiyaMeshVertices {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+}
+
+impl KajiyaMeshVertices {
+    pub fn bounds(&self) -> Option<Aabb> {
+        positions_bounds(&self.positions)
+    }
+}
+
+impl KajiyaDynamicMesh {
+    pub fn new(mesh: Handle<KajiyaMeshData>) -> Self {
+        Self {
+            mesh,
+            ..Default::default()
+        }
+    }
+
+    fn check(&self, mesh: &KajiyaMeshData) -> anyhow::Result<()> {
+        if self.positions.is_empty() {
+            return Ok(());
+        }
+
+        if self.positions.len() != mesh.positions.len() {
+            anyhow::bail!(
+                "{} positions for a mesh of {} vertices",
+                self.positions.len(),
+                mesh.positions.len()
+            );
+        }
+        if !self.normals.is_empty() && self.normals.len() != self.positions.len() {
+            anyhow::bail!(
+                "{} normals for {} positions",
+                self.normals.len(),
+                self.positions.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The deformed copy of `mesh`.
+    pub fn deform(&self, mesh: &KajiyaMeshData) -> anyhow::Result<KajiyaMeshData> {
+        self.check(mesh)?;
+        let mut deformed = mesh.clone();
+
+        if !self.positions.is_empty() {
+            deformed.positions = self.positions.clone();
+            deformed.normals = self.normals.clone();
+            // Tangents of the rest pose would be wrong, use the default frame instead
+            deformed.tangents.clear();
+        }
+
+        deformed.validate()?;
+
+        Ok(deformed)
+    }
+
+    /// The deformed vertices of `mesh`, with normals computed from its triangles when there are
+    /// none, as in its upload.
+    pub fn vertices(&self, mesh: &KajiyaMeshData) -> anyhow::Result<KajiyaMeshVertices> {
+        self.check(mesh)?;
+
+        let (positions, normals) = if self.positions.is_empty() {
+            (&mesh.positions, &mesh.normals)
+        } else {
+            (&self.positions, &self.normals)
+        };
+
+        Ok(KajiyaMeshVertices {
+            positions: positions.clone(),
+            normals: if normals.is_empty() {
+                compute_normals(positions, &mesh.indices)
+            } else {
+                normals.clone()
+            },
+        })
+    }
+}
+
+/// Uploads the deformed mesh of new [`KajiyaDynamicMesh`]es to the [`KajiyaMeshData`] the entity
+/// renders, which is only added the first time, and updates it when they change.
+pub fn update_dynamic_meshes(
+    mut commands: Commands,
+    query: Query<(
+        Entity,
+        &KajiyaDynamicMesh,
+        ChangeTrackers<KajiyaDynamicMesh>,
+        Option<&Handle<KajiyaMeshData>>,
+    )>,
+    mut meshes: ResMut<Assets<KajiyaMeshData>>,
+    #[cfg(feature = "mesh_updates")] mut vertex_updates: ResMut<DynamicMeshUpdates>,
+    #[cfg(feature = "mesh_updates")] mesh_bounds: Res<SharedMeshBounds>,
+) {
+    for (entity, dynamic_mesh, tracker, uploaded_mesh) in query.iter() {
+        // The rest mesh is never modified, it may be shared
+        let uploaded_mesh = uploaded_mesh.filter(|handle| **handle != dynamic_mesh.mesh);
+        if !tracker.is_changed() && uploaded_mesh.is_some() {
+            continue;
+        }
+
+        // Wait for the mesh to be added
+        let mesh = match meshes.get(&dynamic_mesh.mesh) {
+            Some(mesh) => mesh,
+            None => continue,
+        };
+
+        // Keep the triangles of the upload, and only rewrite its vertices
+        #[cfg(feature = "mesh_updates")]
+        if let Some(uploaded_mesh) = uploaded_mesh {
+            match dynamic_mesh.vertices(mesh) {
+                Ok(vertices) => {
+                    if let Some(bounds) = vertices.bounds() {
+                        mesh_bounds.insert(runtime_mesh_name(uploaded_mesh), bounds);
+                    }
+                    vertex_updates
+                        .0
+                        .insert(runtime_mesh_id(uploaded_mesh), vertices);
+                }
+                Err(e) => log::warn!("Couldn't deform the mesh of {:?}: {}", entity, e),
+            }
+            continue;
+        }
+
+        let deformed = match dynamic_mesh.deform(mesh) {
+            Ok(deformed) => deformed,
+            Err(e) => {
+                log::warn!("Couldn't deform the mesh of {:?}: {}", entity, e);
+                // Keep the previous upload, or show the rest pose
+                if uploaded_mesh.is_some() {
+                    continue;
+                }
+                mesh.clone()
+            }
+        };
+
+        match uploaded_mesh.and_then(|handle| meshes.get_mut(handle)) {
+            Some(uploaded_mesh) => *uploaded_mesh = deformed,
+            None => {
+                let handle = meshes.add(deformed);
+                commands.entity(entity).insert(handle);
+            }
+        }
+    }
+}
+
+/// Vertices of the dynamic meshes changed since the last extraction, by runtime mesh id.
+#[cfg(feature = "mesh_updates")]
+#[derive(Default)]
+pub struct DynamicMeshUpdates(pub HashMap<u64, KajiyaMeshVertices>);
+
+/// Vertices waiting for every upload of their mesh to be ready, by runtime mesh id.
+#[cfg(feature = "mesh_updates")]
+#[derive(Default)]
+pub struct PendingMeshVertices(pub HashMap<u64, Arc<KajiyaMeshVertices>>);
+
+#[cfg(feature = "mesh_updates")]
+pub fn extract_dynamic_meshes(
+    mut vertex_updates: ResMut<DynamicMeshUpdates>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    let mut pending_vertices = render_world
+        .get_resource_mut::<PendingMeshVertices>()
+        .unwrap();
+
+    for (id, vertices) in vertex_updates.0.drain() {
+        pending_vertices.0.insert(id, Arc::new(vertices));
+    }
+}
+
+/// Rewrites the vertices of every mirrored and material variant of the updated dynamic meshes.
+#[cfg(feature = "mesh_updates")]
+pub fn update_mesh_vertices(
+    mut pending_vertices: ResMut<PendingMeshVertices>,
+    lm_map: Res<LoadedMeshesMap>,
+    runtime_meshes: Res<RuntimeMeshes>,
+    mut wr_command_queue: ResMut<WRCommandQueue>,
+) {
+    pending_vertices.0.retain(|id, vertices| {
+        let variants: Vec<&RenderMesh> = lm_map
+            .iter()
+            .filter(|(mesh_key, _)| {
+                let (mesh_name, _) = parse_mesh_key(mesh_key);
+                let (source, _, _) = parse_mesh_variant(mesh_name);
+                parse_runtime_mesh_name(source) == Some(*id)
+            })
+            .map(|(_, mesh)| mesh)
+            .collect();
+
+        // Variants being uploaded are baked from the first vertices, so update them once ready,
+        // like variants not requested yet
+        let uploading = variants
+            .iter()
+            .any(|mesh| !matches!(mesh, RenderMesh::Ready(_)));
+        if uploading || variants.is_empty() {
+            return runtime_meshes.0.contains_key(id);
+        }
+
+        for mesh in variants {
+            if let RenderMesh::Ready(mesh_handle) = mesh {
+                wr_command_queue.push(WorldRendererCommand::UpdateMeshVertices(
+                    *mesh_handle,
+                    vertices.clone(),
+                ));
+            }
+        }
+
+        false
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle() -> KajiyaMeshData {
+        KajiyaMeshData {
+            positions: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]],
+            tangents: vec![[1.0, 0.0, 0.0, 1.0]; 3],
+            indices: vec![0, 1, 2],
+            ..Default::default()
+        }
+    }
+
+    fn dynamic_mesh(positions: Vec<[f32; 3]>) -> KajiyaDynamicMesh {
+        KajiyaDynamicMesh {
+            positions,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn deform_replaces_the_vertices() {
+        let mesh = triangle();
+        let positions = vec![[0.0, 0.0, 1.0], [2.0, 0.0, 1.0], [0.0, 2.0, 1.0]];
+
+        let deformed = dynamic_mesh(positions.clone()).deform(&mesh).unwrap();
+
+        assert_eq!(deformed.positions, positions);
+        assert_eq!(deformed.indices, mesh.indices);
+        // Normals are computed when uploading, and tangents use the default frame
+        assert!(deformed.normals.is_empty());
+        assert!(deformed.tangents.is_empty());
+    }
+
+    #[test]
+    fn deform_without_positions_keeps_the_rest_pose() {
+        let mesh = triangle();
+
+        assert_eq!(dynamic_mesh(Vec::new()).deform(&mesh).unwrap(), mesh);
+    }
+
+    #[test]
+    fn deform_rejects_mismatched_lengths() {
+        let mesh = triangle();
+
+        let error = dynamic_mesh(vec![[0.0; 3]; 2]).deform(&mesh).unwrap_err();
+        assert_eq!(error.to_string(), "2 positions for a mesh of 3 vertices");
+
+        let mut with_normals = dynamic_mesh(vec![[0.0; 3]; 3]);
+        with_normals.normals = vec![[0.0, 0.0, 1.0]; 4];
+        let error = with_normals.deform(&mesh).unwrap_err();
+        assert_eq!(error.to_string(), "4 normals for 3 positions");
+        assert!(with_normals.vertices(&mesh).is_err());
+    }
+
+    #[test]
+    fn vertices_compute_missing_normals() {
+        let mesh = triangle();
+        let positions = vec![[0.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, 1.0, 0.0]];
+
+        let vertices = dynamic_mesh(positions.clone()).vertices(&mesh).unwrap();
+
+        assert_eq!(vertices.positions, positions);
+        assert_eq!(vertices.normals, vec![[1.0, 0.0, 0.0]; 3]);
+        assert_eq!(
+            vertices.bounds(),
+            Some(Aabb::from_min_max(
+                Vec3::new(0.0, 0.0, -1.0),
+                Vec3::new(0.0, 1.0, 0.0)
+            ))
+        );
+    }
+}
@@ -0,0 +1,304 @@
+use std::{io::Cursor, path::PathBuf, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    tasks::{AsyncComputeTaskPool, Task},
+    utils::BoxedFuture,
+};
+use exr::prelude::{ReadChannels as _, ReadLayers as _};
+use futures_lite::future;
+use kajiya::backend::canonical_path_from_vfs;
+
+use crate::{
+    camera::{EnvironmentSettings, KajiyaCamera},
+    plugin::RenderWorld,
+    render_resources::KajiyaRenderers,
+};
+
+/// An equirectangular HDR image of the surroundings, loaded from a `.hdr` or `.exr` file.
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "b2a7c8e4-5d1f-4e6a-9c3b-7f0e2d4a6b91"]
+pub struct KajiyaEnvironmentMap {
+    pub width: u32,
+    pub height: u32,
+    /// Linear RGB, row by row from the top. Shared, so extracting the map doesn't copy it.
+    pub pixels: Arc<[[f32; 3]]>,
+}
+
+impl KajiyaEnvironmentMap {
+    pub fn from_hdr(bytes: &[u8]) -> anyhow::Result<Self> {
+        let image = radiant::load(Cursor::new(bytes))?;
+
+        Ok(Self {
+            width: image.width as u32,
+            height: image.height as u32,
+            pixels: image.data.iter().map(|rgb| [rgb.r, rgb.g, rgb.b]).collect(),
+        })
+    }
+
+    pub fn from_exr(bytes: &[u8]) -> anyhow::Result<Self> {
+        let image = exr::prelude::read()
+            .no_deep_data()
+            .largest_resolution_level()
+            .rgb_channels(
+                |resolution, _| {
+                    let (width, height) = (resolution.width(), resolution.height());
+                    (width, height, vec![[0.0; 3]; width * height])
+                },
+                |(width, _, pixels): &mut (usize, usize, Vec<[f32; 3]>),
+                 position,
+                 (r, g, b): (f32, f32, f32)| {
+                    pixels[position.y() * *width + position.x()] = [r, g, b];
+                },
+            )
+            .first_valid_layer()
+            .all_attributes()
+            .from_buffered(Cursor::new(bytes))?;
+
+        let (width, height, pixels) = image.layer_data.channel_data.pixels;
+        Ok(Self {
+            width: width as u32,
+            height: height as u32,
+            pixels: pixels.into(),
+        })
+    }
+
+    /// The map turned by `rotation` degrees around the vertical axis, and scaled by `intensity`.
+    pub fn transformed(&self, rotation: f32, intensity: f32) -> Self {
+        let width = self.width as usize;
+        if width == 0 {
+            return self.clone();
+        }
+        let shift = (rotation / 360.0).rem_euclid(1.0) * width as f32;
+
+        let mut pixels = Vec::with_capacity(self.pixels.len());
+        for row in self.pixels.chunks_exact(width) {
+            for x in 0..width {
+                // Blend the two source columns, wrapping around the seam
+                let source = (x as f32 - shift).rem_euclid(width as f32);
+                let (left, t) = (source.floor() as usize % width, source.fract());
+                let (a, b) = (row[left], row[(left + 1) % width]);
+                pixels.push([0, 1, 2].map(|c| (a[c] + (b[c] - a[c]) * t) * intensity));
+            }
+        }
+
+        Self {
+            width: self.width,
+            height: self.height,
+            pixels: pixels.into(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct KajiyaEnvironmentMapLoader;
+
+impl AssetLoader for KajiyaEnvironmentMapLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let is_exr = load_context
+                .path()
+                .extension()
+                .map_or(false, |extension| extension.eq_ignore_ascii_case("exr"));
+            let map = if is_exr {
+                KajiyaEnvironmentMap::from_exr(bytes)?
+            } else {
+                KajiyaEnvironmentMap::from_hdr(bytes)?
+            };
+            load_context.set_default_asset(LoadedAsset::new(map));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["hdr", "exr"]
+    }
+}
+
+/// The environment map of the camera's [`EnvironmentSettings`], extracted when it changes.
+pub struct ExtractedEnvironmentMap {
+    pub handle: Option<Handle<KajiyaEnvironmentMap>>,
+    pub rotation: f32,
+    pub intensity: f32,
+    pub map: Option<KajiyaEnvironmentMap>,
+    pub changed: bool,
+}
+
+impl Default for ExtractedEnvironmentMap {
+    fn default() -> Self {
+        Self {
+            handle: None,
+            rotation: 0.0,
+            intensity: 1.0,
+            map: None,
+            changed: false,
+        }
+    }
+}
+
+pub fn extract_environment_map(
+    query: Query<&EnvironmentSettings, With<KajiyaCamera>>,
+    maps: Res<Assets<KajiyaEnvironmentMap>>,
+    mut ev_map: EventReader<AssetEvent<KajiyaEnvironmentMap>>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    let mut extracted_map = render_world
+        .get_resource_mut::<ExtractedEnvironmentMap>()
+        .unwrap();
+
+    let (handle, rotation, intensity) = match query.iter().next() {
+        Some(settings) => (
+            settings.environment_map.clone(),
+            settings.environment_rotation,
+            settings.environment_intensity,
+        ),
+        None => (None, 0.0, 1.0),
+    };
+
+    // A removed map is unloaded below, as it can't be found anymore
+    let modified = ev_map.iter().any(|ev| match ev {
+        AssetEvent::Created { handle: ev_handle }
+        | AssetEvent::Modified { handle: ev_handle }
+        | AssetEvent::Removed { handle: ev_handle } => Some(ev_handle) == handle.as_ref(),
+    });
+
+    let transform_changed = handle.is_some()
+        && (extracted_map.rotation != rotation || extracted_map.intensity != intensity);
+
+    if modified || transform_changed || extracted_map.handle != handle {
+        extracted_map.map = handle.as_ref().and_then(|handle| maps.get(handle)).cloned();
+        extracted_map.handle = handle;
+        extracted_map.rotation = rotation;
+        extracted_map.intensity = intensity;
+        extracted_map.changed = true;
+    }
+}
+
+/// Bakes the rotated and scaled environment map to the cache off the render thread, and lights
+/// the scene with it instead of the procedural sky once written.
+pub fn prepare_environment_map(
+    wr_res: NonSendMut<KajiyaRenderers>,
+    mut extracted_map: ResMut<ExtractedEnvironmentMap>,
+    mut write_task: Local<Option<Task<anyhow::Result<PathBuf>>>>,
+) {
+    // Only one bake writes the cached file at a time
+    if let Some(task) = write_task.as_mut() {
+        let written = match future::block_on(future::poll_once(task)) {
+            Some(written) => written,
+            None => return,
+        };
+        *write_task = None;
+
+        // Skip the bake if the map changed since it started
+        if !extracted_map.changed {
+            let mut world_renderer = wr_res.world_renderer.lock().unwrap();
+            let loaded = written.and_then(|path| world_renderer.ibl.load_image(&path));
+            if let Err(e) = loaded {
+                log::warn!("Couldn't load the environment map: {}", e);
+                world_renderer.ibl.unload_image();
+            }
+        }
+    }
+
+    if !extracted_map.changed {
+        return;
+    }
+    extracted_map.changed = false;
+
+    let map = match &extracted_map.map {
+        Some(map) => map.clone(),
+        // Not set, or not loaded yet
+        None => {
+            wr_res.world_renderer.lock().unwrap().ibl.unload_image();
+            return;
+        }
+    };
+
+    let (rotation, intensity) = (extracted_map.rotation, extracted_map.intensity);
+    *write_task = Some(AsyncComputeTaskPool::get().spawn(async move {
+        kajiya_profile_scope!("write_environment_map");
+        write_environment_map(&map.transformed(rotation, intensity))
+    }));
+}
+
+fn write_environment_map(map: &KajiyaEnvironmentMap) -> anyhow::Result<PathBuf> {
+    let path = canonical_path_from_vfs("/cache/environment_map.exr")?;
+    let width = map.width as usize;
+
+    exr::prelude::write_rgb_file(&path, width, map.height as usize, |x, y| {
+        let [r, g, b] = map.pixels[y * width + x];
+        (r, g, b)
+    })?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(pixels: &[f32]) -> KajiyaEnvironmentMap {
+        KajiyaEnvironmentMap {
+            width: pixels.len() as u32,
+            height: 1,
+            pixels: pixels.iter().map(|&v| [v, v * 2.0, v * 3.0]).collect(),
+        }
+    }
+
+    fn reds(map: &KajiyaEnvironmentMap) -> Vec<f32> {
+        map.pixels.iter().map(|rgb| rgb[0]).collect()
+    }
+
+    #[test]
+    fn rotation_wraps_around_the_seam() {
+        let source = map(&[1.0, 2.0, 3.0, 4.0]);
+
+        assert_eq!(reds(&source.transformed(0.0, 1.0)), [1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(reds(&source.transformed(90.0, 1.0)), [4.0, 1.0, 2.0, 3.0]);
+        assert_eq!(reds(&source.transformed(-90.0, 1.0)), [2.0, 3.0, 4.0, 1.0]);
+        assert_eq!(reds(&source.transformed(450.0, 1.0)), [4.0, 1.0, 2.0, 3.0]);
+        assert_eq!(reds(&source.transformed(360.0, 1.0)), [1.0, 2.0, 3.0, 4.0]);
+
+        // Half a column blends the neighbours, across the seam for the first one
+        assert_eq!(reds(&source.transformed(45.0, 1.0)), [2.5, 1.5, 2.5, 3.5]);
+    }
+
+    #[test]
+    fn rotation_keeps_the_rows_apart() {
+        let source = KajiyaEnvironmentMap {
+            width: 2,
+            height: 2,
+            pixels: vec![[1.0; 3], [2.0; 3], [10.0; 3], [20.0; 3]].into(),
+        };
+
+        assert_eq!(
+            reds(&source.transformed(180.0, 1.0)),
+            [2.0, 1.0, 20.0, 10.0]
+        );
+    }
+
+    #[test]
+    fn intensity_scales_every_channel() {
+        let scaled = map(&[1.0, 0.5]).transformed(0.0, 0.5);
+
+        assert_eq!(scaled.pixels[..], [[0.5, 1.0, 1.5], [0.25, 0.5, 0.75]]);
+        assert_eq!(
+            map(&[1.0]).transformed(0.0, 0.0).pixels[..],
+            [[0.0, 0.0, 0.0]]
+        );
+    }
+
+    #[test]
+    fn extracted_map_defaults_to_full_intensity() {
+        let extracted = ExtractedEnvironmentMap::default();
+
+        assert_eq!(extracted.intensity, 1.0);
+        assert!(!extracted.changed);
+    }
+}
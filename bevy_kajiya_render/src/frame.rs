@@ -1,8 +1,11 @@
 use bevy::ecs::prelude::*;
 use kajiya::{frame_desc::WorldFrameDesc, rg};
 
-use crate::render_resources::{
-    KajiyaRGRenderer, KajiyaRenderBackend, KajiyaRenderers, RenderContext,
+use crate::{
+    camera::ExtractedCamera,
+    post_process::PostProcessRenderer,
+    render_passes::ExtractedRenderPasses,
+    render_resources::{KajiyaRGRenderer, KajiyaRenderBackend, KajiyaRenderers, RenderContext},
 };
 
 pub fn render_frame(
@@ -11,7 +14,12 @@ pub fn render_frame(
     mut render_ctx: ResMut<RenderContext>,
     frame_desc: Res<WorldFrameDesc>,
     mut render_backend: ResMut<KajiyaRenderBackend>,
+    render_passes: Res<ExtractedRenderPasses>,
+    post_renderer: NonSend<PostProcessRenderer>,
+    extracted_camera: Res<ExtractedCamera>,
 ) {
+    kajiya_profile_scope!("render_frame");
+
     let swapchain_extent = render_ctx.swapchain_extent;
     let mut world_renderer = wr_res.world_renderer.lock().unwrap();
     let mut ui_renderer = wr_res.ui_renderer.lock().unwrap();
@@ -33,6 +41,13 @@ pub fn render_frame(
         rg_renderer.prepare_frame(|rg| {
             rg.debug_hook = world_renderer.rg_debug_hook.take();
             let main_img = world_renderer.prepare_render_graph(rg, &frame_desc);
+            let main_img = render_passes.prepare_render_graph(rg, main_img);
+            let main_img = match extracted_camera.post_process.as_ref() {
+                Some(post_process) => {
+                    post_renderer.prepare_render_graph(rg, main_img, post_process)
+                }
+                None => main_img,
+            };
             let ui_img = ui_renderer.prepare_render_graph(rg);
 
             let mut swap_chain = rg.get_swap_chain();
@@ -0,0 +1,198 @@
+use bevy::{prelude::*, utils::HashSet};
+
+use crate::{
+    asset::GltfMeshAsset,
+    mesh::{
+        mesh_source_path, node_mesh_name, parse_mesh_variant, KajiyaMeshInstance,
+        KajiyaMeshInstanceBundle,
+    },
+};
+
+/// Splits the glTF scene of `mesh` by node: each node is spawned as a child entity with the
+/// node's local [`Transform`], and nodes with a mesh render it as a [`KajiyaMeshInstance`]
+/// baked on its own. Parts of the scene, like wheels, can then be moved independently.
+///
+/// The hierarchy is spawned again when `mesh` changes or when the glTF file is hot reloaded.
+#[derive(Component, Clone, Debug, Default, Reflect)]
+#[reflect(Component)]
+pub struct KajiyaGltfNodes {
+    pub mesh: String,
+}
+
+#[derive(Bundle, Default)]
+pub struct KajiyaGltfNodesBundle {
+    pub nodes: KajiyaGltfNodes,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+/// Marks an entity spawned for the glTF node `index` of the [`KajiyaGltfNodes`] on `scene`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct KajiyaGltfNode {
+    pub scene: Entity,
+    pub index: usize,
+}
+
+/// Restricts a glTF document to the meshes of `node`, without its transform and children, so
+/// kajiya can bake them as a mesh of their own.
+pub fn restrict_gltf_to_node(root: &mut gltf::json::Root, node: usize) -> anyhow::Result<()> {
+    let scene_index = root.scene.map_or(0, |scene| scene.value());
+    let mut scene = root
+        .scenes
+        .get(scene_index)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("No scene in the glTF document"))?;
+    scene.nodes = vec![gltf::json::Index::new(node as u32)];
+    root.scenes = vec![scene];
+    root.scene = Some(gltf::json::Index::new(0));
+
+    let node_desc = root
+        .nodes
+        .get_mut(node)
+        .ok_or_else(|| anyhow::anyhow!("No node {} in the glTF document", node))?;
+    node_desc.children = None;
+    node_desc.matrix = None;
+    node_desc.translation = None;
+    node_desc.rotation = None;
+    node_desc.scale = None;
+
+    Ok(())
+}
+
+fn spawn_gltf_node(
+    commands: &mut Commands,
+    node: gltf::Node,
+    mesh: &str,
+    scene: Entity,
+    parent: Entity,
+) {
+    let (translation, rotation, scale) = node.transform().decomposed();
+    let transform = Transform {
+        translation: Vec3::from(translation),
+        rotation: Quat::from_array(rotation),
+        scale: Vec3::from(scale),
+    };
+
+    let mut entity = match node.mesh() {
+        Some(_) => commands.spawn_bundle(KajiyaMeshInstanceBundle {
+            mesh_instance: KajiyaMeshInstance {
+                mesh: node_mesh_name(mesh, node.index()),
+                ..Default::default()
+            },
+            transform,
+            ..Default::default()
+        }),
+        None => commands.spawn_bundle(TransformBundle::from_transform(transform)),
+    };
+    entity.insert(KajiyaGltfNode {
+        scene,
+        index: node.index(),
+    });
+    if let Some(name) = node.name() {
+        entity.insert(Name::new(name.to_string()));
+    }
+    let entity = entity.id();
+
+    commands.entity(parent).add_child(entity);
+
+    for child in node.children() {
+        spawn_gltf_node(commands, child, mesh, scene, entity);
+    }
+}
+
+/// Spawns the node hierarchy of new or modified [`KajiyaGltfNodes`], and of those whose glTF
+/// file was reloaded, replacing the previous one.
+pub fn spawn_gltf_nodes(
+    mut commands: Commands,
+    mut asset_events: EventReader<AssetEvent<GltfMeshAsset>>,
+    gltf_assets: Res<Assets<GltfMeshAsset>>,
+    query: Query<(
+        Entity,
+        &KajiyaGltfNodes,
+        ChangeTrackers<KajiyaGltfNodes>,
+        Option<&Children>,
+    )>,
+    node_query: Query<&KajiyaGltfNode>,
+) {
+    let reloaded_sources: HashSet<&str> = asset_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } => gltf_assets.get(handle),
+            _ => None,
+        })
+        .map(|asset| asset.mesh_src.as_str())
+        .collect();
+
+    for (scene_entity, gltf_nodes, change_trackers, children) in query.iter() {
+        let (source, _, _) = parse_mesh_variant(&gltf_nodes.mesh);
+        if !change_trackers.is_changed() && !reloaded_sources.contains(source) {
+            continue;
+        }
+
+        for child in children.into_iter().flat_map(|children| children.iter()) {
+            if let Ok(node) = node_query.get(*child) {
+                if node.scene == scene_entity {
+                    commands.entity(*child).despawn_recursive();
+                }
+            }
+        }
+
+        let path = mesh_source_path(&gltf_nodes.mesh);
+        let gltf = match gltf::Gltf::open(&path) {
+            Ok(gltf) => gltf,
+            Err(e) => {
+                log::warn!("Couldn't read the glTF nodes of {:?}: {}", path, e);
+                continue;
+            }
+        };
+
+        if let Some(scene) = gltf.default_scene().or_else(|| gltf.scenes().next()) {
+            for node in scene.nodes() {
+                spawn_gltf_node(
+                    &mut commands,
+                    node,
+                    &gltf_nodes.mesh,
+                    scene_entity,
+                    scene_entity,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restricted_gltf_keeps_only_the_node() {
+        let mut root: gltf::json::Root = gltf::json::deserialize::from_str(
+            r#"{
+                "asset": { "version": "2.0" },
+                "scene": 1,
+                "scenes": [{ "nodes": [] }, { "nodes": [0] }],
+                "nodes": [
+                    { "children": [1, 2] },
+                    { "mesh": 0, "translation": [1.0, 0.0, 0.0], "children": [2] },
+                    { "mesh": 1, "scale": [2.0, 2.0, 2.0] }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        restrict_gltf_to_node(&mut root, 1).unwrap();
+
+        assert_eq!(root.scenes.len(), 1);
+        assert_eq!(root.scene.map(|scene| scene.value()), Some(0));
+        assert_eq!(root.scenes[0].nodes, [gltf::json::Index::new(1)]);
+
+        let node = &root.nodes[1];
+        assert!(node.children.is_none());
+        assert!(node.translation.is_none());
+        assert_eq!(node.mesh.map(|mesh| mesh.value()), Some(0));
+        // The other nodes are kept, but the scene doesn't refer to them anymore
+        assert_eq!(root.nodes.len(), 3);
+
+        assert!(restrict_gltf_to_node(&mut root, 3).is_err());
+    }
+}
@@ -1,15 +1,62 @@
+#[macro_use]
+pub mod profiling;
+
+pub mod animation;
 mod asset;
+pub mod bounds;
 pub mod camera;
+pub mod culling;
+pub mod debug;
+pub mod diagnostics;
+pub mod dynamic_mesh;
+pub mod environment_map;
 mod frame;
+pub mod gltf_nodes;
+pub mod light;
+pub mod lod;
+pub mod material;
 pub mod mesh;
+pub mod mirror;
+pub mod picking;
 pub mod plugin;
+pub mod post_process;
 pub mod render_instances;
+pub mod render_passes;
 pub mod render_resources;
+pub mod runtime_mesh;
+pub mod scene;
+pub mod solar;
+pub mod sun;
 mod world_renderer;
 
-pub use camera::{EnvironmentSettings, KajiyaCamera, KajiyaCameraBundle};
-pub use mesh::{KajiyaMeshInstance, KajiyaMeshInstanceBundle};
-pub use plugin::KajiyaRenderPlugin;
+pub use animation::{
+    KajiyaAnimationClip, KajiyaAnimationPlayer, KajiyaSkinnedGltf, KajiyaSkinnedGltfBundle,
+};
+pub use camera::{
+    EnvironmentSettings, KajiyaCamera, KajiyaCameraBundle, KajiyaPhysicalCamera, KajiyaProjection,
+};
+pub use culling::{KajiyaCulling, KajiyaCullingPolicy};
+pub use debug::{KajiyaDebugMode, KajiyaDebugView};
+pub use diagnostics::{KajiyaDiagnosticsPlugin, KajiyaRenderStats};
+pub use dynamic_mesh::{KajiyaDynamicMesh, KajiyaMeshVertices};
+pub use environment_map::KajiyaEnvironmentMap;
+pub use gltf_nodes::{KajiyaGltfNode, KajiyaGltfNodes, KajiyaGltfNodesBundle};
+pub use light::{KajiyaPointLight, KajiyaPointLightBundle, KajiyaSpotLight, KajiyaSpotLightBundle};
+pub use lod::{KajiyaLod, KajiyaLodLevel};
+pub use material::KajiyaMaterialOverride;
+pub use mesh::{Aabb, KajiyaMeshInstance, KajiyaMeshInstanceBundle};
+pub use picking::{KajiyaPickEvent, KajiyaPickHit, KajiyaPicking, KajiyaPickingPlugin, KajiyaRay};
+pub use plugin::{KajiyaAppSystem, KajiyaRenderAppExt, KajiyaRenderPlugin, KajiyaRenderSystem};
+pub use post_process::{
+    KajiyaExposure, KajiyaLut, KajiyaPostProcess, KajiyaTonemapper, KAJIYA_UNIT_LUMINANCE,
+};
+pub use profiling::KajiyaProfilingSettings;
+pub use render_passes::{KajiyaRenderPass, KajiyaRenderPasses};
+pub use runtime_mesh::{KajiyaMeshData, KajiyaMeshMaterial};
+pub use scene::{save_kajiya_scene, KajiyaSceneAsset, KajiyaSceneBundle, KajiyaSceneInstance};
+pub use solar::SolarClock;
+pub use sun::{KajiyaSun, KajiyaSunBundle};
+pub use world_renderer::{SunState, WRCommandQueue, WorldRendererCommand};
 
 #[derive(Clone)]
 pub struct KajiyaDescriptor {
@@ -0,0 +1,585 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
+
+use crate::{
+    material::MATERIAL_OVERRIDE_STEP,
+    mesh::{KajiyaMeshInstance, KajiyaMeshInstanceBundle},
+    post_process::KAJIYA_UNIT_LUMINANCE,
+    runtime_mesh::{KajiyaMeshData, KajiyaMeshMaterial},
+};
+
+/// Spherical light. kajiya has no punctual lights, so it is rendered as an emissive sphere of
+/// `radius`, lighting the scene through kajiya's emissive surface sampling.
+///
+/// The emitter's luminance is converted from nits to kajiya's radiance with
+/// [`KAJIYA_UNIT_LUMINANCE`], like the exposure of
+/// [`KajiyaPhysicalCamera`](crate::KajiyaPhysicalCamera).
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct KajiyaPointLight {
+    /// Linear RGB, multiplied by the luminance derived from `intensity`.
+    pub color: Vec3,
+    /// Luminous power in lumens.
+    pub intensity: f32,
+    pub radius: f32,
+}
+
+impl Default for KajiyaPointLight {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ONE,
+            // A 60W incandescent bulb
+            intensity: 800.0,
+            radius: 0.05,
+        }
+    }
+}
+
+/// Spot light shining along the entity's forward (-Z) axis, rendered as an emissive disc of
+/// `radius` at the bottom of a dark cylindrical shade. Takes precedence over a
+/// [`KajiyaPointLight`] on the same entity.
+///
+/// kajiya's emissive surfaces shine in every direction of their hemisphere, so the cone is
+/// shaped by the shade: it blocks the light beyond `cone_angle`, and the falloff from
+/// `inner_cone_angle` to the edge of the cone follows from its partial occlusion of the disc.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct KajiyaSpotLight {
+    /// Linear RGB, multiplied by the luminance derived from `intensity`.
+    pub color: Vec3,
+    /// Luminous power leaving the shade, in lumens.
+    pub intensity: f32,
+    pub radius: f32,
+    /// Half angle of the cone, in radians.
+    pub cone_angle: f32,
+    /// Half angle of the fully lit part of the cone, in radians, up to `cone_angle`.
+    pub inner_cone_angle: f32,
+}
+
+impl Default for KajiyaSpotLight {
+    fn default() -> Self {
+        Self {
+            color: Vec3::ONE,
+            intensity: 800.0,
+            radius: 0.05,
+            cone_angle: PI / 4.0,
+            inner_cone_angle: 0.0,
+        }
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct KajiyaPointLightBundle {
+    pub light: KajiyaPointLight,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+#[derive(Bundle, Default)]
+pub struct KajiyaSpotLightBundle {
+    pub light: KajiyaSpotLight,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+/// Luminance of the surface of a sphere of `radius` emitting `intensity` lumens.
+pub fn point_light_luminance(intensity: f32, radius: f32) -> f32 {
+    // Lambertian emitter: power = pi * luminance * area
+    intensity / (PI * 4.0 * PI * radius * radius)
+}
+
+/// Luminance of a disc of `radius` which emits `intensity` lumens within `cone_angle`.
+pub fn spot_light_luminance(intensity: f32, radius: f32, cone_angle: f32) -> f32 {
+    // A Lambertian emitter sends sin^2 of its power within an angle of its normal. The shade
+    // cuts the cone progressively, which this ignores.
+    let cone_fraction = cone_angle.sin().powi(2).max(1e-4);
+    intensity / (PI * PI * radius * radius * cone_fraction)
+}
+
+/// Radius and depth of the shade of a spot light, so that no light leaves it beyond
+/// `cone_angle`, and the whole disc of `radius` is visible within `inner_cone_angle`.
+pub fn spot_shade_size(radius: f32, cone_angle: f32, inner_cone_angle: f32) -> (f32, f32) {
+    let cone_angle = cone_angle.clamp(0.01, PI / 2.0 - 0.01);
+    let inner_cone_angle = inner_cone_angle.clamp(0.0, cone_angle - 0.01);
+    let (outer, inner) = (cone_angle.tan(), inner_cone_angle.tan());
+
+    // The rim of the shade hides the far edge of the disc at the inner angle, and its near edge
+    // at the outer one
+    let depth = 2.0 * radius / (outer - inner);
+    (radius + depth * inner, depth)
+}
+
+/// Sphere centered on the origin, with `sectors` around the Y axis and `stacks` from pole to pole.
+pub fn uv_sphere(radius: f32, sectors: u32, stacks: u32) -> KajiyaMeshData {
+    let mut mesh = KajiyaMeshData::default();
+
+    for stack in 0..=stacks {
+        let theta = PI * stack as f32 / stacks as f32;
+        for sector in 0..=sectors {
+            let phi = 2.0 * PI * sector as f32 / sectors as f32;
+            let normal = Vec3::new(
+                theta.sin() * phi.cos(),
+                theta.cos(),
+                theta.sin() * phi.sin(),
+            );
+
+            mesh.positions.push((normal * radius).to_array());
+            mesh.normals.push(normal.to_array());
+            mesh.uvs
+                .push([sector as f32 / sectors as f32, stack as f32 / stacks as f32]);
+        }
+    }
+
+    let row = sectors + 1;
+    for stack in 0..stacks {
+        for sector in 0..sectors {
+            let a = stack * row + sector;
+            let (b, d) = (a + row, a + 1);
+            let c = b + 1;
+            mesh.indices.extend([a, d, b, d, c, b]);
+        }
+    }
+
+    mesh
+}
+
+/// Disc centered on the Z axis at `z`, facing -Z, or +Z if `facing_forward` is false.
+pub fn disc(radius: f32, segments: u32, z: f32, facing_forward: bool) -> KajiyaMeshData {
+    let mut mesh = KajiyaMeshData::default();
+    let normal = if facing_forward { -Vec3::Z } else { Vec3::Z };
+
+    mesh.positions.push([0.0, 0.0, z]);
+    for segment in 0..segments {
+        let angle = 2.0 * PI * segment as f32 / segments as f32;
+        mesh.positions
+            .push([radius * angle.cos(), radius * angle.sin(), z]);
+    }
+    mesh.normals = vec![normal.to_array(); mesh.positions.len()];
+
+    for segment in 0..segments {
+        let (rim, next_rim) = (segment + 1, (segment + 1) % segments + 1);
+        if facing_forward {
+            mesh.indices.extend([0, next_rim, rim]);
+        } else {
+            mesh.indices.extend([0, rim, next_rim]);
+        }
+    }
+
+    mesh
+}
+
+/// Two-sided open cylinder around the Z axis, from z = 0 to z = -`length`.
+pub fn open_cylinder(radius: f32, length: f32, segments: u32) -> KajiyaMeshData {
+    let mut mesh = KajiyaMeshData::default();
+
+    for inside in [false, true] {
+        let base = mesh.positions.len() as u32;
+
+        for segment in 0..=segments {
+            let angle = 2.0 * PI * segment as f32 / segments as f32;
+            let outward = Vec3::new(angle.cos(), angle.sin(), 0.0);
+            let normal = if inside { -outward } else { outward };
+
+            for z in [0.0, -length] {
+                mesh.positions
+                    .push([radius * outward.x, radius * outward.y, z]);
+                mesh.normals.push(normal.to_array());
+            }
+        }
+
+        for segment in 0..segments {
+            let a = base + segment * 2;
+            let (b, d) = (a + 1, a + 2);
+            let c = d + 1;
+            if inside {
+                mesh.indices.extend([a, d, b, b, d, c]);
+            } else {
+                mesh.indices.extend([a, b, d, b, c, d]);
+            }
+        }
+    }
+
+    mesh
+}
+
+fn merge_meshes(meshes: impl IntoIterator<Item = KajiyaMeshData>) -> KajiyaMeshData {
+    let mut merged = KajiyaMeshData::default();
+
+    for mesh in meshes {
+        let base = merged.positions.len() as u32;
+        merged.positions.extend(mesh.positions);
+        merged.normals.extend(mesh.normals);
+        merged
+            .indices
+            .extend(mesh.indices.into_iter().map(|index| base + index));
+    }
+
+    merged
+}
+
+fn emissive_material(color: Vec3) -> KajiyaMeshMaterial {
+    KajiyaMeshMaterial {
+        base_color: [0.0, 0.0, 0.0, 1.0],
+        roughness: 1.0,
+        metalness: 0.0,
+        emissive: color.max(Vec3::ZERO).to_array(),
+    }
+}
+
+const SEGMENTS: u32 = 24;
+
+/// Unit meshes of the light proxies, scaled by the proxy transforms. Emitters are shared by the
+/// lights of the same color.
+#[derive(Default)]
+pub struct LightProxyMeshes {
+    spheres: HashMap<[u32; 3], Handle<KajiyaMeshData>>,
+    discs: HashMap<[u32; 3], Handle<KajiyaMeshData>>,
+    shade: Option<Handle<KajiyaMeshData>>,
+}
+
+/// Emitter colors are rounded like [`KajiyaMaterialOverride`](crate::KajiyaMaterialOverride)
+/// values, so animating them makes a bounded number of meshes.
+fn color_key(color: Vec3) -> [u32; 3] {
+    (color.max(Vec3::ZERO) / MATERIAL_OVERRIDE_STEP)
+        .round()
+        .to_array()
+        .map(|value| value as u32)
+}
+
+fn key_color(key: [u32; 3]) -> Vec3 {
+    Vec3::from_array(key.map(|value| value as f32)) * MATERIAL_OVERRIDE_STEP
+}
+
+impl LightProxyMeshes {
+    fn emitter(
+        &mut self,
+        meshes: &mut Assets<KajiyaMeshData>,
+        color: Vec3,
+        spot: bool,
+    ) -> Handle<KajiyaMeshData> {
+        let key = color_key(color);
+        let emitters = if spot {
+            &mut self.discs
+        } else {
+            &mut self.spheres
+        };
+
+        emitters
+            .entry(key)
+            .or_insert_with(|| {
+                let mut mesh = if spot {
+                    disc(1.0, SEGMENTS, 0.0, true)
+                } else {
+                    uv_sphere(1.0, SEGMENTS, SEGMENTS / 2)
+                };
+                mesh.material = emissive_material(key_color(key));
+                meshes.add(mesh)
+            })
+            .clone()
+    }
+
+    fn shade(&mut self, meshes: &mut Assets<KajiyaMeshData>) -> Handle<KajiyaMeshData> {
+        self.shade
+            .get_or_insert_with(|| {
+                // The cap keeps the back of the disc from lighting the scene
+                let mut shade = merge_meshes([
+                    open_cylinder(1.0, 1.0, SEGMENTS),
+                    disc(1.0, SEGMENTS, 0.01, false),
+                ]);
+                shade.material = KajiyaMeshMaterial {
+                    base_color: [0.02, 0.02, 0.02, 1.0],
+                    roughness: 0.8,
+                    metalness: 0.0,
+                    emissive: [0.0; 3],
+                };
+                meshes.add(shade)
+            })
+            .clone()
+    }
+}
+
+/// Transforms of the unit emitter and shade meshes of a spot light.
+pub fn spot_light_proxy_transforms(light: &KajiyaSpotLight) -> (Transform, Transform) {
+    let (shade_radius, depth) =
+        spot_shade_size(light.radius, light.cone_angle, light.inner_cone_angle);
+
+    (
+        Transform::from_scale(Vec3::new(light.radius, light.radius, 1.0)),
+        Transform::from_scale(Vec3::new(shade_radius, shade_radius, depth)),
+    )
+}
+
+/// The proxy entities rendering a light, and the emitter color they were made with.
+#[derive(Component, Clone, Debug)]
+pub struct KajiyaLightProxies {
+    pub emitter: Entity,
+    pub shade: Option<Entity>,
+    color: [u32; 3],
+}
+
+/// Marks an entity spawned to render the light on `light`.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct KajiyaLightProxy {
+    pub light: Entity,
+}
+
+fn spawn_proxy(
+    commands: &mut Commands,
+    light: Entity,
+    mesh: Handle<KajiyaMeshData>,
+    transform: Transform,
+    emission: f32,
+) -> Entity {
+    let proxy = commands
+        .spawn_bundle(KajiyaMeshInstanceBundle {
+            mesh_instance: KajiyaMeshInstance {
+                emission,
+                ..Default::default()
+            },
+            transform,
+            ..Default::default()
+        })
+        .insert(mesh)
+        .insert(KajiyaLightProxy { light })
+        .id();
+    commands.entity(light).add_child(proxy);
+
+    proxy
+}
+
+fn despawn_proxies(commands: &mut Commands, light: Entity, proxies: &KajiyaLightProxies) {
+    for proxy in [Some(proxies.emitter), proxies.shade].into_iter().flatten() {
+        commands.entity(proxy).despawn_recursive();
+    }
+    commands.entity(light).remove::<KajiyaLightProxies>();
+}
+
+/// Spawns the proxy meshes of new lights, updates them when the lights change and despawns
+/// them when the light components are removed. Proxies are unit meshes scaled by their
+/// transform, so only a new color makes a new mesh.
+#[allow(clippy::too_many_arguments)]
+pub fn update_light_proxies(
+    mut commands: Commands,
+    point_lights: Query<
+        (
+            Entity,
+            &KajiyaPointLight,
+            ChangeTrackers<KajiyaPointLight>,
+            Option<&KajiyaLightProxies>,
+        ),
+        Without<KajiyaSpotLight>,
+    >,
+    spot_lights: Query<(
+        Entity,
+        &KajiyaSpotLight,
+        ChangeTrackers<KajiyaSpotLight>,
+        Option<&KajiyaLightProxies>,
+    )>,
+    removed_point_lights: RemovedComponents<KajiyaPointLight>,
+    removed_spot_lights: RemovedComponents<KajiyaSpotLight>,
+    proxies_query: Query<&KajiyaLightProxies>,
+    mut proxy_query: Query<(&mut KajiyaMeshInstance, &mut Transform), With<KajiyaLightProxy>>,
+    mut proxy_meshes: Local<LightProxyMeshes>,
+    mut meshes: ResMut<Assets<KajiyaMeshData>>,
+) {
+    // Proxies of a point light are kept when the entity has a spot light, which they belong to
+    let removed_lights: HashSet<Entity> = removed_point_lights
+        .iter()
+        .filter(|entity| spot_lights.get(*entity).is_err())
+        .chain(removed_spot_lights.iter())
+        .collect();
+    for entity in removed_lights.iter() {
+        if let Ok(proxies) = proxies_query.get(*entity) {
+            despawn_proxies(&mut commands, *entity, proxies);
+        }
+    }
+
+    let mut lights = Vec::new();
+    for (entity, light, tracker, proxies) in point_lights.iter() {
+        if tracker.is_changed() || proxies.is_none() {
+            let emission =
+                point_light_luminance(light.intensity, light.radius) / KAJIYA_UNIT_LUMINANCE;
+            let transform = Transform::from_scale(Vec3::splat(light.radius));
+            lights.push((entity, proxies, light.color, emission, transform, None));
+        }
+    }
+    for (entity, light, tracker, proxies) in spot_lights.iter() {
+        if tracker.is_changed() || proxies.is_none() {
+            let emission = spot_light_luminance(light.intensity, light.radius, light.cone_angle)
+                / KAJIYA_UNIT_LUMINANCE;
+            let (transform, shade_transform) = spot_light_proxy_transforms(light);
+            let shade = Some(shade_transform);
+            lights.push((entity, proxies, light.color, emission, transform, shade));
+        }
+    }
+
+    for (entity, proxies, color, emission, transform, shade_transform) in lights {
+        // Spawned again next frame, once the proxies of the removed light are gone
+        if removed_lights.contains(&entity) {
+            continue;
+        }
+
+        let spot = shade_transform.is_some();
+
+        if let Some(proxies) = proxies {
+            // Unless the light changed kind, ex: a spot light was added to a point light
+            if proxies.shade.is_some() == spot {
+                if let Ok((mut mesh_instance, mut proxy_transform)) =
+                    proxy_query.get_mut(proxies.emitter)
+                {
+                    mesh_instance.emission = emission;
+                    *proxy_transform = transform;
+                }
+                if let (Some(shade), Some(shade_transform)) = (proxies.shade, shade_transform) {
+                    if let Ok((_, mut proxy_transform)) = proxy_query.get_mut(shade) {
+                        *proxy_transform = shade_transform;
+                    }
+                }
+
+                if proxies.color != color_key(color) {
+                    let emitter = proxy_meshes.emitter(&mut meshes, color, spot);
+                    commands.entity(proxies.emitter).insert(emitter);
+                    commands.entity(entity).insert(KajiyaLightProxies {
+                        color: color_key(color),
+                        ..proxies.clone()
+                    });
+                }
+                continue;
+            }
+
+            despawn_proxies(&mut commands, entity, proxies);
+        }
+
+        let emitter = proxy_meshes.emitter(&mut meshes, color, spot);
+        let emitter = spawn_proxy(&mut commands, entity, emitter, transform, emission);
+        let shade = shade_transform.map(|shade_transform| {
+            let shade = proxy_meshes.shade(&mut meshes);
+            spawn_proxy(&mut commands, entity, shade, shade_transform, 1.0)
+        });
+
+        commands.entity(entity).insert(KajiyaLightProxies {
+            emitter,
+            shade,
+            color: color_key(color),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() <= 1e-4 * b.abs().max(1.0), "{} != {}", a, b);
+    }
+
+    /// Checks that every triangle is counter-clockwise seen from the side its normals face.
+    fn assert_faces_its_normals(mesh: &KajiyaMeshData) {
+        mesh.validate().unwrap();
+
+        for triangle in mesh.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(mesh.positions[triangle[i] as usize]));
+            let face_normal = (b - a).cross(c - a);
+            // Triangles at the poles of the sphere are degenerate
+            if face_normal.length() < 1e-6 {
+                continue;
+            }
+
+            for index in triangle {
+                let normal = Vec3::from(mesh.normals[*index as usize]);
+                assert_close(normal.length(), 1.0);
+                assert!(face_normal.dot(normal) > 0.0, "{:?}", triangle);
+            }
+        }
+    }
+
+    #[test]
+    fn point_light_emits_its_intensity() {
+        let (intensity, radius) = (800.0, 0.05);
+        let luminance = point_light_luminance(intensity, radius);
+
+        // Exitance of a Lambertian surface is pi times its luminance
+        let area = 4.0 * PI * radius * radius;
+        assert_close(PI * luminance * area, intensity);
+    }
+
+    #[test]
+    fn spot_light_emits_its_intensity_within_the_cone() {
+        let (intensity, radius) = (800.0, 0.05);
+        let area = PI * radius * radius;
+
+        // A disc without shade sends all its light in its hemisphere
+        let hemisphere = spot_light_luminance(intensity, radius, PI / 2.0);
+        assert_close(PI * hemisphere * area, intensity);
+
+        let cone_angle = PI / 6.0;
+        let luminance = spot_light_luminance(intensity, radius, cone_angle);
+        let within_cone = PI * luminance * area * cone_angle.sin().powi(2);
+        assert_close(within_cone, intensity);
+        assert!(luminance > hemisphere);
+    }
+
+    #[test]
+    fn spot_shade_cuts_the_cone() {
+        let radius = 0.05;
+        let (cone_angle, inner_cone_angle) = (PI / 4.0, PI / 8.0);
+        let (shade_radius, depth) = spot_shade_size(radius, cone_angle, inner_cone_angle);
+
+        // Where a ray from the disc crosses the rim of the shade, along the plane of the axis
+        let rim_offset = |start: f32, angle: f32| start + depth * angle.tan();
+        let starts = (0..=10).map(|i| radius * (i as f32 / 5.0 - 1.0));
+
+        for start in starts {
+            assert!(rim_offset(start, inner_cone_angle) <= shade_radius + 1e-5);
+            assert!(rim_offset(start, cone_angle + 0.01) > shade_radius);
+        }
+        assert_close(rim_offset(radius, inner_cone_angle), shade_radius);
+        assert_close(rim_offset(-radius, cone_angle), shade_radius);
+    }
+
+    #[test]
+    fn spot_shade_size_clamps_the_angles() {
+        let (shade_radius, depth) = spot_shade_size(0.05, PI, PI);
+        assert!(shade_radius.is_finite() && depth.is_finite());
+        assert!(depth > 0.0 && shade_radius >= 0.05);
+    }
+
+    #[test]
+    fn sphere_faces_outward() {
+        let sphere = uv_sphere(2.0, 8, 4);
+        assert_faces_its_normals(&sphere);
+
+        for (position, normal) in sphere.positions.iter().zip(sphere.normals.iter()) {
+            assert_close(Vec3::from(*position).length(), 2.0);
+            assert!(Vec3::from(*position).dot(Vec3::from(*normal)) > 0.0);
+        }
+    }
+
+    #[test]
+    fn discs_face_their_side() {
+        let forward = disc(1.0, 8, 0.5, true);
+        assert_faces_its_normals(&forward);
+        assert_eq!(forward.normals[0], [0.0, 0.0, -1.0]);
+        assert_eq!(forward.indices.len(), 8 * 3);
+
+        let backward = disc(1.0, 8, 0.5, false);
+        assert_faces_its_normals(&backward);
+        assert_eq!(backward.normals[0], [0.0, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn cylinder_faces_both_sides() {
+        let cylinder = open_cylinder(1.0, 2.0, 8);
+        assert_faces_its_normals(&cylinder);
+
+        let (outside, inside) = cylinder.normals.split_at(cylinder.normals.len() / 2);
+        let positions = &cylinder.positions[..outside.len()];
+        for ((position, out), inn) in positions.iter().zip(outside).zip(inside) {
+            assert!(Vec3::from(*position).dot(Vec3::from(*out)) > 0.0);
+            assert_eq!(Vec3::from(*inn), -Vec3::from(*out));
+            assert!((-2.0..=0.0).contains(&position[2]));
+        }
+    }
+}
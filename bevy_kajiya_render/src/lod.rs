@@ -0,0 +1,215 @@
+use bevy::prelude::*;
+
+use crate::{
+    camera::ExtractedCamera,
+    mesh::MeshInstanceExtracted,
+    render_instances::{
+        LoadedMeshesMap, RenderInstance, RenderInstancesMap, RenderMesh, WRInstance,
+    },
+    world_renderer::{WRCommandQueue, WorldRendererCommand},
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaLodLevel {
+    /// Mesh source, like [`KajiyaMeshInstance::mesh`](crate::KajiyaMeshInstance::mesh).
+    pub mesh: String,
+    /// Camera distance up to which this level is used.
+    pub max_distance: f32,
+}
+
+/// Level-of-detail group. When added next to a [`KajiyaMeshInstance`](crate::KajiyaMeshInstance),
+/// the instance's mesh is picked from `levels` based on its distance to the camera, and the
+/// instance's own `mesh` is ignored.
+///
+/// Levels go from the most to the least detailed, the last one being used past every
+/// `max_distance`. Meshes are loaded the first time they are needed, and the previous level is
+/// kept on screen until the next one is ready.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct KajiyaLod {
+    pub levels: Vec<KajiyaLodLevel>,
+    /// Fraction of a level's `max_distance` the camera has to move past before switching, so
+    /// instances close to a threshold don't keep swapping meshes.
+    pub hysteresis: f32,
+}
+
+impl Default for KajiyaLod {
+    fn default() -> Self {
+        Self {
+            levels: Vec::new(),
+            hysteresis: 0.1,
+        }
+    }
+}
+
+impl KajiyaLod {
+    pub fn new(levels: impl IntoIterator<Item = (impl Into<String>, f32)>) -> Self {
+        Self {
+            levels: levels
+                .into_iter()
+                .map(|(mesh, max_distance)| KajiyaLodLevel {
+                    mesh: mesh.into(),
+                    max_distance,
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// The level to use at `distance`, coming from the `current` level.
+    pub fn select_level(&self, current: usize, distance: f32) -> usize {
+        if self.levels.is_empty() {
+            return 0;
+        }
+
+        let mut level = current.min(self.levels.len() - 1);
+        while level + 1 < self.levels.len()
+            && distance > self.levels[level].max_distance * (1.0 + self.hysteresis)
+        {
+            level += 1;
+        }
+        while level > 0 && distance < self.levels[level - 1].max_distance * (1.0 - self.hysteresis)
+        {
+            level -= 1;
+        }
+
+        level
+    }
+}
+
+/// Picks the level of detail of instances with a [`KajiyaLod`], and swaps their mesh once the
+/// mesh of the new level is loaded.
+pub fn select_lods(
+    query_extracted_instances: Query<&MeshInstanceExtracted>,
+    extracted_camera: Res<ExtractedCamera>,
+    mut wr_command_queue: ResMut<WRCommandQueue>,
+    mut ri_map: ResMut<RenderInstancesMap>,
+    mut lm_map: ResMut<LoadedMeshesMap>,
+) {
+    let camera_position = extracted_camera.transform.0;
+
+    for extracted_instance in query_extracted_instances.iter() {
+        let lod = match extracted_instance.lod.as_ref() {
+            Some(lod) if !lod.levels.is_empty() => lod,
+            _ => continue,
+        };
+
+        let distance = extracted_instance
+            .transform
+            .position()
+            .distance(camera_position);
+
+        let render_instance = ri_map
+            .entry(extracted_instance.instance_entity)
+            .or_insert_with(|| {
+                let lod_level = lod.select_level(lod.levels.len() - 1, distance);
+                RenderInstance {
+                    mesh_name: extracted_instance.mesh_key(&lod.levels[lod_level].mesh),
+                    transform: extracted_instance.transform,
+                    instance: WRInstance::None,
+                    active: true,
+                    lod_level,
+                }
+            });
+
+        if !lm_map.contains_key(&render_instance.mesh_name) {
+            lm_map.insert(render_instance.mesh_name.clone(), RenderMesh::Empty);
+        }
+
+        let lod_level = lod.select_level(render_instance.lod_level, distance);
+        let mesh_name = extracted_instance.mesh_key(&lod.levels[lod_level].mesh);
+
+        if mesh_name == render_instance.mesh_name {
+            render_instance.lod_level = lod_level;
+            continue;
+        }
+
+        match lm_map.get(&mesh_name) {
+            Some(RenderMesh::Ready(_)) => match render_instance.instance {
+                WRInstance::Ready(inst_handle) => {
+                    wr_command_queue.push(WorldRendererCommand::RemoveInstance(inst_handle));
+                    render_instance.instance = WRInstance::None;
+                }
+                WRInstance::None => {}
+                // Wait for the instance of the previous level to be added
+                WRInstance::Queued => continue,
+            },
+            Some(_) => continue,
+            None => {
+                // Start loading the mesh of the new level
+                lm_map.insert(mesh_name, RenderMesh::Empty);
+                continue;
+            }
+        }
+
+        render_instance.mesh_name = mesh_name;
+        render_instance.lod_level = lod_level;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Levels switching at 10 and 20, with a 10% hysteresis band.
+    fn lod() -> KajiyaLod {
+        KajiyaLod::new([("high", 10.0), ("mid", 20.0), ("low", 40.0)])
+    }
+
+    #[test]
+    fn moving_away_switches_past_the_band() {
+        let lod = lod();
+
+        assert_eq!(lod.select_level(0, 10.5), 0);
+        assert_eq!(lod.select_level(0, 11.0), 0);
+        assert_eq!(lod.select_level(0, 11.5), 1);
+        assert_eq!(lod.select_level(1, 21.5), 1);
+        assert_eq!(lod.select_level(1, 22.5), 2);
+    }
+
+    #[test]
+    fn moving_closer_switches_past_the_band() {
+        let lod = lod();
+
+        assert_eq!(lod.select_level(1, 9.5), 1);
+        assert_eq!(lod.select_level(1, 9.0), 1);
+        assert_eq!(lod.select_level(1, 8.5), 0);
+        assert_eq!(lod.select_level(2, 18.5), 2);
+        assert_eq!(lod.select_level(2, 17.5), 1);
+    }
+
+    #[test]
+    fn the_band_keeps_either_level() {
+        let lod = lod();
+
+        // Between 9 and 11, the current level is kept, whichever it is
+        for distance in [9.5, 10.0, 10.5] {
+            assert_eq!(lod.select_level(0, distance), 0);
+            assert_eq!(lod.select_level(1, distance), 1);
+        }
+    }
+
+    #[test]
+    fn the_last_level_is_used_past_every_distance() {
+        let lod = lod();
+
+        assert_eq!(lod.select_level(0, 1000.0), 2);
+        assert_eq!(lod.select_level(2, 1000.0), 2);
+        assert_eq!(lod.select_level(2, 0.0), 0);
+        // Levels removed since the last selection
+        assert_eq!(lod.select_level(5, 1000.0), 2);
+        assert_eq!(KajiyaLod::default().select_level(3, 5.0), 0);
+    }
+
+    #[test]
+    fn no_hysteresis_switches_at_the_thresholds() {
+        let lod = KajiyaLod {
+            hysteresis: 0.0,
+            ..lod()
+        };
+
+        assert_eq!(lod.select_level(0, 10.0), 0);
+        assert_eq!(lod.select_level(0, 10.01), 1);
+        assert_eq!(lod.select_level(1, 10.0), 1);
+        assert_eq!(lod.select_level(1, 9.99), 0);
+    }
+}
@@ -0,0 +1,248 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use bevy::{prelude::*, utils::HashMap};
+
+/// Overrides the materials of a [`KajiyaMeshInstance`](crate::KajiyaMeshInstance)'s mesh.
+///
+/// With the `instance_materials` feature, the override is set in the dynamic parameters of the
+/// kajiya instance, next to the emissive multiplier of
+/// [`KajiyaMeshInstance::emission`](crate::KajiyaMeshInstance::emission), so it can be animated
+/// freely and nothing is baked.
+///
+/// Without it, kajiya's only per-instance material parameter is the emissive multiplier, so the
+/// override is applied to the glTF materials and baked into a variant of the mesh, shared by the
+/// instances with the same override. Values are then rounded to steps of
+/// [`MATERIAL_OVERRIDE_STEP`], but each step still bakes a new mesh which kajiya never frees:
+/// only use a few distinct overrides.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct KajiyaMaterialOverride {
+    /// Linear RGBA multiplier of the base color of every material.
+    pub base_color_tint: Vec4,
+    pub roughness_multiplier: f32,
+    pub metalness_multiplier: f32,
+    /// Linear RGB emissive color replacing the materials' own. Emissive textures are kept and
+    /// multiplied by it, like in glTF.
+    pub emissive_color: Option<Vec3>,
+}
+
+impl Default for KajiyaMaterialOverride {
+    fn default() -> Self {
+        Self {
+            base_color_tint: Vec4::ONE,
+            roughness_multiplier: 1.0,
+            metalness_multiplier: 1.0,
+            emissive_color: None,
+        }
+    }
+}
+
+/// Resolution of the values of a [`KajiyaMaterialOverride`], like 8-bit colors.
+pub const MATERIAL_OVERRIDE_STEP: f32 = 1.0 / 256.0;
+
+fn quantize(value: f32) -> f32 {
+    (value / MATERIAL_OVERRIDE_STEP).round() * MATERIAL_OVERRIDE_STEP
+}
+
+impl KajiyaMaterialOverride {
+    /// The override actually baked, with its values rounded to [`MATERIAL_OVERRIDE_STEP`].
+    pub fn quantized(&self) -> Self {
+        Self {
+            base_color_tint: Vec4::from_array(self.base_color_tint.to_array().map(quantize)),
+            roughness_multiplier: quantize(self.roughness_multiplier),
+            metalness_multiplier: quantize(self.metalness_multiplier),
+            emissive_color: self
+                .emissive_color
+                .map(|color| Vec3::from_array(color.to_array().map(quantize))),
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.quantized() == Self::default()
+    }
+
+    /// Whether instances with this override render a baked variant of their mesh, see
+    /// [`KajiyaMaterialOverride`].
+    pub fn bakes_variant(&self) -> bool {
+        !cfg!(feature = "instance_materials") && !self.is_identity()
+    }
+
+    /// Identifies the mesh variant baked with this override, shared by the overrides rounding
+    /// to the same values.
+    pub fn variant_id(&self) -> u64 {
+        let quantized = self.quantized();
+        let mut hasher = DefaultHasher::new();
+        for value in quantized.base_color_tint.to_array() {
+            value.to_bits().hash(&mut hasher);
+        }
+        quantized.roughness_multiplier.to_bits().hash(&mut hasher);
+        quantized.metalness_multiplier.to_bits().hash(&mut hasher);
+        quantized
+            .emissive_color
+            .map(|color| color.to_array().map(f32::to_bits))
+            .hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Applies the override to every material of a glTF document.
+    pub fn apply_to_gltf(&self, root: &mut gltf::json::Root) {
+        let quantized = self.quantized();
+        for material in root.materials.iter_mut() {
+            let pbr = &mut material.pbr_metallic_roughness;
+
+            let base_color = Vec4::from(pbr.base_color_factor.0) * quantized.base_color_tint;
+            pbr.base_color_factor.0 = base_color.clamp(Vec4::ZERO, Vec4::ONE).to_array();
+            pbr.roughness_factor.0 =
+                (pbr.roughness_factor.0 * quantized.roughness_multiplier).clamp(0.0, 1.0);
+            pbr.metallic_factor.0 =
+                (pbr.metallic_factor.0 * quantized.metalness_multiplier).clamp(0.0, 1.0);
+
+            if let Some(emissive_color) = quantized.emissive_color {
+                material.emissive_factor.0 = emissive_color.max(Vec3::ZERO).to_array();
+            }
+        }
+    }
+}
+
+const MATERIAL_VARIANT_SEPARATOR: &str = "#material";
+
+/// Name of the variant of `mesh` baked with `material`, or `mesh` itself when it doesn't bake
+/// one.
+pub fn material_mesh_name(mesh: &str, material: Option<&KajiyaMaterialOverride>) -> String {
+    match material {
+        Some(material) if material.bakes_variant() => format!(
+            "{}{}{:016x}",
+            mesh,
+            MATERIAL_VARIANT_SEPARATOR,
+            material.variant_id()
+        ),
+        _ => mesh.to_string(),
+    }
+}
+
+/// Splits a name from [`material_mesh_name`] into the mesh name and the variant id, if any.
+pub fn parse_material_mesh_name(mesh: &str) -> (&str, Option<u64>) {
+    if let Some((base, id)) = mesh.rsplit_once(MATERIAL_VARIANT_SEPARATOR) {
+        if let Ok(id) = u64::from_str_radix(id, 16) {
+            return (base, Some(id));
+        }
+    }
+
+    (mesh, None)
+}
+
+/// The overrides of the material variants requested by the instances of the current frame, by
+/// variant id, so meshes can be baked from their key alone.
+#[derive(Clone, Default)]
+pub struct MaterialVariants(pub HashMap<u64, KajiyaMaterialOverride>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn close_overrides_share_a_variant() {
+        let material = KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(1.0, 0.2, 0.2, 1.0),
+            ..Default::default()
+        };
+        let animated = KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(1.0, 0.2001, 0.2, 1.0),
+            ..Default::default()
+        };
+        assert_eq!(material.variant_id(), animated.variant_id());
+
+        let other = KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(1.0, 0.21, 0.2, 1.0),
+            ..Default::default()
+        };
+        assert_ne!(material.variant_id(), other.variant_id());
+
+        let almost_identity = KajiyaMaterialOverride {
+            roughness_multiplier: 1.0001,
+            ..Default::default()
+        };
+        assert!(almost_identity.is_identity());
+    }
+
+    #[test]
+    fn quantized_values_are_multiples_of_the_step() {
+        let material = KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(0.2, 1.0, 0.0, 1.0),
+            roughness_multiplier: 0.5001,
+            emissive_color: Some(Vec3::new(3.0, 0.001, 0.0)),
+            ..Default::default()
+        };
+
+        let quantized = material.quantized();
+
+        assert_eq!(quantized.base_color_tint.x, 51.0 / 256.0);
+        assert_eq!(quantized.roughness_multiplier, 0.5);
+        assert_eq!(quantized.emissive_color, Some(Vec3::new(3.0, 0.0, 0.0)));
+        assert_eq!(quantized.quantized(), quantized);
+    }
+
+    #[cfg(not(feature = "instance_materials"))]
+    #[test]
+    fn material_mesh_names_round_trip() {
+        let material = KajiyaMaterialOverride {
+            metalness_multiplier: 0.0,
+            ..Default::default()
+        };
+
+        let name = material_mesh_name("car#node3", Some(&material));
+        assert_eq!(
+            parse_material_mesh_name(&name),
+            ("car#node3", Some(material.variant_id()))
+        );
+
+        assert_eq!(
+            material_mesh_name("car", Some(&KajiyaMaterialOverride::default())),
+            "car"
+        );
+        assert_eq!(material_mesh_name("car", None), "car");
+        assert_eq!(parse_material_mesh_name("car#node3"), ("car#node3", None));
+    }
+
+    #[cfg(feature = "instance_materials")]
+    #[test]
+    fn instance_materials_bake_no_variant() {
+        let material = KajiyaMaterialOverride {
+            metalness_multiplier: 0.0,
+            ..Default::default()
+        };
+
+        assert!(!material.bakes_variant());
+        assert_eq!(material_mesh_name("car", Some(&material)), "car");
+    }
+
+    #[test]
+    fn overrides_apply_to_every_gltf_material() {
+        let mut root = gltf::json::Root::default();
+        let mut red = gltf::json::Material::default();
+        red.pbr_metallic_roughness.base_color_factor.0 = [0.5, 0.5, 0.5, 1.0];
+        red.emissive_factor.0 = [0.0, 0.0, 1.0];
+        root.materials = vec![red, gltf::json::Material::default()];
+
+        KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(4.0, 1.0, 0.5, 1.0),
+            roughness_multiplier: 0.5,
+            metalness_multiplier: 2.0,
+            emissive_color: Some(Vec3::new(1.0, 0.0, 0.0)),
+        }
+        .apply_to_gltf(&mut root);
+
+        let pbr = &root.materials[0].pbr_metallic_roughness;
+        // Clamped to the valid range of the factors
+        assert_eq!(pbr.base_color_factor.0, [1.0, 0.5, 0.25, 1.0]);
+        assert_eq!(pbr.roughness_factor.0, 0.5);
+        assert_eq!(pbr.metallic_factor.0, 1.0);
+        assert_eq!(root.materials[0].emissive_factor.0, [1.0, 0.0, 0.0]);
+
+        let pbr = &root.materials[1].pbr_metallic_roughness;
+        assert_eq!(pbr.base_color_factor.0, [1.0, 1.0, 0.5, 1.0]);
+        assert_eq!(root.materials[1].emissive_factor.0, [1.0, 0.0, 0.0]);
+    }
+}
@@ -1,17 +1,28 @@
 use bevy::{math, prelude::*, utils::HashMap};
-use glam::{Quat, Vec3};
+use glam::{Affine3A, Mat4, Quat, Vec3};
 use kajiya::backend::canonical_path_from_vfs;
 use kajiya::world_renderer::{InstanceHandle, MeshHandle};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, HashSet},
     hash::{Hash, Hasher},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
-use crate::{asset::register_unique_gltf_asset, plugin::RenderWorld};
+use crate::{
+    asset::register_unique_gltf_asset,
+    bounds::gltf_scene_bounds,
+    gltf_nodes::restrict_gltf_to_node,
+    lod::KajiyaLod,
+    material::{
+        material_mesh_name, parse_material_mesh_name, KajiyaMaterialOverride, MaterialVariants,
+    },
+    mirror::write_mirrored_gltf,
+    plugin::RenderWorld,
+    runtime_mesh::{parse_runtime_mesh_name, runtime_mesh_name, KajiyaMeshData, PackedMesh},
+};
 
 /// An Axis-Aligned Bounding Box
-#[derive(Component, Clone, Debug, Default, Reflect)]
+#[derive(Component, Clone, Debug, Default, PartialEq, Reflect)]
 #[reflect(Component)]
 pub struct Aabb {
     pub center: math::Vec3,
@@ -66,7 +77,6 @@ pub struct RenderInstance {
 pub struct RenderInstances {
     pub user_instances: HashMap<Entity, RenderInstance>,
     pub unique_loaded_meshes: HashMap<String, MeshHandle>,
-    pub scene_mesh_instance_queue: Vec<(KajiyaMeshInstance, Transform)>,
 }
 
 #[derive(Bundle, Default)]
@@ -76,18 +86,15 @@ pub struct KajiyaMeshInstanceBundle {
     pub global_transform: GlobalTransform,
 }
 
-#[derive(Clone)]
-pub enum MeshInstanceType {
-    UserInstanced(Entity),
-    SceneInstanced(usize),
-}
-
 #[derive(Component, Reflect, Clone)]
 #[reflect(Component)]
 pub struct KajiyaMeshInstance {
     pub mesh: String,
     pub emission: f32,
     pub selection_bb_size: f32,
+    /// Distance from the camera beyond which the instance is removed from the scene, when
+    /// [`KajiyaCulling`](crate::KajiyaCulling) is enabled.
+    pub max_draw_distance: f32,
 }
 
 impl Default for KajiyaMeshInstance {
@@ -96,15 +103,217 @@ impl Default for KajiyaMeshInstance {
             mesh: Default::default(),
             emission: 1.0,
             selection_bb_size: 1.0,
+            max_draw_distance: f32::INFINITY,
         }
     }
 }
 
-#[derive(Component, Clone, Copy)]
+/// World transform of an instance, kept as a full affine matrix so shear from non-uniformly
+/// scaled parents survives.
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
 pub struct MeshTransform {
-    pub position: Vec3,
-    pub rotation: Quat,
-    pub scale: Vec3,
+    pub affine: Affine3A,
+}
+
+impl MeshTransform {
+    pub fn from_global_transform(transform: &GlobalTransform) -> Self {
+        let matrix = Mat4::from_cols_array(&transform.compute_matrix().to_cols_array());
+        Self {
+            affine: Affine3A::from_mat4(matrix),
+        }
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.affine.translation.into()
+    }
+
+    /// Whether the transform flips handedness, which would turn the mesh inside out.
+    pub fn is_mirrored(&self) -> bool {
+        self.affine.matrix3.determinant() < 0.0
+    }
+
+    /// The key of `mesh` to render with this transform: mirrored transforms use a copy of the
+    /// mesh with its triangles wound the other way, see [`write_mirrored_gltf`].
+    pub fn mesh_key(&self, mesh: &str) -> String {
+        mesh_key(mesh, self.is_mirrored())
+    }
+}
+
+const MIRRORED_MESH_SUFFIX: &str = "#mirrored";
+
+/// Key of a mesh in the [`LoadedMeshesMap`](crate::render_instances::LoadedMeshesMap).
+pub fn mesh_key(mesh: &str, mirrored: bool) -> String {
+    if mirrored {
+        format!("{}{}", mesh, MIRRORED_MESH_SUFFIX)
+    } else {
+        mesh.to_string()
+    }
+}
+
+/// Splits a key from [`mesh_key`] into the mesh name and whether it is mirrored.
+pub fn parse_mesh_key(key: &str) -> (&str, bool) {
+    match key.strip_suffix(MIRRORED_MESH_SUFFIX) {
+        Some(mesh) => (mesh, true),
+        None => (key, false),
+    }
+}
+
+const NODE_MESH_SEPARATOR: &str = "#node";
+
+/// Name of the mesh of a single node of a glTF scene, see
+/// [`KajiyaGltfNodes`](crate::KajiyaGltfNodes).
+pub fn node_mesh_name(mesh: &str, node: usize) -> String {
+    format!("{}{}{}", mesh, NODE_MESH_SEPARATOR, node)
+}
+
+/// Splits a mesh name into the name of its glTF source and the node it is restricted to, if any.
+/// Material variants are named after the node mesh, see [`parse_mesh_variant`].
+pub fn parse_node_mesh_name(mesh: &str) -> (&str, Option<usize>) {
+    if let Some((source, node)) = mesh.rsplit_once(NODE_MESH_SEPARATOR) {
+        if let Ok(node) = node.parse() {
+            return (source, Some(node));
+        }
+    }
+
+    (mesh, None)
+}
+
+/// Splits a mesh name into its glTF source name, node and material variant id.
+pub fn parse_mesh_variant(mesh: &str) -> (&str, Option<usize>, Option<u64>) {
+    let (mesh, material) = parse_material_mesh_name(mesh);
+    let (source, node) = parse_node_mesh_name(mesh);
+
+    (source, node, material)
+}
+
+/// Path of the glTF source of a mesh, ex: "my_mesh" -> "assets/meshes/my_mesh/scene.gltf".
+/// Node meshes and material variants are generated in the cache, ex: "my_mesh#node3" ->
+/// "cache/meshes/my_mesh/scene.node3.gltf", see [`write_mesh_key_gltf`].
+///
+/// Runtime meshes have no source file and are uploaded from memory, their path only names them.
+pub fn mesh_source_path(mesh: &str) -> PathBuf {
+    mesh_gltf_path(mesh, false)
+}
+
+/// Path of the glTF a [`mesh_key`] is baked from: [`mesh_source_path`], or the mirrored copy
+/// generated in the cache, ex: "my_mesh#mirrored" -> "cache/meshes/my_mesh/scene.mirrored.gltf".
+pub fn mesh_key_source_path(key: &str) -> PathBuf {
+    let (mesh, mirrored) = parse_mesh_key(key);
+    mesh_gltf_path(mesh, mirrored)
+}
+
+fn mesh_gltf_path(mesh: &str, mirrored: bool) -> PathBuf {
+    let (source, node, material) = parse_mesh_variant(mesh);
+    let mirrored = if mirrored { ".mirrored" } else { "" };
+
+    if let Some(id) = parse_runtime_mesh_name(source) {
+        let material = material.map_or(String::new(), |material| format!(".{:016x}", material));
+        return format!("runtime/{:016x}{}{}", id, material, mirrored).into();
+    }
+
+    let mut file_name = "scene".to_string();
+    if let Some(node) = node {
+        file_name += &format!(".node{}", node);
+    }
+    if let Some(material) = material {
+        file_name += &format!(".material{:016x}", material);
+    }
+    file_name += mirrored;
+
+    if file_name == "scene" {
+        format!("assets/meshes/{}/scene.gltf", source).into()
+    } else {
+        generated_gltf_dir(source).join(file_name + ".gltf")
+    }
+}
+
+/// Directory of the glTF files generated from the source of `mesh`, in the cache which is
+/// cleared at startup.
+pub fn generated_gltf_dir(mesh: &str) -> PathBuf {
+    let (source, _, _) = parse_mesh_variant(mesh);
+    format!("cache/meshes/{}", source).into()
+}
+
+/// Writes the glTF files a [`mesh_key`] is baked from, unless they are in `generated` already:
+/// the node mesh or material variant, then its mirrored copy. `material` is the override of
+/// material variants.
+///
+/// The files are written to the cache, with absolute buffer and image URIs. Keys sharing a file
+/// are loaded in parallel, so it must be written before their bakes start.
+pub fn write_mesh_key_gltf(
+    key: &str,
+    material: Option<&KajiyaMaterialOverride>,
+    generated: &mut HashSet<PathBuf>,
+) -> anyhow::Result<()> {
+    let (mesh, mirrored) = parse_mesh_key(key);
+    let (source, node, material_id) = parse_mesh_variant(mesh);
+    if parse_runtime_mesh_name(source).is_some() {
+        return Ok(());
+    }
+
+    let variant_path = mesh_source_path(mesh);
+    if (node.is_some() || material_id.is_some()) && !generated.contains(&variant_path) {
+        write_mesh_variant(mesh, material, &variant_path)?;
+        generated.insert(variant_path.clone());
+    }
+
+    let path = mesh_key_source_path(key);
+    if mirrored && !generated.contains(&path) {
+        std::fs::create_dir_all(generated_gltf_dir(mesh))?;
+        write_mirrored_gltf(&variant_path, &path)?;
+        generated.insert(path);
+    }
+
+    Ok(())
+}
+
+fn write_mesh_variant(
+    mesh: &str,
+    material: Option<&KajiyaMaterialOverride>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let (source, node, material_id) = parse_mesh_variant(mesh);
+
+    let source_path = mesh_source_path(source);
+    let mut root = gltf::Gltf::open(&source_path)?.document.into_json();
+    make_uris_absolute(&mut root, &source_path)?;
+
+    if let Some(node) = node {
+        restrict_gltf_to_node(&mut root, node)?;
+    }
+    if material_id.is_some() {
+        material
+            .ok_or_else(|| anyhow::anyhow!("Unknown material variant of {:?}", source_path))?
+            .apply_to_gltf(&mut root);
+    }
+
+    std::fs::create_dir_all(generated_gltf_dir(mesh))?;
+    std::fs::write(path, root.to_string_pretty()?)?;
+
+    Ok(())
+}
+
+/// Makes the relative buffer and image URIs of the glTF document at `path` absolute, so a copy
+/// of the document can be written elsewhere.
+pub fn make_uris_absolute(root: &mut gltf::json::Root, path: &Path) -> anyhow::Result<()> {
+    let base = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("No directory for {:?}", path))?
+        .canonicalize()?;
+
+    let uris = root
+        .buffers
+        .iter_mut()
+        .map(|buffer| &mut buffer.uri)
+        .chain(root.images.iter_mut().map(|image| &mut image.uri));
+    for uri in uris.flatten() {
+        // Data URIs contain a colon, and other schemes aren't supported by kajiya
+        if !uri.contains(':') && !uri.starts_with('/') {
+            *uri = base.join(&*uri).to_string_lossy().replace('\\', "/");
+        }
+    }
+
+    Ok(())
 }
 
 #[derive(Component, Clone)]
@@ -113,6 +322,19 @@ pub struct MeshInstanceExtracted {
     pub mesh_name: String,
     pub transform: MeshTransform,
     pub emission: f32,
+    pub max_draw_distance: f32,
+    pub aabb: Option<Aabb>,
+    pub lod: Option<KajiyaLod>,
+    pub material: Option<KajiyaMaterialOverride>,
+}
+
+impl MeshInstanceExtracted {
+    /// The key of `mesh` to render this instance with, given its material override and
+    /// transform.
+    pub fn mesh_key(&self, mesh: &str) -> String {
+        self.transform
+            .mesh_key(&material_mesh_name(mesh, self.material.as_ref()))
+    }
 }
 
 #[derive(Bundle, Clone)]
@@ -123,40 +345,99 @@ pub struct MeshInstanceExtractedBundle {
 // TODO: query for KajiyaMeshInstance(s) and internal render entity accordingly
 // NOTE: don't forget to drain entities before next cycle to avoid entity duplicates
 pub fn extract_meshes(
-    query: Query<(Entity, &GlobalTransform, &KajiyaMeshInstance)>,
+    query: Query<(
+        Entity,
+        &GlobalTransform,
+        &KajiyaMeshInstance,
+        Option<&Aabb>,
+        Option<&KajiyaLod>,
+        Option<&KajiyaMaterialOverride>,
+        Option<&Handle<KajiyaMeshData>>,
+    )>,
     mut render_world: ResMut<RenderWorld>,
     mut asset_server: ResMut<AssetServer>,
 ) {
     let mut mesh_instances: Vec<MeshInstanceExtractedBundle> = vec![];
+    let mut material_variants = HashMap::default();
+
+    for (entity, transform, mesh_instance, aabb, lod, material, runtime_mesh) in query.iter() {
+        let transform = MeshTransform::from_global_transform(transform);
 
-    for (entity, transform, mesh_instance) in query.iter() {
-        let (scale, rotation, position) = transform.to_scale_rotation_translation();
-        let position_decomp: (f32, f32, f32) = position.into();
-        let rotation_decomp: [f32; 4] = rotation.into();
-        let scale_decomp: (f32, f32, f32) = scale.into();
+        if let Some(material) = material.filter(|material| material.bakes_variant()) {
+            material_variants
+                .entry(material.variant_id())
+                .or_insert_with(|| material.quantized());
+        }
+
+        let mesh = match runtime_mesh {
+            Some(runtime_mesh) => runtime_mesh_name(runtime_mesh),
+            None => mesh_instance.mesh.clone(),
+        };
 
-        register_unique_gltf_asset(&mut asset_server, &mut render_world, &mesh_instance.mesh);
+        // Like dynamic meshes waiting for their first upload
+        if mesh.is_empty() && lod.is_none() {
+            continue;
+        }
+
+        match (lod, runtime_mesh) {
+            (Some(lod), _) => {
+                for level in lod.levels.iter() {
+                    register_unique_gltf_asset(&mut asset_server, &mut render_world, &level.mesh);
+                }
+            }
+            (None, None) => register_unique_gltf_asset(&mut asset_server, &mut render_world, &mesh),
+            (None, Some(_)) => {}
+        }
+
+        let mut extracted_instance = MeshInstanceExtracted {
+            instance_entity: entity,
+            mesh_name: String::new(),
+            transform,
+            emission: mesh_instance.emission,
+            max_draw_distance: mesh_instance.max_draw_distance,
+            aabb: aabb.cloned(),
+            lod: lod.cloned(),
+            material: material.cloned(),
+        };
+        extracted_instance.mesh_name = extracted_instance.mesh_key(&mesh);
 
         mesh_instances.push(MeshInstanceExtractedBundle {
-            mesh_instance: MeshInstanceExtracted {
-                instance_entity: entity,
-                mesh_name: mesh_instance.mesh.to_string(),
-                transform: MeshTransform {
-                    position: Vec3::from(position_decomp),
-                    rotation: Quat::from_array(rotation_decomp),
-                    scale: Vec3::from(scale_decomp),
-                },
-                emission: mesh_instance.emission,
-            },
+            mesh_instance: extracted_instance,
         });
     }
 
+    // Variants no longer requested aren't baked anymore, see `process_renderer_meshes`
+    render_world.insert_resource(MaterialVariants(material_variants));
     render_world.spawn_batch(mesh_instances);
 }
 
-pub fn load_mesh(path: &PathBuf) -> anyhow::Result<PathBuf> {
-    log::info!("Loading a mesh from {:?}", path);
+/// Where kajiya reads a baked mesh from.
+pub enum BakedMeshData {
+    /// A file of the baked mesh cache.
+    Cached(PathBuf),
+    /// A packed mesh in memory, for runtime meshes.
+    InMemory(PackedMesh),
+}
 
+pub struct BakedMesh {
+    pub data: BakedMeshData,
+    pub cache_hit: bool,
+    /// Local-space bounds of the mesh source, if they could be read. They don't include the
+    /// bake scale, like instance [`Aabb`]s.
+    pub bounds: Option<Aabb>,
+}
+
+fn source_mesh_bounds(path: &PathBuf) -> Option<Aabb> {
+    match gltf_scene_bounds(path) {
+        Ok(bounds) => Some(bounds),
+        Err(e) => {
+            log::warn!("Couldn't compute the bounds of {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+fn cached_mesh_name(path: &PathBuf) -> String {
     fn calculate_hash(t: &PathBuf) -> u64 {
         let mut s = DefaultHasher::new();
         t.hash(&mut s);
@@ -168,19 +449,46 @@ pub fn load_mesh(path: &PathBuf) -> anyhow::Result<PathBuf> {
         Err(_) => calculate_hash(path),
     };
 
-    let cached_mesh_name = format!("{:8.8x}", path_hash);
-    let cached_mesh_path = PathBuf::from(format!("/cache/{}.mesh", cached_mesh_name));
-
-    if !canonical_path_from_vfs(&cached_mesh_path).map_or(false, |path| path.exists()) {
-        if let Ok(()) =
-            kajiya_asset_pipe::process_mesh_asset(kajiya_asset_pipe::MeshAssetProcessParams {
-                path: path.clone(),
-                output_name: cached_mesh_name,
-                scale: 1.0,
-            })
-        {
-            return Ok(cached_mesh_path);
-        }
+    format!("{:8.8x}", path_hash)
+}
+
+pub fn cached_mesh_path(path: &PathBuf) -> PathBuf {
+    PathBuf::from(format!("/cache/{}.mesh", cached_mesh_name(path)))
+}
+
+fn is_mesh_cached(path: &PathBuf) -> bool {
+    canonical_path_from_vfs(&cached_mesh_path(path)).map_or(false, |path| path.exists())
+}
+
+/// Bakes the glTF at `path` into the cache, or reuses its baked mesh when it is already there.
+///
+/// The cache is cleared at startup, so a baked mesh only exists when it was baked earlier in the
+/// same run; [`invalidate_cached_mesh`] removes it when its source changes.
+pub fn load_mesh(path: &PathBuf) -> anyhow::Result<BakedMesh> {
+    log::info!("Loading a mesh from {:?}", path);
+
+    let cached_mesh_path = cached_mesh_path(path);
+
+    if is_mesh_cached(path) {
+        return Ok(BakedMesh {
+            data: BakedMeshData::Cached(cached_mesh_path),
+            cache_hit: true,
+            bounds: source_mesh_bounds(path),
+        });
+    }
+
+    if let Ok(()) =
+        kajiya_asset_pipe::process_mesh_asset(kajiya_asset_pipe::MeshAssetProcessParams {
+            path: path.clone(),
+            output_name: cached_mesh_name(path),
+            scale: 1.0,
+        })
+    {
+        return Ok(BakedMesh {
+            data: BakedMeshData::Cached(cached_mesh_path),
+            cache_hit: false,
+            bounds: source_mesh_bounds(path),
+        });
     }
 
     Err(anyhow::Error::msg(format!(
@@ -188,3 +496,90 @@ pub fn load_mesh(path: &PathBuf) -> anyhow::Result<PathBuf> {
         path,
     )))
 }
+
+/// Removes the baked meshes of `mesh` and its mirrored copy from the cache so the next load
+/// bakes them again, after the source changed.
+pub fn invalidate_cached_mesh(mesh: &str) {
+    for mirrored in [false, true] {
+        let path = mesh_gltf_path(mesh, mirrored);
+        if let Ok(cached_mesh_path) = canonical_path_from_vfs(&cached_mesh_path(&path)) {
+            if !cached_mesh_path.exists() {
+                continue;
+            }
+            if let Err(e) = std::fs::remove_file(&cached_mesh_path) {
+                log::warn!("Couldn't remove cached mesh {:?}: {}", cached_mesh_path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mesh_transform(transform: Transform) -> MeshTransform {
+        MeshTransform::from_global_transform(&GlobalTransform::from(transform))
+    }
+
+    #[test]
+    fn mirroring_transforms() {
+        let mirrored = |scale: [f32; 3]| {
+            mesh_transform(Transform::from_scale(math::Vec3::from(scale))).is_mirrored()
+        };
+
+        assert!(!mirrored([1.0, 1.0, 1.0]));
+        assert!(mirrored([-1.0, 1.0, 1.0]));
+        assert!(mirrored([2.0, -0.5, 3.0]));
+        // Two flips make a rotation
+        assert!(!mirrored([-1.0, -1.0, 1.0]));
+        assert!(mirrored([-1.0, -1.0, -1.0]));
+
+        let rotation = Transform::from_rotation(math::Quat::from_rotation_y(2.0));
+        assert!(!mesh_transform(rotation).is_mirrored());
+    }
+
+    #[test]
+    fn mirrored_instances_keep_their_transform() {
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0)
+            .with_rotation(math::Quat::from_rotation_x(0.5))
+            .with_scale(math::Vec3::new(-2.0, 1.0, 1.0));
+        let matrix = transform.compute_matrix().to_cols_array();
+
+        // The mirrored mesh is only wound the other way, so kajiya gets the transform as is
+        let mesh_transform = mesh_transform(transform);
+        assert!(mesh_transform.is_mirrored());
+        assert_eq!(Mat4::from(mesh_transform.affine).to_cols_array(), matrix);
+    }
+
+    #[test]
+    fn generated_gltf_paths() {
+        assert_eq!(
+            mesh_source_path("car"),
+            PathBuf::from("assets/meshes/car/scene.gltf")
+        );
+        assert_eq!(
+            mesh_source_path("car#node3#material00000000000000ff"),
+            PathBuf::from("cache/meshes/car/scene.node3.material00000000000000ff.gltf")
+        );
+        assert_eq!(
+            mesh_key_source_path("car#mirrored"),
+            PathBuf::from("cache/meshes/car/scene.mirrored.gltf")
+        );
+        assert!(mesh_source_path("car#node3").starts_with(generated_gltf_dir("car#node3")));
+    }
+
+    #[test]
+    fn mesh_keys_round_trip() {
+        for mesh in ["car", "car#node3", "car#node3#material00000000000000ff"] {
+            for mirrored in [false, true] {
+                assert_eq!(parse_mesh_key(&mesh_key(mesh, mirrored)), (mesh, mirrored));
+            }
+        }
+
+        assert_eq!(mesh_key("car", true), "car#mirrored");
+        assert_eq!(
+            mesh_key_source_path("car#node3#mirrored"),
+            PathBuf::from("cache/meshes/car/scene.node3.mirrored.gltf")
+        );
+    }
+}
@@ -0,0 +1,237 @@
+use std::path::Path;
+
+use gltf::json::{self, Index};
+
+use crate::mesh::make_uris_absolute;
+
+/// Reverses the winding of triangles, so they still face outwards once mirrored by an instance
+/// transform.
+pub fn reverse_winding(indices: &mut [u32]) {
+    for triangle in indices.chunks_exact_mut(3) {
+        triangle.swap(1, 2);
+    }
+}
+
+/// Flips the bitangent sign of a tangent: mirroring the normal and tangent keeps their cross
+/// product, while the bitangent should be mirrored too.
+pub fn flip_bitangent([x, y, z, w]: [f32; 4]) -> [f32; 4] {
+    [x, y, z, -w]
+}
+
+/// Writes a copy of the glTF at `source` to `path`, for instances with mirroring transforms. The
+/// triangles of the copy are wound the other way and its bitangents flipped, while the vertices
+/// stay in place: the instance transform does the mirroring.
+///
+/// The new indices and tangents are written to a `.bin` file next to the copy, other buffers and
+/// images are shared with the source through absolute URIs.
+pub fn write_mirrored_gltf(source: &Path, path: &Path) -> anyhow::Result<()> {
+    let bin_path = path.with_extension("bin");
+
+    let gltf = gltf::Gltf::open(source)?;
+    let buffers = gltf::import_buffers(&gltf.document, source.parent(), gltf.blob.clone())?;
+
+    let mut root = gltf.document.clone().into_json();
+    make_uris_absolute(&mut root, source)?;
+    let buffer = Index::new(root.buffers.len() as u32);
+    let mut data = Vec::new();
+
+    for mesh in gltf.document.meshes() {
+        for primitive in mesh.primitives() {
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                continue;
+            }
+            let reader =
+                primitive.reader(|buffer| buffers.get(buffer.index()).map(|data| &data[..]));
+
+            let mut indices: Vec<u32> = match reader.read_indices() {
+                Some(indices) => indices.into_u32().collect(),
+                None => {
+                    let vertex_count = reader
+                        .read_positions()
+                        .map_or(0, |positions| positions.len());
+                    (0..vertex_count as u32).collect()
+                }
+            };
+            reverse_winding(&mut indices);
+            let bytes: Vec<u8> = indices
+                .iter()
+                .flat_map(|index| index.to_le_bytes())
+                .collect();
+            let indices = push_accessor(
+                &mut root,
+                buffer,
+                &mut data,
+                &bytes,
+                indices.len(),
+                ("SCALAR", UNSIGNED_INT),
+            )?;
+
+            let tangents = match reader.read_tangents() {
+                Some(tangents) => {
+                    let tangents: Vec<[f32; 4]> = tangents.map(flip_bitangent).collect();
+                    let bytes: Vec<u8> = tangents
+                        .iter()
+                        .flatten()
+                        .flat_map(|value| value.to_le_bytes())
+                        .collect();
+                    Some(push_accessor(
+                        &mut root,
+                        buffer,
+                        &mut data,
+                        &bytes,
+                        tangents.len(),
+                        ("VEC4", FLOAT),
+                    )?)
+                }
+                None => None,
+            };
+
+            let primitive_desc = &mut root.meshes[mesh.index()].primitives[primitive.index()];
+            primitive_desc.indices = Some(indices);
+            if let Some(tangents) = tangents {
+                primitive_desc.attributes.insert(
+                    json::validation::Checked::Valid(json::mesh::Semantic::Tangents),
+                    tangents,
+                );
+            }
+        }
+    }
+
+    let bin_name = bin_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow::anyhow!("Invalid glTF path {:?}", path))?;
+    root.buffers.push(json::deserialize::from_str(&format!(
+        r#"{{"byteLength":{},"uri":{}}}"#,
+        data.len(),
+        json::serialize::to_string(bin_name)?,
+    ))?);
+
+    std::fs::write(&bin_path, &data)?;
+    std::fs::write(path, root.to_string_pretty()?)?;
+
+    Ok(())
+}
+
+const UNSIGNED_INT: u32 = 5125;
+const FLOAT: u32 = 5126;
+
+/// Appends `bytes` to the `data` of `buffer`, with a buffer view and an accessor of `count`
+/// elements of the given type and component type. The descriptions are parsed from JSON, as the
+/// fields of gltf's structs depend on its features.
+fn push_accessor(
+    root: &mut json::Root,
+    buffer: Index<json::Buffer>,
+    data: &mut Vec<u8>,
+    bytes: &[u8],
+    count: usize,
+    (type_, component_type): (&str, u32),
+) -> anyhow::Result<Index<json::Accessor>> {
+    let view = root.buffer_views.len();
+    root.buffer_views.push(json::deserialize::from_str(&format!(
+        r#"{{"buffer":{},"byteOffset":{},"byteLength":{}}}"#,
+        buffer.value(),
+        data.len(),
+        bytes.len(),
+    ))?);
+    data.extend_from_slice(bytes);
+
+    let accessor = Index::new(root.accessors.len() as u32);
+    root.accessors.push(json::deserialize::from_str(&format!(
+        r#"{{"bufferView":{},"componentType":{},"count":{},"type":"{}"}}"#,
+        view, component_type, count, type_,
+    ))?);
+
+    Ok(accessor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversed_winding_flips_the_face_normal() {
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let mut indices = vec![0, 1, 2];
+        reverse_winding(&mut indices);
+        assert_eq!(indices, [0, 2, 1]);
+
+        // Mirrored along X, the reversed triangle faces +Z again like the original
+        let mirrored: Vec<bevy::math::Vec3> = indices
+            .iter()
+            .map(|&index| {
+                let [x, y, z] = positions[index as usize];
+                bevy::math::Vec3::new(-x, y, z)
+            })
+            .collect();
+        let normal = (mirrored[1] - mirrored[0]).cross(mirrored[2] - mirrored[0]);
+        assert!(normal.z > 0.0);
+    }
+
+    #[test]
+    fn flipped_bitangent() {
+        assert_eq!(flip_bitangent([1.0, 0.0, 0.0, 1.0]), [1.0, 0.0, 0.0, -1.0]);
+    }
+
+    #[test]
+    fn mirrored_gltf_reverses_triangles() {
+        let dir = std::env::temp_dir().join(format!("bevy_kajiya_mirror_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // One triangle without indices, with tangents
+        let positions: [[f32; 3]; 3] = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 1.0, 0.0]];
+        let tangents: [[f32; 4]; 3] = [[1.0, 0.0, 0.0, 1.0]; 3];
+        let bytes: Vec<u8> = positions
+            .iter()
+            .flatten()
+            .chain(tangents.iter().flatten())
+            .flat_map(|value| value.to_le_bytes())
+            .collect();
+        std::fs::write(dir.join("scene.bin"), &bytes).unwrap();
+        std::fs::write(
+            dir.join("scene.gltf"),
+            r#"{
+                "asset": {"version": "2.0"},
+                "buffers": [{"byteLength": 84, "uri": "scene.bin"}],
+                "bufferViews": [
+                    {"buffer": 0, "byteOffset": 0, "byteLength": 36},
+                    {"buffer": 0, "byteOffset": 36, "byteLength": 48}
+                ],
+                "accessors": [
+                    {"bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3",
+                     "min": [0, 0, 0], "max": [1, 1, 0]},
+                    {"bufferView": 1, "componentType": 5126, "count": 3, "type": "VEC4"}
+                ],
+                "meshes": [{"primitives": [{"attributes": {"POSITION": 0, "TANGENT": 1}}]}],
+                "nodes": [{"mesh": 0}],
+                "scenes": [{"nodes": [0]}],
+                "scene": 0
+            }"#,
+        )
+        .unwrap();
+
+        // Written to another directory, like the cache
+        let mirrored_dir = dir.join("cache");
+        std::fs::create_dir_all(&mirrored_dir).unwrap();
+        let mirrored_path = mirrored_dir.join("scene.mirrored.gltf");
+        write_mirrored_gltf(&dir.join("scene.gltf"), &mirrored_path).unwrap();
+        let (document, buffers, _) = gltf::import(&mirrored_path).unwrap();
+        let primitive = document
+            .meshes()
+            .next()
+            .unwrap()
+            .primitives()
+            .next()
+            .unwrap();
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+        let indices: Vec<u32> = reader.read_indices().unwrap().into_u32().collect();
+        assert_eq!(indices, [0, 2, 1]);
+        let positions_read: Vec<[f32; 3]> = reader.read_positions().unwrap().collect();
+        assert_eq!(positions_read, positions);
+        let tangents: Vec<[f32; 4]> = reader.read_tangents().unwrap().collect();
+        assert_eq!(tangents, [[1.0, 0.0, 0.0, -1.0]; 3]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
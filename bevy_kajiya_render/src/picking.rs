@@ -0,0 +1,360 @@
+use bevy::{input::InputSystem, prelude::*};
+
+use crate::{
+    camera::KajiyaCamera,
+    light::KajiyaLightProxy,
+    mesh::{Aabb, KajiyaMeshInstance},
+};
+
+/// A ray in world space.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KajiyaRay {
+    pub origin: Vec3,
+    pub direction: Vec3,
+}
+
+impl KajiyaRay {
+    /// The ray going through `cursor_position`, in logical pixels from the bottom left corner of
+    /// the window like [`Window::cursor_position`].
+    pub fn from_screen(
+        camera: &KajiyaCamera,
+        transform: &Transform,
+        cursor_position: Vec2,
+        window_size: Vec2,
+    ) -> Option<Self> {
+        let ndc = 2.0 * cursor_position / window_size - Vec2::ONE;
+
+        let pos = transform.translation;
+        let rot = transform.rotation;
+        let matrices = camera.camera_matrices((
+            glam::Vec3::new(pos.x, pos.y, pos.z),
+            glam::Quat::from_xyzw(rot.x, rot.y, rot.z, rot.w),
+        ));
+        let clip_to_world = Mat4::from_cols_array(
+            &(matrices.view_to_world * matrices.clip_to_view).to_cols_array(),
+        );
+
+        // Reverse-Z: the near plane is at a depth of 1, and anything below it is further away
+        let near = clip_to_world.project_point3(Vec3::new(ndc.x, ndc.y, 1.0));
+        let far = clip_to_world.project_point3(Vec3::new(ndc.x, ndc.y, 0.5));
+
+        Some(Self {
+            origin: near,
+            direction: (far - near).try_normalize()?,
+        })
+    }
+
+    pub fn at(&self, t: f32) -> Vec3 {
+        self.origin + t * self.direction
+    }
+
+    /// Slab test against the box. Returns the distance along the ray to the entry point, in
+    /// multiples of `direction`, or 0 if the ray starts inside the box.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        let (min, max) = (aabb.min(), aabb.max());
+        let mut t_enter = 0.0f32;
+        let mut t_exit = f32::INFINITY;
+
+        for axis in 0..3 {
+            let (origin, direction) = (self.origin[axis], self.direction[axis]);
+            if direction == 0.0 {
+                // Parallel to the slabs, where the distances would be NaN
+                if origin < min[axis] || origin > max[axis] {
+                    return None;
+                }
+                continue;
+            }
+
+            let t0 = (min[axis] - origin) / direction;
+            let t1 = (max[axis] - origin) / direction;
+            t_enter = t_enter.max(t0.min(t1));
+            t_exit = t_exit.min(t0.max(t1));
+        }
+
+        (t_exit >= t_enter).then(|| t_enter)
+    }
+
+    /// Intersects the box, given in the local space of `transform`. The ray is moved to local
+    /// space rather than the box to world space, so rotated boxes stay tight.
+    pub fn intersect_transformed_aabb(
+        &self,
+        aabb: &Aabb,
+        transform: &GlobalTransform,
+    ) -> Option<f32> {
+        let world_to_local = transform.compute_matrix().inverse();
+
+        // Distances along the ray are kept as long as the direction isn't normalized again
+        let local_ray = KajiyaRay {
+            origin: world_to_local.transform_point3(self.origin),
+            direction: world_to_local.transform_vector3(self.direction),
+        };
+
+        local_ray.intersect_aabb(aabb)
+    }
+}
+
+/// The closest instance hit by a [`KajiyaRay`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KajiyaPickHit {
+    pub entity: Entity,
+    pub distance: f32,
+    pub position: Vec3,
+}
+
+/// Bounds used for picking a mesh instance: its [`Aabb`] component if it has one, or a cube of
+/// [`KajiyaMeshInstance::selection_bb_size`] centered on its origin.
+pub fn instance_pick_bounds(mesh_instance: &KajiyaMeshInstance, aabb: Option<&Aabb>) -> Aabb {
+    aabb.cloned().unwrap_or_else(|| {
+        Aabb::from_center_padding(Vec3::ZERO, 0.5 * mesh_instance.selection_bb_size)
+    })
+}
+
+/// Finds the closest of the `instances` hit by the ray, with their bounds in local space.
+pub fn pick_closest<'a>(
+    ray: &KajiyaRay,
+    instances: impl IntoIterator<Item = (Entity, &'a GlobalTransform, Aabb)>,
+) -> Option<KajiyaPickHit> {
+    instances
+        .into_iter()
+        .filter_map(|(entity, transform, aabb)| {
+            let distance = ray.intersect_transformed_aabb(&aabb, transform)?;
+            Some(KajiyaPickHit {
+                entity,
+                distance,
+                position: ray.at(distance),
+            })
+        })
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KajiyaPickEvent {
+    HoverStart(KajiyaPickHit),
+    HoverEnd(Entity),
+    Click(KajiyaPickHit),
+}
+
+/// Picking state, updated every frame from the cursor position.
+pub struct KajiyaPicking {
+    pub enabled: bool,
+    /// Mouse button sending [`KajiyaPickEvent::Click`] events.
+    pub button: MouseButton,
+    /// The ray under the cursor, if the cursor is in the window.
+    pub ray: Option<KajiyaRay>,
+    pub hovered: Option<KajiyaPickHit>,
+}
+
+impl Default for KajiyaPicking {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            button: MouseButton::Left,
+            ray: None,
+            hovered: None,
+        }
+    }
+}
+
+pub fn update_picking(
+    mut picking: ResMut<KajiyaPicking>,
+    mut pick_events: EventWriter<KajiyaPickEvent>,
+    windows: Res<Windows>,
+    mouse_button_input: Res<Input<MouseButton>>,
+    camera_query: Query<(&KajiyaCamera, &Transform)>,
+    // The meshes rendering lights aren't part of the scene, ex: a shade would hide the lamp
+    instance_query: Query<
+        (Entity, &GlobalTransform, &KajiyaMeshInstance, Option<&Aabb>),
+        Without<KajiyaLightProxy>,
+    >,
+) {
+    if !picking.enabled {
+        return;
+    }
+
+    let ray = windows.get_primary().and_then(|window| {
+        let cursor_position = window.cursor_position()?;
+        let (camera, transform) = camera_query.iter().next()?;
+        let window_size = Vec2::new(window.width(), window.height());
+
+        KajiyaRay::from_screen(camera, transform, cursor_position, window_size)
+    });
+
+    let hit = ray.as_ref().and_then(|ray| {
+        pick_closest(
+            ray,
+            instance_query
+                .iter()
+                .map(|(entity, transform, mesh_instance, aabb)| {
+                    (entity, transform, instance_pick_bounds(mesh_instance, aabb))
+                }),
+        )
+    });
+
+    let previous_entity = picking.hovered.map(|hovered| hovered.entity);
+    let hit_entity = hit.map(|hit| hit.entity);
+
+    if previous_entity != hit_entity {
+        if let Some(previous_entity) = previous_entity {
+            pick_events.send(KajiyaPickEvent::HoverEnd(previous_entity));
+        }
+        if let Some(hit) = hit {
+            pick_events.send(KajiyaPickEvent::HoverStart(hit));
+        }
+    }
+
+    if let Some(hit) = hit {
+        if mouse_button_input.just_pressed(picking.button) {
+            pick_events.send(KajiyaPickEvent::Click(hit));
+        }
+    }
+
+    picking.ray = ray;
+    picking.hovered = hit;
+}
+
+/// Casts a ray under the cursor against [`KajiyaMeshInstance`] bounds, and sends
+/// [`KajiyaPickEvent`]s when instances are hovered or clicked. The meshes rendering lights
+/// can't be picked.
+pub struct KajiyaPickingPlugin;
+
+impl Plugin for KajiyaPickingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<KajiyaPicking>()
+            .add_event::<KajiyaPickEvent>()
+            .add_system_to_stage(CoreStage::PreUpdate, update_picking.after(InputSystem));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::from_center_padding(Vec3::ZERO, 1.0)
+    }
+
+    #[test]
+    fn ray_through_the_screen_center_follows_the_view() {
+        let camera = KajiyaCamera::default();
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0);
+
+        let ray = KajiyaRay::from_screen(
+            &camera,
+            &transform,
+            Vec2::new(400.0, 300.0),
+            Vec2::new(800.0, 600.0),
+        )
+        .unwrap();
+
+        // Cameras look towards -Z, starting from the near plane
+        assert!(ray.direction.abs_diff_eq(-Vec3::Z, 1e-5));
+        let near = Vec3::new(1.0, 2.0, 3.0 - camera.near_plane_distance);
+        assert!(ray.origin.abs_diff_eq(near, 1e-4));
+    }
+
+    #[test]
+    fn ray_through_the_screen_top_follows_the_fov() {
+        let camera = KajiyaCamera::default();
+        let window_size = Vec2::new(600.0, 600.0);
+
+        let ray = KajiyaRay::from_screen(
+            &camera,
+            &Transform::identity(),
+            Vec2::new(300.0, 600.0),
+            window_size,
+        )
+        .unwrap();
+
+        let angle = ray.direction.angle_between(-Vec3::Z).to_degrees();
+        assert!((angle - 0.5 * camera.vertical_fov).abs() < 1e-3);
+        assert!(ray.direction.y > 0.0);
+    }
+
+    #[test]
+    fn slab_test_hits_and_misses() {
+        let ray = KajiyaRay {
+            origin: Vec3::new(-5.0, 0.5, 0.5),
+            direction: Vec3::new(1.0, 0.0, 0.0),
+        };
+        assert_eq!(ray.intersect_aabb(&unit_box()), Some(4.0));
+
+        let diagonal = KajiyaRay {
+            origin: Vec3::splat(-3.0),
+            direction: Vec3::ONE.normalize(),
+        };
+        let distance = diagonal.intersect_aabb(&unit_box()).unwrap();
+        assert!((distance - 2.0 * 3f32.sqrt()).abs() < 1e-5);
+
+        let behind = KajiyaRay {
+            origin: Vec3::new(5.0, 0.0, 0.0),
+            direction: Vec3::new(1.0, 0.0, 0.0),
+        };
+        assert_eq!(behind.intersect_aabb(&unit_box()), None);
+    }
+
+    #[test]
+    fn slab_test_of_axis_aligned_rays() {
+        // Parallel to the X and Z slabs, outside of the X slab
+        let outside = KajiyaRay {
+            origin: Vec3::new(2.0, -5.0, 0.0),
+            direction: Vec3::Y,
+        };
+        assert_eq!(outside.intersect_aabb(&unit_box()), None);
+
+        // On a face of the box
+        let grazing = KajiyaRay {
+            origin: Vec3::new(1.0, -5.0, 0.0),
+            direction: Vec3::Y,
+        };
+        assert_eq!(grazing.intersect_aabb(&unit_box()), Some(4.0));
+
+        let inside = KajiyaRay {
+            origin: Vec3::new(0.5, 0.5, 0.0),
+            direction: -Vec3::Z,
+        };
+        assert_eq!(inside.intersect_aabb(&unit_box()), Some(0.0));
+    }
+
+    #[test]
+    fn pick_closest_instance() {
+        let ray = KajiyaRay {
+            origin: Vec3::new(0.0, 0.0, 10.0),
+            direction: -Vec3::Z,
+        };
+        let near = GlobalTransform::from_translation(Vec3::new(0.0, 0.0, 2.0));
+        let far = GlobalTransform::from_translation(Vec3::new(0.0, 0.0, -4.0));
+        let aside = GlobalTransform::from_translation(Vec3::new(5.0, 0.0, 5.0));
+        let (near_entity, far_entity, aside_entity) = (
+            Entity::from_raw(0),
+            Entity::from_raw(1),
+            Entity::from_raw(2),
+        );
+
+        let hit = pick_closest(
+            &ray,
+            [
+                (far_entity, &far, unit_box()),
+                (aside_entity, &aside, unit_box()),
+                (near_entity, &near, unit_box()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(hit.entity, near_entity);
+        assert_eq!(hit.distance, 7.0);
+        assert_eq!(hit.position, Vec3::new(0.0, 0.0, 3.0));
+
+        let scaled =
+            GlobalTransform::from(Transform::from_xyz(0.0, 0.0, -4.0).with_scale(Vec3::splat(8.0)));
+        let hit = pick_closest(
+            &ray,
+            [
+                (near_entity, &near, unit_box()),
+                (far_entity, &scaled, unit_box()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(hit.entity, far_entity);
+        assert_eq!(hit.distance, 6.0);
+    }
+}
@@ -4,8 +4,9 @@ use std::path::Path;
 
 use bevy::{
     app::{App, AppLabel, Plugin},
-    ecs::schedule::ShouldRun,
+    ecs::schedule::{IntoSystemDescriptor, ShouldRun},
     prelude::*,
+    transform::TransformSystem,
 };
 use kajiya::{
     backend::{
@@ -23,19 +24,54 @@ use std::{
 };
 use turbosloth::LazyCache;
 
+use crate::animation::{advance_animations, skin_meshes, spawn_skinned_gltfs};
+use crate::bounds::{update_instance_bounds, SharedMeshBounds};
+use crate::culling::{
+    cull_instances, extract_culling, CulledInstances, ExtractedCulling, KajiyaCulling,
+};
+use crate::debug::{apply_debug_view, extract_debug_view, ExtractedDebugView, KajiyaDebugView};
+use crate::diagnostics::{collect_render_stats, KajiyaRenderStats, SharedKajiyaRenderStats};
+use crate::dynamic_mesh::update_dynamic_meshes;
+#[cfg(feature = "mesh_updates")]
+use crate::dynamic_mesh::{
+    extract_dynamic_meshes, update_mesh_vertices, DynamicMeshUpdates, PendingMeshVertices,
+};
+use crate::environment_map::{
+    extract_environment_map, prepare_environment_map, ExtractedEnvironmentMap,
+    KajiyaEnvironmentMap, KajiyaEnvironmentMapLoader,
+};
+use crate::gltf_nodes::{spawn_gltf_nodes, KajiyaGltfNodes};
+use crate::light::update_light_proxies;
+use crate::lod::select_lods;
+use crate::material::MaterialVariants;
+use crate::post_process::{
+    extract_lut, prepare_post_process, write_embedded_shaders, ExtractedLut, KajiyaLut,
+    KajiyaLutLoader, PostProcessRenderer,
+};
+use crate::profiling::{new_profiling_frame, setup_profiling};
+use crate::render_passes::{extract_render_passes, ExtractedRenderPasses, KajiyaRenderPasses};
 use crate::render_resources::{
     KajiyaRGRenderer, KajiyaRenderBackend, KajiyaRenderers, RenderContext, WindowConfig,
 };
+use crate::runtime_mesh::{extract_runtime_meshes, KajiyaMeshData, RuntimeMeshes};
+use crate::scene::{update_kajiya_scenes, KajiyaSceneAsset, KajiyaSceneAssetLoader};
+use crate::solar::{update_solar_clocks, SolarClock};
+use crate::sun::prepare_sun;
 use crate::world_renderer::setup_world_renderer;
 use crate::KajiyaDescriptor;
-use crate::{camera::extract_camera, mesh::extract_meshes};
+use crate::{
+    camera::{apply_physical_camera, extract_camera, KajiyaPhysicalCamera},
+    mesh::extract_meshes,
+};
 use crate::{
     frame::render_frame,
     render_instances::{
         process_renderer_instances, process_renderer_meshes, remove_unused_instances,
         LoadedMeshesMap, RenderInstancesMap,
     },
-    world_renderer::{process_world_renderer_cmds, update_world_renderer_view, WRCommandQueue},
+    world_renderer::{
+        process_world_renderer_cmds, update_world_renderer_view, RuntimeMeshUploads, WRCommandQueue,
+    },
 };
 
 /// Contains the Bevy interface to the Kajiya renderer.
@@ -65,6 +101,28 @@ pub enum KajiyaRenderStage {
     Cleanup,
 }
 
+/// Labels of the systems of the rendering sub-app, to order custom systems relative to them.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+pub enum KajiyaRenderSystem {
+    /// Decides which instances are culled, in [`KajiyaRenderStage::Process`].
+    CullInstances,
+    /// Picks the level of detail of instances, in [`KajiyaRenderStage::Process`].
+    SelectLods,
+    /// Adds, updates and removes kajiya instances, in [`KajiyaRenderStage::Process`].
+    ProcessInstances,
+}
+
+/// Labels of the systems of the main app, in [`CoreStage::PostUpdate`].
+#[derive(Debug, Hash, PartialEq, Eq, Clone, SystemLabel)]
+pub enum KajiyaAppSystem {
+    /// Poses the nodes of animated meshes, before transform propagation.
+    AdvanceAnimations,
+    /// Skins animated meshes with the propagated joint transforms.
+    SkinMeshes,
+    /// Uploads changed [`KajiyaDynamicMesh`](crate::KajiyaDynamicMesh)es.
+    UpdateDynamicMeshes,
+}
+
 /// The Render App World. This is only available as a resource during the Extract step.
 #[derive(Default)]
 pub struct RenderWorld(World);
@@ -87,6 +145,56 @@ impl DerefMut for RenderWorld {
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, AppLabel)]
 pub struct KajiyaRenderApp;
 
+/// Extends [`App`] with access to the rendering sub-app, so plugins can extract their own data
+/// into the render world and push their own
+/// [`WorldRendererCommand`](crate::WorldRendererCommand)s.
+///
+/// ```ignore
+/// fn extract_my_component(
+///     query: Query<&MyComponent>,
+///     mut render_world: ResMut<RenderWorld>,
+/// ) {
+///     render_world.spawn_batch(query.iter().cloned().collect::<Vec<_>>());
+/// }
+///
+/// app.add_kajiya_render_system(KajiyaRenderStage::Extract, extract_my_component)
+///     .add_kajiya_render_system(KajiyaRenderStage::Process, process_my_component);
+/// ```
+pub trait KajiyaRenderAppExt {
+    /// Adds a system to one of the [`KajiyaRenderStage`]s of the rendering sub-app.
+    ///
+    /// Systems in the [`Extract`](KajiyaRenderStage::Extract) stage run on the app world and
+    /// access the render world through the [`RenderWorld`] resource, systems in every other
+    /// stage run on the render world.
+    fn add_kajiya_render_system<Params>(
+        &mut self,
+        stage: KajiyaRenderStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self;
+
+    /// Returns the rendering sub-app.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the [`KajiyaRenderPlugin`] hasn't been added yet.
+    fn kajiya_render_app(&mut self) -> &mut App;
+}
+
+impl KajiyaRenderAppExt for App {
+    fn add_kajiya_render_system<Params>(
+        &mut self,
+        stage: KajiyaRenderStage,
+        system: impl IntoSystemDescriptor<Params>,
+    ) -> &mut Self {
+        self.kajiya_render_app().add_system_to_stage(stage, system);
+        self
+    }
+
+    fn kajiya_render_app(&mut self) -> &mut App {
+        self.sub_app_mut(KajiyaRenderApp)
+    }
+}
+
 /// A "scratch" world used to avoid allocating new worlds every frame when
 /// swapping out the [`RenderWorld`].
 #[derive(Default)]
@@ -96,7 +204,11 @@ fn clear_cache_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
     for entry in fs::read_dir(path)? {
         let entry = entry?;
         let path = entry.path();
-        fs::remove_file(path)?;
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(path)?;
+        } else {
+            fs::remove_file(path)?;
+        }
     }
     Ok(())
 }
@@ -110,8 +222,12 @@ impl Plugin for KajiyaRenderPlugin {
         // Game-specific assets in the current directory
         set_vfs_mount_point("/cache", "./cache");
         set_vfs_mount_point("/meshes", "./assets/meshes");
+        set_vfs_mount_point("/user_shaders", "./assets/shaders");
 
         clear_cache_dir(PathBuf::from("./cache")).expect("Couldn't clear cache directory");
+        write_embedded_shaders(Path::new("./cache")).expect("Couldn't write embedded shaders");
+
+        setup_profiling(app);
 
         let WindowConfig {
             raw_window_handle,
@@ -153,6 +269,7 @@ impl Plugin for KajiyaRenderPlugin {
         let ui_renderer = UiRenderer::default();
 
         let rg_renderer = kajiya::rg::renderer::Renderer::new(&render_backend).unwrap();
+        let post_renderer = PostProcessRenderer::new(render_backend.device.clone());
 
         let kajiya_renderers = KajiyaRenderers {
             world_renderer: Mutex::new(world_renderer),
@@ -177,10 +294,52 @@ impl Plugin for KajiyaRenderPlugin {
             .unwrap_or_default()
             .create_default_pools();
 
-        app.init_resource::<ScratchRenderWorld>();
+        let shared_stats = SharedKajiyaRenderStats::default();
+        let shared_mesh_bounds = SharedMeshBounds::default();
+
+        app.init_resource::<ScratchRenderWorld>()
+            .init_resource::<KajiyaDebugView>()
+            .init_resource::<KajiyaRenderPasses>()
+            .init_resource::<KajiyaCulling>()
+            .insert_resource(shared_stats.clone())
+            .insert_resource(shared_mesh_bounds.clone())
+            .add_system(update_instance_bounds);
         app.add_asset::<crate::asset::GltfMeshAsset>()
             .init_asset_loader::<crate::asset::GltfMeshAssetLoader>()
             .add_startup_system(crate::asset::setup_assets);
+        app.register_type::<KajiyaPhysicalCamera>()
+            .add_system_to_stage(CoreStage::PostUpdate, apply_physical_camera);
+        app.add_asset::<KajiyaLut>()
+            .init_asset_loader::<KajiyaLutLoader>();
+        app.add_asset::<KajiyaEnvironmentMap>()
+            .init_asset_loader::<KajiyaEnvironmentMapLoader>();
+        app.add_asset::<KajiyaMeshData>().add_system_to_stage(
+            CoreStage::PostUpdate,
+            update_dynamic_meshes
+                .label(KajiyaAppSystem::UpdateDynamicMeshes)
+                .after(KajiyaAppSystem::SkinMeshes),
+        );
+        app.add_system(update_light_proxies);
+        app.register_type::<SolarClock>()
+            .add_system(update_solar_clocks);
+        app.add_system(spawn_skinned_gltfs)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                advance_animations
+                    .label(KajiyaAppSystem::AdvanceAnimations)
+                    .before(TransformSystem::TransformPropagate),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                skin_meshes
+                    .label(KajiyaAppSystem::SkinMeshes)
+                    .after(TransformSystem::TransformPropagate),
+            );
+        app.add_asset::<KajiyaSceneAsset>()
+            .init_asset_loader::<KajiyaSceneAssetLoader>()
+            .add_system(update_kajiya_scenes);
+        app.register_type::<KajiyaGltfNodes>()
+            .add_system(spawn_gltf_nodes);
 
         render_app
             .add_stage(
@@ -194,49 +353,91 @@ impl Plugin for KajiyaRenderPlugin {
                 SystemStage::parallel()
                     .with_system(extract_camera)
                     .with_system(extract_meshes)
+                    .with_system(extract_runtime_meshes)
+                    .with_system(extract_debug_view)
+                    .with_system(extract_render_passes)
+                    .with_system(extract_lut)
+                    .with_system(extract_environment_map)
+                    .with_system(extract_culling)
                     .with_system(crate::asset::watch_asset),
             )
             .add_stage(
                 KajiyaRenderStage::Process,
                 SystemStage::parallel()
                     .with_system(update_world_renderer_view)
-                    .with_system(process_renderer_instances)
+                    .with_system(
+                        cull_instances
+                            .label(KajiyaRenderSystem::CullInstances)
+                            .before(KajiyaRenderSystem::ProcessInstances),
+                    )
+                    .with_system(
+                        select_lods
+                            .label(KajiyaRenderSystem::SelectLods)
+                            .before(KajiyaRenderSystem::ProcessInstances),
+                    )
+                    .with_system(
+                        process_renderer_instances.label(KajiyaRenderSystem::ProcessInstances),
+                    )
                     .with_system(process_renderer_meshes)
                     .with_system(remove_unused_instances),
             )
             .add_stage(
                 KajiyaRenderStage::Prepare,
-                SystemStage::single(process_world_renderer_cmds),
+                SystemStage::parallel()
+                    .with_system(process_world_renderer_cmds)
+                    .with_system(apply_debug_view)
+                    .with_system(prepare_post_process)
+                    .with_system(prepare_sun)
+                    .with_system(prepare_environment_map),
             )
             .add_stage(KajiyaRenderStage::Render, SystemStage::single(render_frame))
-            .add_stage(KajiyaRenderStage::Cleanup, SystemStage::parallel())
+            .add_stage(
+                KajiyaRenderStage::Cleanup,
+                SystemStage::parallel().with_system(collect_render_stats),
+            )
             .init_resource::<crate::asset::MeshAssetsState>()
             .init_resource::<WRCommandQueue>()
             .init_resource::<RenderInstancesMap>()
             .init_resource::<LoadedMeshesMap>()
+            .init_resource::<ExtractedDebugView>()
+            .init_resource::<ExtractedRenderPasses>()
+            .init_resource::<ExtractedLut>()
+            .init_resource::<ExtractedEnvironmentMap>()
+            .init_resource::<ExtractedCulling>()
+            .init_resource::<CulledInstances>()
+            .init_resource::<MaterialVariants>()
+            .init_resource::<RuntimeMeshes>()
+            .init_resource::<RuntimeMeshUploads>()
+            .init_resource::<KajiyaRenderStats>()
+            .insert_resource(shared_stats)
+            .insert_resource(shared_mesh_bounds)
             .insert_non_send_resource(kajiya_renderers)
             .insert_resource(render_backend)
             .insert_non_send_resource(rg_renderer)
+            .insert_non_send_resource(post_renderer)
             .insert_resource(render_context)
             .insert_resource(scene_descriptor)
             .insert_resource(window_properties);
 
+        #[cfg(feature = "mesh_updates")]
+        {
+            app.init_resource::<DynamicMeshUpdates>();
+            render_app
+                .init_resource::<PendingMeshVertices>()
+                .add_system_to_stage(KajiyaRenderStage::Extract, extract_dynamic_meshes)
+                .add_system_to_stage(KajiyaRenderStage::Process, update_mesh_vertices);
+        }
+
         // render_app.schedule
         // .stage("yak", |schedule: &mut Schedule| {
         //     schedule.add_stage(KajiyaRenderStage::Setup, SystemStage::parallel())
         // });
 
         app.add_sub_app(KajiyaRenderApp, render_app, move |app_world, render_app| {
-            #[cfg(feature = "trace")]
-            let render_span = bevy_utils::tracing::info_span!("renderer subapp");
-            #[cfg(feature = "trace")]
-            let _render_guard = render_span.enter();
+            new_profiling_frame();
+            kajiya_profile_scope!("renderer subapp");
             {
-                #[cfg(feature = "trace")]
-                let stage_span =
-                    bevy_utils::tracing::info_span!("stage", name = "reserve_and_flush");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("reserve_and_flush");
 
                 // reserve all existing app entities for use in render_app
                 // they can only be spawned using `get_or_spawn()`
@@ -253,6 +454,8 @@ impl Plugin for KajiyaRenderPlugin {
             }
 
             {
+                kajiya_profile_scope!("setup");
+
                 let setup = render_app
                     .schedule
                     .get_stage_mut::<SystemStage>(&KajiyaRenderStage::Setup)
@@ -261,20 +464,14 @@ impl Plugin for KajiyaRenderPlugin {
             }
 
             {
-                #[cfg(feature = "trace")]
-                let stage_span = bevy_utils::tracing::info_span!("stage", name = "extract");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("extract");
 
                 // extract
                 extract(app_world, render_app);
             }
 
             {
-                #[cfg(feature = "trace")]
-                let stage_span = bevy_utils::tracing::info_span!("stage", name = "process");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("process");
 
                 // process
                 let process = render_app
@@ -285,10 +482,7 @@ impl Plugin for KajiyaRenderPlugin {
             }
 
             {
-                #[cfg(feature = "trace")]
-                let stage_span = bevy_utils::tracing::info_span!("stage", name = "prepare");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("prepare");
 
                 // prepare
                 let prepare = render_app
@@ -299,10 +493,7 @@ impl Plugin for KajiyaRenderPlugin {
             }
 
             {
-                #[cfg(feature = "trace")]
-                let stage_span = bevy_utils::tracing::info_span!("stage", name = "render");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("render");
 
                 // render
                 let render = render_app
@@ -313,10 +504,7 @@ impl Plugin for KajiyaRenderPlugin {
             }
 
             {
-                #[cfg(feature = "trace")]
-                let stage_span = bevy_utils::tracing::info_span!("stage", name = "cleanup");
-                #[cfg(feature = "trace")]
-                let _stage_guard = stage_span.enter();
+                kajiya_profile_scope!("cleanup");
 
                 // cleanup
                 let cleanup = render_app
@@ -0,0 +1,526 @@
+use std::{path::Path, sync::Arc};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use kajiya::{
+    backend::{
+        ash::vk,
+        vk_sync::AccessType,
+        vulkan::{
+            device::Device,
+            image::{Image, ImageDesc, ImageSubResourceData},
+        },
+    },
+    rg,
+    world_renderer::WorldRenderer,
+};
+
+use crate::{
+    camera::{ExtractedCamera, KajiyaCamera, KajiyaPhysicalCamera},
+    plugin::RenderWorld,
+    render_resources::KajiyaRenderers,
+};
+
+/// Luminance, in cd/m², of one unit of kajiya's radiance.
+///
+/// kajiya's radiance has no physical unit: its sky and sun are scaled so that daylight scenes
+/// are exposed at an `ev_shift` of 0. This is the scale for which a camera at EV100 15, about
+/// "Sunny 16", gives that exposure.
+pub const KAJIYA_UNIT_LUMINANCE: f32 = 1.2 * 32768.0;
+
+/// Converts an exposure value at ISO 100 into kajiya's `ev_shift`.
+///
+/// Uses the usual photometric exposure `1 / (1.2 * 2^EV100)` of a luminance in cd/m², converted
+/// to kajiya's radiance with [`KAJIYA_UNIT_LUMINANCE`].
+pub fn ev100_to_ev_shift(ev100: f32) -> f32 {
+    (KAJIYA_UNIT_LUMINANCE / 1.2).log2() - ev100
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KajiyaExposure {
+    /// Fixed exposure, as an exposure value at ISO 100.
+    Manual { ev100: f32 },
+    /// Histogram-based eye adaptation.
+    Auto {
+        /// Exposure compensation, in stops.
+        compensation: f32,
+        /// Adaptation speed, as a base 2 logarithm.
+        speed_log2: f32,
+        /// Fraction of the darkest pixels ignored when metering.
+        low_clip: f32,
+        /// Fraction of the brightest pixels ignored when metering.
+        high_clip: f32,
+    },
+}
+
+impl Default for KajiyaExposure {
+    fn default() -> Self {
+        KajiyaExposure::Auto {
+            compensation: 0.0,
+            speed_log2: 0.0,
+            low_clip: 0.1,
+            high_clip: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KajiyaTonemapper {
+    /// kajiya's built-in neutral tonemapping.
+    Neutral,
+    /// A smooth S-curve raising the contrast of the neutral tonemapping's output, for a punchier
+    /// look. It grades the tonemapped image, so highlights are not rolled off any further.
+    ContrastCurve,
+}
+
+impl Default for KajiyaTonemapper {
+    fn default() -> Self {
+        KajiyaTonemapper::Neutral
+    }
+}
+
+/// Exposure, tonemapping and color grading settings. Add this to the [`KajiyaCamera`] entity.
+///
+/// The exposure is ignored if the camera also has a [`KajiyaPhysicalCamera`].
+#[derive(Component, Clone, Debug)]
+pub struct KajiyaPostProcess {
+    pub exposure: KajiyaExposure,
+    pub tonemapper: KajiyaTonemapper,
+    pub contrast: f32,
+    pub saturation: f32,
+    /// Color grading 3D LUT, loaded from a `.cube` file.
+    pub lut: Option<Handle<KajiyaLut>>,
+    /// Blend factor between the ungraded and the graded image.
+    pub lut_strength: f32,
+}
+
+impl Default for KajiyaPostProcess {
+    fn default() -> Self {
+        Self {
+            exposure: Default::default(),
+            tonemapper: Default::default(),
+            contrast: 1.0,
+            saturation: 1.0,
+            lut: None,
+            lut_strength: 1.0,
+        }
+    }
+}
+
+/// A 3D color lookup table.
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "6f1d3e0c-8c5b-4a43-a2f3-0c1f5b9e2d71"]
+pub struct KajiyaLut {
+    pub size: u32,
+    /// RGB entries, red changing fastest, then green, then blue.
+    pub data: Vec<[f32; 3]>,
+}
+
+impl KajiyaLut {
+    pub fn identity(size: u32) -> Self {
+        let max = (size - 1) as f32;
+        let mut data = Vec::with_capacity((size * size * size) as usize);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.push([r as f32 / max, g as f32 / max, b as f32 / max]);
+                }
+            }
+        }
+
+        Self { size, data }
+    }
+
+    /// Parses an Adobe/Resolve `.cube` 3D LUT.
+    pub fn from_cube(source: &str) -> anyhow::Result<Self> {
+        let mut size = None;
+        let mut data = Vec::new();
+
+        for line in source.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("TITLE") {
+                continue;
+            }
+
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("LUT_3D_SIZE") => {
+                    let value: u32 = tokens
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("Missing LUT_3D_SIZE value"))?
+                        .parse()?;
+                    if value < 2 {
+                        anyhow::bail!("Invalid LUT_3D_SIZE {}", value);
+                    }
+                    size = Some(value);
+                }
+                Some("LUT_1D_SIZE") => anyhow::bail!("1D LUTs are not supported"),
+                Some(keyword @ ("DOMAIN_MIN" | "DOMAIN_MAX")) => {
+                    let expected = if keyword == "DOMAIN_MIN" { 0.0 } else { 1.0 };
+                    for value in tokens {
+                        if value.parse::<f32>()? != expected {
+                            anyhow::bail!("Only the [0, 1] LUT domain is supported");
+                        }
+                    }
+                }
+                Some(r) => {
+                    let r: f32 = r.parse()?;
+                    let mut next = || -> anyhow::Result<f32> {
+                        Ok(tokens
+                            .next()
+                            .ok_or_else(|| anyhow::anyhow!("Incomplete LUT entry: '{}'", line))?
+                            .parse()?)
+                    };
+                    let g = next()?;
+                    let b = next()?;
+                    data.push([r, g, b]);
+                }
+                None => {}
+            }
+        }
+
+        let size = size.ok_or_else(|| anyhow::anyhow!("Missing LUT_3D_SIZE"))?;
+        if data.len() != (size * size * size) as usize {
+            anyhow::bail!(
+                "Expected {} LUT entries, found {}",
+                size * size * size,
+                data.len()
+            );
+        }
+
+        Ok(Self { size, data })
+    }
+}
+
+#[derive(Default)]
+pub struct KajiyaLutLoader;
+
+impl AssetLoader for KajiyaLutLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let lut = KajiyaLut::from_cube(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(lut));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["cube"]
+    }
+}
+
+/// The LUT used by the camera's [`KajiyaPostProcess`], extracted when it changes.
+#[derive(Default)]
+pub struct ExtractedLut {
+    pub handle: Option<Handle<KajiyaLut>>,
+    pub lut: Option<KajiyaLut>,
+    pub changed: bool,
+}
+
+pub fn extract_lut(
+    query: Query<&KajiyaPostProcess, With<KajiyaCamera>>,
+    luts: Res<Assets<KajiyaLut>>,
+    mut ev_lut: EventReader<AssetEvent<KajiyaLut>>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    let mut extracted_lut = render_world.get_resource_mut::<ExtractedLut>().unwrap();
+
+    let handle = query
+        .iter()
+        .next()
+        .and_then(|post_process| post_process.lut.clone());
+
+    let modified = ev_lut.iter().any(|ev| match ev {
+        AssetEvent::Created { handle: ev_handle } | AssetEvent::Modified { handle: ev_handle } => {
+            Some(ev_handle) == handle.as_ref()
+        }
+        AssetEvent::Removed { .. } => false,
+    });
+
+    if modified || extracted_lut.handle != handle {
+        extracted_lut.lut = handle.as_ref().and_then(|handle| luts.get(handle)).cloned();
+        extracted_lut.handle = handle;
+        extracted_lut.changed = true;
+    }
+}
+
+/// The color grading shader, embedded so binaries don't depend on the location of this crate.
+const COLOR_GRADING_SHADER: &str = include_str!("../shaders/color_grading.hlsl");
+/// Where the shader is written at startup, for kajiya to load it through its virtual file system.
+const COLOR_GRADING_SHADER_PATH: &str = "/cache/bevy_kajiya_color_grading.hlsl";
+
+/// Writes the shaders of the passes added by this crate to `cache_dir`, mounted as `/cache`.
+pub fn write_embedded_shaders(cache_dir: &Path) -> std::io::Result<()> {
+    let file_name = Path::new(COLOR_GRADING_SHADER_PATH).file_name().unwrap();
+    std::fs::write(cache_dir.join(file_name), COLOR_GRADING_SHADER)
+}
+
+/// GPU resources of the color grading pass.
+pub struct PostProcessRenderer {
+    device: Arc<Device>,
+    identity_lut: Arc<Image>,
+    lut: Option<(Arc<Image>, u32)>,
+}
+
+impl PostProcessRenderer {
+    pub fn new(device: Arc<Device>) -> Self {
+        let identity_lut = Self::create_lut_image(&device, &KajiyaLut::identity(2));
+
+        Self {
+            device,
+            identity_lut,
+            lut: None,
+        }
+    }
+
+    fn create_lut_image(device: &Device, lut: &KajiyaLut) -> Arc<Image> {
+        let bytes: Vec<u8> = lut
+            .data
+            .iter()
+            .flat_map(|[r, g, b]| [*r, *g, *b, 1.0])
+            .flat_map(f32::to_ne_bytes)
+            .collect();
+
+        let texel_size = 4 * std::mem::size_of::<f32>();
+        let size = lut.size as usize;
+
+        let image = device
+            .create_image(
+                ImageDesc::new_3d(vk::Format::R32G32B32A32_SFLOAT, [lut.size; 3])
+                    .usage(vk::ImageUsageFlags::SAMPLED),
+                vec![ImageSubResourceData {
+                    data: &bytes,
+                    row_pitch: size * texel_size,
+                    slice_pitch: size * size * texel_size,
+                }],
+            )
+            .expect("Failed to create color grading LUT image");
+
+        Arc::new(image)
+    }
+
+    pub fn prepare_render_graph(
+        &self,
+        rg: &mut rg::TemporalRenderGraph,
+        main_img: rg::Handle<Image>,
+        post_process: &KajiyaPostProcess,
+    ) -> rg::Handle<Image> {
+        let (lut_image, lut_size, lut_strength) = match &self.lut {
+            Some((lut_image, lut_size)) => {
+                (lut_image.clone(), *lut_size, post_process.lut_strength)
+            }
+            None => (self.identity_lut.clone(), 2, 0.0),
+        };
+        let tonemapper = match post_process.tonemapper {
+            KajiyaTonemapper::Neutral => 0.0,
+            KajiyaTonemapper::ContrastCurve => 1.0,
+        };
+
+        let lut_img = rg.import(
+            lut_image,
+            AccessType::AnyShaderReadSampledImageOrUniformTexelBuffer,
+        );
+        let mut output_img = rg.create(
+            main_img
+                .desc()
+                .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+        );
+
+        rg::SimpleRenderPass::new_compute(rg.add_pass("color grading"), COLOR_GRADING_SHADER_PATH)
+            .read(&main_img)
+            .read(&lut_img)
+            .write(&mut output_img)
+            .constants((
+                main_img.desc().extent_inv_extent_2d(),
+                [
+                    post_process.saturation,
+                    tonemapper,
+                    lut_strength,
+                    lut_size as f32,
+                ],
+            ))
+            .dispatch(main_img.desc().extent);
+
+        output_img
+    }
+}
+
+/// The exposure settings of the world renderer before any [`KajiyaPostProcess`] was applied.
+#[derive(Clone, Copy)]
+pub struct DefaultExposure {
+    ev_shift: f32,
+    contrast: f32,
+    dynamic_exposure: bool,
+    speed_log2: f32,
+    low_clip: f32,
+    high_clip: f32,
+}
+
+impl DefaultExposure {
+    fn capture(world_renderer: &WorldRenderer) -> Self {
+        let dynamic_exposure = &world_renderer.dynamic_exposure;
+        Self {
+            ev_shift: world_renderer.ev_shift,
+            contrast: world_renderer.contrast,
+            dynamic_exposure: dynamic_exposure.enabled,
+            speed_log2: dynamic_exposure.speed_log2,
+            low_clip: dynamic_exposure.histogram_clipping.low,
+            high_clip: dynamic_exposure.histogram_clipping.high,
+        }
+    }
+
+    fn restore(&self, world_renderer: &mut WorldRenderer) {
+        world_renderer.ev_shift = self.ev_shift;
+        world_renderer.contrast = self.contrast;
+        let dynamic_exposure = &mut world_renderer.dynamic_exposure;
+        dynamic_exposure.enabled = self.dynamic_exposure;
+        dynamic_exposure.speed_log2 = self.speed_log2;
+        dynamic_exposure.histogram_clipping.low = self.low_clip;
+        dynamic_exposure.histogram_clipping.high = self.high_clip;
+    }
+}
+
+pub fn prepare_post_process(
+    wr_res: NonSendMut<KajiyaRenderers>,
+    mut post_renderer: NonSendMut<PostProcessRenderer>,
+    mut extracted_lut: ResMut<ExtractedLut>,
+    extracted_camera: Res<ExtractedCamera>,
+    mut default_exposure: Local<Option<DefaultExposure>>,
+) {
+    if extracted_lut.changed {
+        extracted_lut.changed = false;
+        post_renderer.lut = extracted_lut.lut.as_ref().map(|lut| {
+            (
+                PostProcessRenderer::create_lut_image(&post_renderer.device, lut),
+                lut.size,
+            )
+        });
+    }
+
+    let mut world_renderer = wr_res.world_renderer.lock().unwrap();
+    let default_exposure =
+        *default_exposure.get_or_insert_with(|| DefaultExposure::capture(&world_renderer));
+
+    // Start from kajiya's settings, so removed components don't leave theirs behind
+    default_exposure.restore(&mut world_renderer);
+
+    let post_process = extracted_camera.post_process.as_ref();
+    let exposure = match extracted_camera.physical_camera.as_ref() {
+        Some(physical_camera) => KajiyaExposure::Manual {
+            ev100: physical_camera.ev100(),
+        },
+        None => match post_process {
+            Some(post_process) => post_process.exposure,
+            None => return,
+        },
+    };
+
+    match exposure {
+        KajiyaExposure::Manual { ev100 } => {
+            world_renderer.ev_shift = ev100_to_ev_shift(ev100);
+            world_renderer.dynamic_exposure.enabled = false;
+        }
+        KajiyaExposure::Auto {
+            compensation,
+            speed_log2,
+            low_clip,
+            high_clip,
+        } => {
+            world_renderer.ev_shift = compensation;
+            world_renderer.dynamic_exposure.enabled = true;
+            world_renderer.dynamic_exposure.speed_log2 = speed_log2;
+            world_renderer.dynamic_exposure.histogram_clipping.low = low_clip;
+            world_renderer.dynamic_exposure.histogram_clipping.high = high_clip;
+        }
+    }
+    if let Some(post_process) = post_process {
+        world_renderer.contrast = post_process.contrast;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY_CUBE: &str = "TITLE \"identity\"
+# Created by hand
+LUT_3D_SIZE 2
+DOMAIN_MIN 0.0 0.0 0.0
+DOMAIN_MAX 1.0 1.0 1.0
+
+0.0 0.0 0.0
+1.0 0.0 0.0
+0.0 1.0 0.0
+1.0 1.0 0.0
+0.0 0.0 1.0
+1.0 0.0 1.0
+0.0 1.0 1.0
+1.0 1.0 1.0
+";
+
+    #[test]
+    fn daylight_exposure_matches_kajiya() {
+        let sunny_16 = KajiyaPhysicalCamera::default().ev100();
+        assert!(ev100_to_ev_shift(sunny_16).abs() < 0.5);
+
+        // Each stop of EV100 lowers the shift by a stop
+        assert!((ev100_to_ev_shift(16.0) - ev100_to_ev_shift(15.0) + 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn parses_cube_files() {
+        let lut = KajiyaLut::from_cube(IDENTITY_CUBE).unwrap();
+        let identity = KajiyaLut::identity(2);
+
+        assert_eq!(lut.size, 2);
+        assert_eq!(lut.data, identity.data);
+    }
+
+    #[test]
+    fn identity_lut_changes_red_fastest() {
+        let lut = KajiyaLut::identity(3);
+
+        assert_eq!(lut.data.len(), 27);
+        assert_eq!(lut.data[1], [0.5, 0.0, 0.0]);
+        assert_eq!(lut.data[3], [0.0, 0.5, 0.0]);
+        assert_eq!(lut.data[9], [0.0, 0.0, 0.5]);
+        assert_eq!(lut.data[26], [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn rejects_other_domains() {
+        let scaled = IDENTITY_CUBE.replace("DOMAIN_MAX 1.0 1.0 1.0", "DOMAIN_MAX 2.0 2.0 2.0");
+        assert!(KajiyaLut::from_cube(&scaled).is_err());
+
+        let offset = IDENTITY_CUBE.replace("DOMAIN_MIN 0.0 0.0 0.0", "DOMAIN_MIN -1 0 0");
+        assert!(KajiyaLut::from_cube(&offset).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_cube_files() {
+        let without_size = IDENTITY_CUBE.replace("LUT_3D_SIZE 2", "");
+        let small = IDENTITY_CUBE.replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 1");
+        let wrong_size = IDENTITY_CUBE.replace("LUT_3D_SIZE 2", "LUT_3D_SIZE 3");
+        let one_dimensional = IDENTITY_CUBE.replace("LUT_3D_SIZE 2", "LUT_1D_SIZE 2");
+        let incomplete = IDENTITY_CUBE.replace("1.0 0.0 1.0", "1.0 0.0");
+        let not_a_number = IDENTITY_CUBE.replace("0.0 1.0 1.0", "0.0 one 1.0");
+
+        for source in [
+            without_size,
+            small,
+            wrong_size,
+            one_dimensional,
+            incomplete,
+            not_a_number,
+        ] {
+            assert!(KajiyaLut::from_cube(&source).is_err(), "{}", source);
+        }
+    }
+}
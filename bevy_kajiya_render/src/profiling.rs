@@ -0,0 +1,61 @@
+/// Opens a `tracing` span and a puffin scope lasting until the end of the enclosing block.
+///
+/// Expands to nothing unless the `profiling` feature is enabled.
+macro_rules! kajiya_profile_scope {
+    ($name:expr) => {
+        #[cfg(feature = "profiling")]
+        let _kajiya_span_guard = bevy::utils::tracing::info_span!("kajiya", name = $name).entered();
+        #[cfg(feature = "profiling")]
+        puffin::profile_scope!($name);
+    };
+}
+
+/// Profiler configuration, read when [`crate::KajiyaRenderPlugin`] is built.
+#[derive(Clone)]
+pub struct KajiyaProfilingSettings {
+    /// Collect puffin scopes. Only has an effect with the `profiling` feature.
+    pub puffin_scopes: bool,
+    /// Address (ex: "0.0.0.0:8585") to serve puffin captures on, for `puffin_viewer`.
+    /// Only has an effect with the `puffin_server` feature.
+    pub puffin_server_addr: Option<String>,
+}
+
+impl Default for KajiyaProfilingSettings {
+    fn default() -> Self {
+        Self {
+            puffin_scopes: true,
+            puffin_server_addr: None,
+        }
+    }
+}
+
+#[cfg(feature = "puffin_server")]
+pub struct PuffinServer(pub puffin_http::Server);
+
+pub fn setup_profiling(app: &mut bevy::prelude::App) {
+    let _settings = app
+        .world
+        .get_resource::<KajiyaProfilingSettings>()
+        .cloned()
+        .unwrap_or_default();
+
+    #[cfg(feature = "profiling")]
+    puffin::set_scopes_on(_settings.puffin_scopes);
+
+    #[cfg(feature = "puffin_server")]
+    if let Some(addr) = _settings.puffin_server_addr.as_ref() {
+        match puffin_http::Server::new(addr) {
+            Ok(server) => {
+                log::info!("Serving puffin captures on {}", addr);
+                app.insert_non_send_resource(PuffinServer(server));
+            }
+            Err(e) => log::error!("Couldn't start the puffin server on {}: {}", addr, e),
+        }
+    }
+}
+
+/// Marks the start of a new frame for puffin.
+pub fn new_profiling_frame() {
+    #[cfg(feature = "profiling")]
+    puffin::GlobalProfiler::lock().new_frame();
+}
@@ -3,20 +3,29 @@ use std::path::PathBuf;
 use bevy::{
     prelude::*,
     tasks::{AsyncComputeTaskPool, Task},
-    utils::HashMap,
+    utils::{HashMap, HashSet},
 };
 use kajiya::world_renderer::{InstanceHandle, MeshHandle};
 
 use crate::{
     asset::{GltfMeshAsset, MeshAssetsState},
-    mesh::{load_mesh, MeshInstanceExtracted, MeshTransform},
+    bounds::SharedMeshBounds,
+    culling::CulledInstances,
+    diagnostics::KajiyaRenderStats,
+    material::{parse_material_mesh_name, MaterialVariants},
+    mesh::{
+        generated_gltf_dir, invalidate_cached_mesh, load_mesh, mesh_key_source_path,
+        parse_mesh_key, parse_mesh_variant, write_mesh_key_gltf, BakedMesh, MeshInstanceExtracted,
+        MeshTransform,
+    },
+    runtime_mesh::{bake_runtime_mesh, parse_runtime_mesh_name, RuntimeMeshes},
     world_renderer::{WRCommandQueue, WorldRendererCommand},
 };
 use futures_lite::future;
 
 pub enum RenderMesh {
     Empty,
-    Queued(String, Task<std::result::Result<PathBuf, anyhow::Error>>),
+    Queued(String, Task<std::result::Result<BakedMesh, anyhow::Error>>),
     GLTFLoaded,
     Ready(MeshHandle),
     Update,
@@ -35,6 +44,8 @@ pub struct RenderInstance {
     pub transform: MeshTransform,
     pub instance: WRInstance,
     pub active: bool,
+    /// Current level of the instance's [`KajiyaLod`](crate::KajiyaLod), if it has one.
+    pub lod_level: usize,
 }
 
 pub type RenderInstancesMap = HashMap<Entity, RenderInstance>;
@@ -45,6 +56,7 @@ pub fn process_renderer_instances(
     mut wr_command_queue: ResMut<WRCommandQueue>,
     mut ri_map: ResMut<RenderInstancesMap>,
     mut lm_map: ResMut<LoadedMeshesMap>,
+    culled_instances: Res<CulledInstances>,
 ) {
     for extracted_instance in query_extracted_instances.iter() {
         if let Some(mut render_instance) = ri_map.get_mut(&extracted_instance.instance_entity) {
@@ -55,7 +67,45 @@ pub fn process_renderer_instances(
 
             render_instance.transform = extracted_instance.transform;
 
+            // The mesh changed, or the transform started or stopped mirroring it
+            if extracted_instance.lod.is_none()
+                && render_instance.mesh_name != extracted_instance.mesh_name
+            {
+                let mesh_ready = matches!(
+                    lm_map.get(&extracted_instance.mesh_name),
+                    Some(RenderMesh::Ready(_))
+                );
+                if !lm_map.contains_key(&extracted_instance.mesh_name) {
+                    lm_map.insert(extracted_instance.mesh_name.clone(), RenderMesh::Empty);
+                }
+
+                // Keep the previous mesh on screen until the new one is loaded
+                let showing_mesh = matches!(render_instance.instance, WRInstance::Ready(_));
+                if mesh_ready || !showing_mesh {
+                    match render_instance.instance {
+                        WRInstance::Ready(inst_handle) => {
+                            wr_command_queue
+                                .push(WorldRendererCommand::RemoveInstance(inst_handle));
+                            render_instance.instance = WRInstance::None;
+                        }
+                        WRInstance::Queued => continue,
+                        WRInstance::None => {}
+                    }
+
+                    render_instance.mesh_name = extracted_instance.mesh_name.clone();
+                }
+            }
+
+            let culled = culled_instances
+                .0
+                .contains(&extracted_instance.instance_entity);
+
             match render_instance.instance {
+                WRInstance::Ready(inst_handle) if culled => {
+                    wr_command_queue.push(WorldRendererCommand::RemoveInstance(inst_handle));
+                    render_instance.instance = WRInstance::None;
+                }
+                WRInstance::None if culled => {}
                 WRInstance::Ready(inst_handle) => {
                     if let Some(RenderMesh::Update) = lm_map.get(&render_instance.mesh_name) {
                         wr_command_queue.push(WorldRendererCommand::ReplaceInstance(
@@ -68,16 +118,19 @@ pub fn process_renderer_instances(
                             inst_handle,
                             extracted_instance.emission,
                         ));
+                        #[cfg(feature = "instance_materials")]
+                        wr_command_queue.push(WorldRendererCommand::SetMaterialOverride(
+                            inst_handle,
+                            extracted_instance.material.clone().unwrap_or_default(),
+                        ));
                         wr_command_queue.push(WorldRendererCommand::UpdateInstTransform(
                             inst_handle,
                             render_instance.transform,
                         ));
                     }
                 }
-                WRInstance::None => {
-                    if let Some(RenderMesh::Ready(mesh_handle)) =
-                        lm_map.get(&render_instance.mesh_name)
-                    {
+                WRInstance::None => match lm_map.get(&render_instance.mesh_name) {
+                    Some(RenderMesh::Ready(mesh_handle)) => {
                         wr_command_queue.push(WorldRendererCommand::AddInstance(
                             extracted_instance.instance_entity,
                             *mesh_handle,
@@ -85,7 +138,13 @@ pub fn process_renderer_instances(
                         ));
                         render_instance.instance = WRInstance::Queued;
                     }
-                }
+                    Some(RenderMesh::Update) => {
+                        // The mesh changed while every instance was culled, so nothing
+                        // replaced them and triggered the reload
+                        lm_map.insert(render_instance.mesh_name.clone(), RenderMesh::Empty);
+                    }
+                    _ => {}
+                },
                 _ => {}
             }
         } else {
@@ -95,6 +154,7 @@ pub fn process_renderer_instances(
                 transform: extracted_instance.transform,
                 instance: WRInstance::None,
                 active: true,
+                lod_level: 0,
             };
 
             ri_map.insert(extracted_instance.instance_entity, new_render_instance);
@@ -121,47 +181,100 @@ pub fn remove_unused_instances(
         }
     }
 
-    for (entity, inst_handle) in ri_map
-        .clone()
-        .iter()
-        .filter_map(|(e, ri)| match ri.instance {
-            WRInstance::Ready(ih) => {
-                if !ri.active {
-                    Some((e, ih))
-                } else {
-                    None
-                }
+    // Inactive entries of every state are dropped, queued instances are never added without one
+    ri_map.retain(|_, render_instance| {
+        if !render_instance.active {
+            if let WRInstance::Ready(inst_handle) = render_instance.instance {
+                wr_command_queue.push(WorldRendererCommand::RemoveInstance(inst_handle));
             }
-            _ => None,
-        })
-    {
-        wr_command_queue.push(WorldRendererCommand::RemoveInstance(inst_handle));
-        ri_map.remove(&entity);
-    }
+        }
+
+        render_instance.active
+    });
 }
 
 pub fn process_renderer_meshes(
     mut lm_map: ResMut<LoadedMeshesMap>,
     mut wr_command_queue: ResMut<WRCommandQueue>,
     mut mesh_assets: ResMut<MeshAssetsState>,
+    mut stats: ResMut<KajiyaRenderStats>,
+    mesh_bounds: Res<SharedMeshBounds>,
+    material_variants: Res<MaterialVariants>,
+    runtime_meshes: Res<RuntimeMeshes>,
+    mut generated_gltfs: Local<HashSet<PathBuf>>,
 ) {
     let thread_pool = AsyncComputeTaskPool::get();
+    let mut handled_changes = Vec::new();
+    let mut dropped_meshes = Vec::new();
+
     for (mesh_src, mesh) in lm_map.iter_mut() {
         match mesh {
             RenderMesh::Empty => {
-                let mesh_src = mesh_src.clone();
-                let mesh_src_path: PathBuf =
-                    format!("assets/meshes/{}/scene.gltf", mesh_src).into();
+                let mesh_key = mesh_src.clone();
+                let (mesh_name, mirrored) = parse_mesh_key(mesh_src);
+                let (source, _, material_id) = parse_mesh_variant(mesh_name);
+                let material = match material_id {
+                    Some(id) => match material_variants.0.get(&id) {
+                        Some(material) => Some(material.clone()),
+                        // No instance uses the variant anymore, ex: the override is animated
+                        None => {
+                            dropped_meshes.push(mesh_src.clone());
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
 
-                let load_mesh_task = thread_pool.spawn(async move { load_mesh(&mesh_src_path) });
+                let runtime_mesh = match parse_runtime_mesh_name(source) {
+                    // Wait for the runtime mesh to be extracted
+                    Some(id) => match runtime_meshes.0.get(&id) {
+                        Some(runtime_mesh) => Some(runtime_mesh.clone()),
+                        None => continue,
+                    },
+                    None => None,
+                };
+                let source_path = mesh_key_source_path(&mesh_key);
+
+                // Keys sharing a generated glTF are baked in parallel, so it is written once here
+                if let Err(e) =
+                    write_mesh_key_gltf(&mesh_key, material.as_ref(), &mut generated_gltfs)
+                {
+                    panic!("Error encountered writing the glTF of {}: {}", mesh_key, e);
+                }
+
+                let load_mesh_task = thread_pool.spawn(async move {
+                    kajiya_profile_scope!("load_mesh");
+                    match runtime_mesh {
+                        Some(runtime_mesh) => {
+                            bake_runtime_mesh(&runtime_mesh, mirrored, material.as_ref())
+                        }
+                        None => load_mesh(&source_path),
+                    }
+                });
 
-                *mesh = RenderMesh::Queued(mesh_src, load_mesh_task);
+                *mesh = RenderMesh::Queued(mesh_src.clone(), load_mesh_task);
             }
             RenderMesh::Queued(mesh_src, load_mesh_task) => {
                 match future::block_on(future::poll_once(load_mesh_task)) {
-                    Some(Ok(path)) => {
-                        wr_command_queue
-                            .push(WorldRendererCommand::AddMesh(mesh_src.to_string(), path));
+                    Some(Ok(baked_mesh)) => {
+                        if baked_mesh.cache_hit {
+                            stats.cache_hits += 1;
+                        } else {
+                            stats.cache_misses += 1;
+                        }
+
+                        // Mirrored copies and materials don't change the bounds, so all the
+                        // variants of a mesh share them
+                        if let Some(bounds) = baked_mesh.bounds {
+                            let (mesh_name, _) = parse_mesh_key(mesh_src);
+                            let (mesh_name, _) = parse_material_mesh_name(mesh_name);
+                            mesh_bounds.insert(mesh_name.to_string(), bounds);
+                        }
+
+                        wr_command_queue.push(WorldRendererCommand::AddMesh(
+                            mesh_src.to_string(),
+                            baked_mesh.data,
+                        ));
                         *mesh = RenderMesh::GLTFLoaded;
                     }
                     Some(Err(e)) => {
@@ -171,16 +284,36 @@ pub fn process_renderer_meshes(
                 }
             }
             RenderMesh::Ready(_) => {
-                let mesh_asset = GltfMeshAsset::from_src_path(mesh_src.clone());
+                let (mesh_name, _) = parse_mesh_key(mesh_src);
+                let (source, _, _) = parse_mesh_variant(mesh_name);
+                let mesh_asset = GltfMeshAsset::from_src_path(source.to_string());
 
                 if mesh_assets.meshes_changed.contains(&mesh_asset) {
-                    *mesh = RenderMesh::Update;
+                    // The baked mesh is stale, make sure the next load bakes it again
+                    invalidate_cached_mesh(mesh_name);
+                    let generated_dir = generated_gltf_dir(mesh_name);
+                    generated_gltfs.retain(|path| !path.starts_with(&generated_dir));
+
+                    // Runtime meshes are baked again while the previous upload stays on screen,
+                    // their instances switch over once it is added
+                    *mesh = match parse_runtime_mesh_name(source) {
+                        Some(_) => RenderMesh::Empty,
+                        None => RenderMesh::Update,
+                    };
                     println!("Found changed mesh asset {:?}", mesh_assets.meshes_changed);
 
-                    mesh_assets.meshes_changed.remove(&mesh_asset);
+                    // Every variant of the mesh may be loaded
+                    handled_changes.push(mesh_asset);
                 }
             }
             _ => {}
         }
     }
+
+    for mesh_asset in handled_changes {
+        mesh_assets.meshes_changed.remove(&mesh_asset);
+    }
+    for mesh_src in dropped_meshes {
+        lm_map.remove(&mesh_src);
+    }
 }
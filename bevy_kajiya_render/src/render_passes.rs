@@ -0,0 +1,361 @@
+use std::fmt;
+
+use bevy::{prelude::*, utils::HashMap};
+use kajiya::{
+    backend::{ash::vk, vulkan::image::Image},
+    rg,
+};
+
+use crate::plugin::RenderWorld;
+
+/// Constants passed to a [`KajiyaRenderPass`] shader, after the input image size.
+pub type KajiyaPassConstants = [f32; 16];
+
+/// A compute pass running on kajiya's main image, before the "final blit".
+///
+/// The shader is given the output of the previous pass and writes the input of the next one:
+///
+/// ```hlsl
+/// [[vk::binding(0)]] Texture2D<float4> input_tex;
+/// [[vk::binding(1)]] RWTexture2D<float4> output_tex;
+/// [[vk::binding(2)]] cbuffer _ {
+///     float4 input_tex_size;
+///     float4 constants[4];
+/// };
+/// ```
+///
+/// Shaders are loaded through kajiya's virtual file system; `./assets/shaders` is mounted as
+/// `/user_shaders`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KajiyaRenderPass {
+    pub name: String,
+    pub shader: String,
+    pub constants: KajiyaPassConstants,
+    /// Passes which must run before this one.
+    pub after: Vec<String>,
+    /// Passes which must run after this one.
+    pub before: Vec<String>,
+    pub enabled: bool,
+}
+
+impl KajiyaRenderPass {
+    pub fn new(name: impl Into<String>, shader: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            shader: shader.into(),
+            constants: [0.0; 16],
+            after: Vec::new(),
+            before: Vec::new(),
+            enabled: true,
+        }
+    }
+
+    pub fn after(mut self, pass: impl Into<String>) -> Self {
+        self.after.push(pass.into());
+        self
+    }
+
+    pub fn before(mut self, pass: impl Into<String>) -> Self {
+        self.before.push(pass.into());
+        self
+    }
+
+    pub fn with_constants(mut self, constants: KajiyaPassConstants) -> Self {
+        self.constants = constants;
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KajiyaRenderPassError {
+    DuplicatePass(String),
+    MissingShader(String),
+    UnknownDependency { pass: String, dependency: String },
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for KajiyaRenderPassError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KajiyaRenderPassError::DuplicatePass(pass) => {
+                write!(f, "render pass '{}' is registered more than once", pass)
+            }
+            KajiyaRenderPassError::MissingShader(pass) => {
+                write!(f, "render pass '{}' has no shader", pass)
+            }
+            KajiyaRenderPassError::UnknownDependency { pass, dependency } => write!(
+                f,
+                "render pass '{}' is ordered relative to unknown pass '{}'",
+                pass, dependency
+            ),
+            KajiyaRenderPassError::Cycle(passes) => {
+                write!(f, "render passes form a cycle: {}", passes.join(", "))
+            }
+        }
+    }
+}
+
+impl std::error::Error for KajiyaRenderPassError {}
+
+/// User render passes, run in dependency order on the main image before the "final blit".
+#[derive(Clone, Debug, Default)]
+pub struct KajiyaRenderPasses {
+    passes: Vec<KajiyaRenderPass>,
+}
+
+impl KajiyaRenderPasses {
+    pub fn add(&mut self, pass: KajiyaRenderPass) -> &mut Self {
+        self.passes.push(pass);
+        self
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<KajiyaRenderPass> {
+        let idx = self.passes.iter().position(|pass| pass.name == name)?;
+        Some(self.passes.remove(idx))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&KajiyaRenderPass> {
+        self.passes.iter().find(|pass| pass.name == name)
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut KajiyaRenderPass> {
+        self.passes.iter_mut().find(|pass| pass.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &KajiyaRenderPass> {
+        self.passes.iter()
+    }
+
+    /// Validates the passes and sorts them so every pass runs after its dependencies.
+    /// Passes without ordering constraints keep their registration order.
+    pub fn ordered(&self) -> Result<Vec<&KajiyaRenderPass>, KajiyaRenderPassError> {
+        let mut indices = HashMap::default();
+        for (idx, pass) in self.passes.iter().enumerate() {
+            if pass.shader.is_empty() {
+                return Err(KajiyaRenderPassError::MissingShader(pass.name.clone()));
+            }
+            if indices.insert(pass.name.as_str(), idx).is_some() {
+                return Err(KajiyaRenderPassError::DuplicatePass(pass.name.clone()));
+            }
+        }
+
+        let index_of = |pass: &KajiyaRenderPass, dependency: &String| {
+            indices.get(dependency.as_str()).copied().ok_or_else(|| {
+                KajiyaRenderPassError::UnknownDependency {
+                    pass: pass.name.clone(),
+                    dependency: dependency.clone(),
+                }
+            })
+        };
+
+        // Edges go from a pass to the passes which must run after it
+        let mut successors = vec![Vec::new(); self.passes.len()];
+        let mut in_degree = vec![0; self.passes.len()];
+        for (idx, pass) in self.passes.iter().enumerate() {
+            for dependency in pass.after.iter() {
+                successors[index_of(pass, dependency)?].push(idx);
+                in_degree[idx] += 1;
+            }
+            for dependent in pass.before.iter() {
+                let dependent = index_of(pass, dependent)?;
+                successors[idx].push(dependent);
+                in_degree[dependent] += 1;
+            }
+        }
+
+        let mut ordered = Vec::with_capacity(self.passes.len());
+        let mut done = vec![false; self.passes.len()];
+        while ordered.len() < self.passes.len() {
+            let next = (0..self.passes.len()).find(|idx| !done[*idx] && in_degree[*idx] == 0);
+
+            match next {
+                Some(idx) => {
+                    done[idx] = true;
+                    for successor in successors[idx].iter() {
+                        in_degree[*successor] -= 1;
+                    }
+                    ordered.push(&self.passes[idx]);
+                }
+                None => {
+                    let cycle = (0..self.passes.len())
+                        .filter(|idx| !done[*idx])
+                        .map(|idx| self.passes[idx].name.clone())
+                        .collect();
+                    return Err(KajiyaRenderPassError::Cycle(cycle));
+                }
+            }
+        }
+
+        Ok(ordered)
+    }
+}
+
+/// The enabled user render passes, in execution order.
+#[derive(Default)]
+pub struct ExtractedRenderPasses(pub Vec<KajiyaRenderPass>);
+
+impl ExtractedRenderPasses {
+    pub fn prepare_render_graph(
+        &self,
+        rg: &mut rg::TemporalRenderGraph,
+        main_img: rg::Handle<Image>,
+    ) -> rg::Handle<Image> {
+        let mut input_img = main_img;
+
+        for pass in self.0.iter() {
+            let mut output_img = rg.create(
+                input_img
+                    .desc()
+                    .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE),
+            );
+
+            rg::SimpleRenderPass::new_compute(rg.add_pass(&pass.name), &pass.shader)
+                .read(&input_img)
+                .write(&mut output_img)
+                .constants((input_img.desc().extent_inv_extent_2d(), pass.constants))
+                .dispatch(input_img.desc().extent);
+
+            input_img = output_img;
+        }
+
+        input_img
+    }
+}
+
+pub fn extract_render_passes(
+    render_passes: Res<KajiyaRenderPasses>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    if !render_passes.is_changed() {
+        return;
+    }
+
+    match render_passes.ordered() {
+        Ok(ordered) => {
+            let mut extracted_passes = render_world
+                .get_resource_mut::<ExtractedRenderPasses>()
+                .unwrap();
+            extracted_passes.0 = ordered
+                .into_iter()
+                .filter(|pass| pass.enabled)
+                .cloned()
+                .collect();
+        }
+        Err(e) => log::error!(
+            "Invalid kajiya render passes, keeping the previous ones: {}",
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(name: &str) -> KajiyaRenderPass {
+        KajiyaRenderPass::new(name, format!("/user_shaders/{}.hlsl", name))
+    }
+
+    fn names(passes: &KajiyaRenderPasses) -> Result<Vec<&str>, KajiyaRenderPassError> {
+        passes
+            .ordered()
+            .map(|ordered| ordered.iter().map(|pass| pass.name.as_str()).collect())
+    }
+
+    #[test]
+    fn keeps_the_registration_order_without_constraints() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes.add(pass("a")).add(pass("b")).add(pass("c"));
+
+        assert_eq!(names(&passes), Ok(vec!["a", "b", "c"]));
+    }
+
+    #[test]
+    fn runs_passes_after_their_dependencies() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes
+            .add(pass("grade").after("outline"))
+            .add(pass("outline"))
+            .add(pass("vignette").after("grade"));
+
+        assert_eq!(names(&passes), Ok(vec!["outline", "grade", "vignette"]));
+    }
+
+    #[test]
+    fn runs_passes_before_their_dependents() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes
+            .add(pass("grade"))
+            .add(pass("outline").before("grade"));
+
+        assert_eq!(names(&passes), Ok(vec!["outline", "grade"]));
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes
+            .add(pass("first"))
+            .add(pass("a").after("b"))
+            .add(pass("b").after("c"))
+            .add(pass("c").before("a").after("a"));
+
+        assert_eq!(
+            names(&passes),
+            Err(KajiyaRenderPassError::Cycle(vec![
+                "a".to_string(),
+                "b".to_string(),
+                "c".to_string()
+            ]))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_dependencies() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes.add(pass("a")).add(pass("b").before("missing"));
+
+        assert_eq!(
+            names(&passes),
+            Err(KajiyaRenderPassError::UnknownDependency {
+                pass: "b".to_string(),
+                dependency: "missing".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes.add(pass("a")).add(pass("b")).add(pass("a"));
+
+        assert_eq!(
+            names(&passes),
+            Err(KajiyaRenderPassError::DuplicatePass("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_passes_without_shader() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes.add(KajiyaRenderPass::new("a", ""));
+
+        assert_eq!(
+            names(&passes),
+            Err(KajiyaRenderPassError::MissingShader("a".to_string()))
+        );
+    }
+
+    #[test]
+    fn removes_passes_by_name() {
+        let mut passes = KajiyaRenderPasses::default();
+        passes.add(pass("a").after("b")).add(pass("b"));
+
+        assert_eq!(
+            passes.remove("b").map(|pass| pass.name),
+            Some("b".to_string())
+        );
+        assert!(passes.ordered().is_err());
+        assert_eq!(passes.remove("b"), None);
+    }
+}
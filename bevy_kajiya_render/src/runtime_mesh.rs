@@ -0,0 +1,470 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use bevy::{prelude::*, reflect::TypeUuid, utils::HashMap};
+use kajiya_asset::mesh::{
+    pack_triangle_mesh, MeshMaterial, MeshMaterialMap, PackedTriMesh, TriangleMesh,
+};
+
+use crate::{
+    asset::{GltfMeshAsset, MeshAssetsState},
+    material::KajiyaMaterialOverride,
+    mesh::{parse_mesh_key, parse_mesh_variant, Aabb, BakedMesh, BakedMeshData},
+    mirror::{flip_bitangent, reverse_winding},
+    plugin::RenderWorld,
+    render_instances::LoadedMeshesMap,
+    world_renderer::{WRCommandQueue, WorldRendererCommand},
+};
+
+/// Constant material of a [`KajiyaMeshData`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KajiyaMeshMaterial {
+    /// Linear RGBA, multiplied by the vertex colors.
+    pub base_color: [f32; 4],
+    pub roughness: f32,
+    pub metalness: f32,
+    /// Linear RGB.
+    pub emissive: [f32; 3],
+}
+
+impl Default for KajiyaMeshMaterial {
+    fn default() -> Self {
+        Self {
+            base_color: [1.0; 4],
+            roughness: 0.5,
+            metalness: 0.0,
+            emissive: [0.0; 3],
+        }
+    }
+}
+
+impl KajiyaMeshMaterial {
+    pub fn with_override(&self, material_override: &KajiyaMaterialOverride) -> Self {
+        let material_override = material_override.quantized();
+        let base_color = Vec4::from(self.base_color) * material_override.base_color_tint;
+
+        Self {
+            base_color: base_color.clamp(Vec4::ZERO, Vec4::ONE).to_array(),
+            roughness: (self.roughness * material_override.roughness_multiplier).clamp(0.0, 1.0),
+            metalness: (self.metalness * material_override.metalness_multiplier).clamp(0.0, 1.0),
+            emissive: material_override
+                .emissive_color
+                .map_or(self.emissive, |color| color.max(Vec3::ZERO).to_array()),
+        }
+    }
+}
+
+/// Geometry generated at runtime, like terrain chunks, rendered by adding a
+/// `Handle<KajiyaMeshData>` next to a [`KajiyaMeshInstance`](crate::KajiyaMeshInstance), whose
+/// `mesh` is then ignored.
+///
+/// Vertex attributes other than `positions` may be left empty: normals are then computed from
+/// the triangles, uvs default to zero, tangents to the +X axis and colors to white. Modifying the
+/// asset uploads the mesh again.
+#[derive(Clone, Debug, Default, PartialEq, TypeUuid)]
+#[uuid = "5b6f3a0e-8f43-4c1e-b3d2-2f7c9d8a1e64"]
+pub struct KajiyaMeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>,
+    pub uvs: Vec<[f32; 2]>,
+    /// Tangent in xyz, and bitangent sign in w.
+    pub tangents: Vec<[f32; 4]>,
+    pub colors: Vec<[f32; 4]>,
+    /// Counter-clockwise triangles.
+    pub indices: Vec<u32>,
+    pub material: KajiyaMeshMaterial,
+}
+
+impl KajiyaMeshData {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let vertex_count = self.positions.len();
+
+        for (name, len) in [
+            ("normals", self.normals.len()),
+            ("uvs", self.uvs.len()),
+            ("tangents", self.tangents.len()),
+            ("colors", self.colors.len()),
+        ] {
+            if len != 0 && len != vertex_count {
+                anyhow::bail!("{} {} for {} positions", len, name, vertex_count);
+            }
+        }
+
+        if self.indices.len() % 3 != 0 {
+            anyhow::bail!("{} indices don't make triangles", self.indices.len());
+        }
+        if let Some(index) = self
+            .indices
+            .iter()
+            .find(|index| **index as usize >= vertex_count)
+        {
+            anyhow::bail!("Index {} out of {} vertices", index, vertex_count);
+        }
+
+        Ok(())
+    }
+
+    pub fn bounds(&self) -> Option<Aabb> {
+        positions_bounds(&self.positions)
+    }
+
+    /// Converts the mesh to kajiya's format. `mirrored` meshes are for instances with mirroring
+    /// transforms, with their triangles wound the other way and bitangents flipped, see
+    /// [`crate::mirror`].
+    pub fn to_triangle_mesh(
+        &self,
+        mirrored: bool,
+        material_override: Option<&KajiyaMaterialOverride>,
+    ) -> anyhow::Result<TriangleMesh> {
+        self.validate()?;
+
+        let vertex_count = self.positions.len();
+
+        let normals = if self.normals.is_empty() {
+            compute_normals(&self.positions, &self.indices)
+        } else {
+            self.normals.clone()
+        };
+
+        let mut tangents = if self.tangents.is_empty() {
+            vec![[1.0, 0.0, 0.0, 1.0]; vertex_count]
+        } else {
+            self.tangents.clone()
+        };
+
+        let mut indices = self.indices.clone();
+        if mirrored {
+            reverse_winding(&mut indices);
+            for tangent in tangents.iter_mut() {
+                *tangent = flip_bitangent(*tangent);
+            }
+        }
+
+        let material = match material_override {
+            Some(material_override) => self.material.with_override(material_override),
+            None => self.material,
+        };
+
+        Ok(TriangleMesh {
+            positions: self.positions.clone(),
+            normals,
+            colors: if self.colors.is_empty() {
+                vec![[1.0; 4]; vertex_count]
+            } else {
+                self.colors.clone()
+            },
+            uvs: if self.uvs.is_empty() {
+                vec![[0.0; 2]; vertex_count]
+            } else {
+                self.uvs.clone()
+            },
+            tangents,
+            material_ids: vec![0; vertex_count],
+            indices,
+            materials: vec![mesh_material(&material)],
+            maps: placeholder_maps(&material),
+        })
+    }
+}
+
+/// Bounds of the vertex `positions`, if there are any.
+pub fn positions_bounds(positions: &[[f32; 3]]) -> Option<Aabb> {
+    let mut positions = positions.iter().map(|position| Vec3::from(*position));
+    let first = positions.next()?;
+    let (min, max) = positions.fold((first, first), |(min, max), position| {
+        (min.min(position), max.max(position))
+    });
+
+    Some(Aabb::from_min_max(min, max))
+}
+
+/// Smooth vertex normals, averaging the normals of the triangles around each vertex, weighted
+/// by their area.
+pub fn compute_normals(positions: &[[f32; 3]], indices: &[u32]) -> Vec<[f32; 3]> {
+    let mut normals = vec![Vec3::ZERO; positions.len()];
+
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [0, 1, 2].map(|i| Vec3::from(positions[triangle[i] as usize]));
+        let normal = (b - a).cross(c - a);
+        for index in triangle {
+            normals[*index as usize] += normal;
+        }
+    }
+
+    normals
+        .into_iter()
+        .map(|normal| normal.try_normalize().unwrap_or(Vec3::Y).to_array())
+        .collect()
+}
+
+// Normal, metalness-roughness, base color and emissive maps, as in kajiya's glTF loader
+const NORMAL_MAP: usize = 0;
+const SPEC_MAP: usize = 1;
+const ALBEDO_MAP: usize = 2;
+const EMISSIVE_MAP: usize = 3;
+
+fn placeholder_maps(material: &KajiyaMeshMaterial) -> Vec<MeshMaterialMap> {
+    let has_emissive = material.emissive.iter().any(|value| *value > 0.0);
+
+    [NORMAL_MAP, SPEC_MAP, ALBEDO_MAP, EMISSIVE_MAP]
+        .into_iter()
+        .map(|map| match map {
+            NORMAL_MAP => MeshMaterialMap::Placeholder([127, 127, 255, 255]),
+            EMISSIVE_MAP if !has_emissive => MeshMaterialMap::Placeholder([0, 0, 0, 255]),
+            _ => MeshMaterialMap::Placeholder([255, 255, 255, 255]),
+        })
+        .collect()
+}
+
+fn mesh_material(material: &KajiyaMeshMaterial) -> MeshMaterial {
+    const IDENTITY_MAP_TRANSFORM: [f32; 6] = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+    MeshMaterial {
+        base_color_mult: material.base_color,
+        maps: [
+            NORMAL_MAP as u32,
+            SPEC_MAP as u32,
+            ALBEDO_MAP as u32,
+            EMISSIVE_MAP as u32,
+        ],
+        roughness_mult: material.roughness,
+        metalness_factor: material.metalness,
+        emissive: material.emissive,
+        flags: 0,
+        map_transforms: [IDENTITY_MAP_TRANSFORM; 4],
+    }
+}
+
+const RUNTIME_MESH_PREFIX: &str = "#runtime";
+
+/// Name of the mesh of a [`KajiyaMeshData`] in the
+/// [`LoadedMeshesMap`](crate::render_instances::LoadedMeshesMap).
+pub fn runtime_mesh_name(handle: &Handle<KajiyaMeshData>) -> String {
+    format!("{}{:016x}", RUNTIME_MESH_PREFIX, runtime_mesh_id(handle))
+}
+
+/// Id of the mesh of a [`KajiyaMeshData`], see [`runtime_mesh_name`].
+pub fn runtime_mesh_id(handle: &Handle<KajiyaMeshData>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    handle.id.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The id of a [`runtime_mesh_name`], without material or mirroring suffixes.
+pub fn parse_runtime_mesh_name(mesh: &str) -> Option<u64> {
+    let id = mesh.strip_prefix(RUNTIME_MESH_PREFIX)?;
+    u64::from_str_radix(id, 16).ok()
+}
+
+/// The [`KajiyaMeshData`] assets used by instances, by runtime mesh id.
+#[derive(Default)]
+pub struct RuntimeMeshes(pub HashMap<u64, Arc<KajiyaMeshData>>);
+
+/// Copies new and modified [`KajiyaMeshData`] to the render world, and reloads the modified ones
+/// like hot reloaded glTF files. Removed ones are forgotten.
+pub fn extract_runtime_meshes(
+    mut ev_mesh: EventReader<AssetEvent<KajiyaMeshData>>,
+    meshes: Res<Assets<KajiyaMeshData>>,
+    mut render_world: ResMut<RenderWorld>,
+) {
+    for ev in ev_mesh.iter() {
+        match ev {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                if let Some(mesh) = meshes.get(handle) {
+                    let id = runtime_mesh_id(handle);
+                    render_world
+                        .get_resource_mut::<RuntimeMeshes>()
+                        .unwrap()
+                        .0
+                        .insert(id, Arc::new(mesh.clone()));
+
+                    if let AssetEvent::Modified { .. } = ev {
+                        render_world
+                            .get_resource_mut::<MeshAssetsState>()
+                            .unwrap()
+                            .meshes_changed
+                            .insert(GltfMeshAsset::from_src_path(runtime_mesh_name(handle)));
+                    }
+                }
+            }
+            AssetEvent::Removed { handle } => {
+                let id = runtime_mesh_id(handle);
+                render_world
+                    .get_resource_mut::<RuntimeMeshes>()
+                    .unwrap()
+                    .0
+                    .remove(&id);
+
+                // Stop tracking the variants of the mesh, and release their uploads
+                let mut lm_map = render_world.get_resource_mut::<LoadedMeshesMap>().unwrap();
+                let mut removed_meshes = Vec::new();
+                lm_map.retain(|key, _| {
+                    let (mesh_name, _) = parse_mesh_key(key);
+                    let (source, _, _) = parse_mesh_variant(mesh_name);
+                    let removed = parse_runtime_mesh_name(source) == Some(id);
+                    if removed {
+                        removed_meshes.push(key.clone());
+                    }
+                    !removed
+                });
+
+                let mut wr_command_queue =
+                    render_world.get_resource_mut::<WRCommandQueue>().unwrap();
+                for mesh_key in removed_meshes {
+                    wr_command_queue.push(WorldRendererCommand::RemoveMesh(mesh_key));
+                }
+            }
+        }
+    }
+}
+
+/// Converts a runtime mesh to kajiya's format and packs it in memory for kajiya's world
+/// renderer, without going through the baked mesh cache. Unlike glTF meshes, it is converted
+/// every time.
+pub fn bake_runtime_mesh(
+    mesh: &KajiyaMeshData,
+    mirrored: bool,
+    material_override: Option<&KajiyaMaterialOverride>,
+) -> anyhow::Result<BakedMesh> {
+    let triangle_mesh = mesh.to_triangle_mesh(mirrored, material_override)?;
+
+    let mut bytes = Vec::new();
+    pack_triangle_mesh(&triangle_mesh).flatten_into(&mut bytes);
+
+    Ok(BakedMesh {
+        data: BakedMeshData::InMemory(PackedMesh::new(&bytes)),
+        cache_hit: false,
+        bounds: mesh.bounds(),
+    })
+}
+
+/// A flattened mesh, copied to memory aligned like the mapped files kajiya normally reads meshes
+/// from. kajiya reads it in place, so it has to be kept until the mesh is removed, see
+/// [`RuntimeMeshUploads`](crate::world_renderer::RuntimeMeshUploads).
+pub struct PackedMesh(Box<[u64]>);
+
+impl PackedMesh {
+    pub fn new(bytes: &[u8]) -> Self {
+        let mut words = vec![0u64; (bytes.len() + 7) / 8];
+        // SAFETY: `words` has room for `bytes`, and the two don't overlap
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                words.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            );
+        }
+
+        Self(words.into_boxed_slice())
+    }
+
+    pub fn as_flat(&self) -> &PackedTriMesh::Flat {
+        // SAFETY: the words were copied from a flattened `PackedTriMesh`
+        unsafe { &*(self.0.as_ptr() as *const PackedTriMesh::Flat) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::HandleId;
+
+    use super::*;
+    use crate::mesh::mesh_key;
+
+    fn quad() -> KajiyaMeshData {
+        KajiyaMeshData {
+            positions: vec![
+                [0.0, 0.0, 0.0],
+                [1.0, 0.0, 0.0],
+                [1.0, 1.0, 0.0],
+                [0.0, 1.0, 0.0],
+            ],
+            indices: vec![0, 1, 2, 0, 2, 3],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn normals_face_the_counter_clockwise_side() {
+        let mesh = quad();
+        let normals = compute_normals(&mesh.positions, &mesh.indices);
+        assert_eq!(normals, vec![[0.0, 0.0, 1.0]; 4]);
+
+        // Vertices without triangles get a default normal rather than NaN
+        let normals = compute_normals(&[[0.0; 3]], &[]);
+        assert_eq!(normals, [[0.0, 1.0, 0.0]]);
+    }
+
+    #[test]
+    fn missing_attributes_get_defaults() {
+        let triangle_mesh = quad().to_triangle_mesh(false, None).unwrap();
+
+        assert_eq!(triangle_mesh.normals, vec![[0.0, 0.0, 1.0]; 4]);
+        assert_eq!(triangle_mesh.uvs, vec![[0.0; 2]; 4]);
+        assert_eq!(triangle_mesh.tangents, vec![[1.0, 0.0, 0.0, 1.0]; 4]);
+        assert_eq!(triangle_mesh.colors, vec![[1.0; 4]; 4]);
+        assert_eq!(triangle_mesh.material_ids, vec![0; 4]);
+        assert_eq!(triangle_mesh.indices, [0, 1, 2, 0, 2, 3]);
+        assert_eq!(triangle_mesh.maps.len(), 4);
+    }
+
+    #[test]
+    fn mirrored_meshes_keep_their_vertices() {
+        let mesh = quad();
+        let triangle_mesh = mesh.to_triangle_mesh(true, None).unwrap();
+
+        assert_eq!(triangle_mesh.positions, mesh.positions);
+        assert_eq!(triangle_mesh.normals, vec![[0.0, 0.0, 1.0]; 4]);
+        assert_eq!(triangle_mesh.indices, [0, 2, 1, 0, 3, 2]);
+        assert_eq!(triangle_mesh.tangents, vec![[1.0, 0.0, 0.0, -1.0]; 4]);
+    }
+
+    #[test]
+    fn overrides_apply_to_the_material() {
+        let material_override = KajiyaMaterialOverride {
+            base_color_tint: Vec4::new(0.5, 1.0, 1.0, 1.0),
+            ..Default::default()
+        };
+        let triangle_mesh = quad()
+            .to_triangle_mesh(false, Some(&material_override))
+            .unwrap();
+
+        assert_eq!(
+            triangle_mesh.materials[0].base_color_mult,
+            [0.5, 1.0, 1.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn invalid_meshes_are_rejected() {
+        let mut mesh = quad();
+        mesh.indices.push(4);
+        assert!(mesh.to_triangle_mesh(false, None).is_err());
+
+        let mut mesh = quad();
+        mesh.normals = vec![[0.0, 0.0, 1.0]];
+        assert!(mesh.to_triangle_mesh(false, None).is_err());
+    }
+
+    #[test]
+    fn runtime_mesh_names_round_trip() {
+        let handle = Handle::<KajiyaMeshData>::weak(HandleId::random::<KajiyaMeshData>());
+        let name = runtime_mesh_name(&handle);
+        assert_eq!(
+            parse_runtime_mesh_name(&name),
+            Some(runtime_mesh_id(&handle))
+        );
+
+        let key = mesh_key(&name, true);
+        let (mesh_name, mirrored) = parse_mesh_key(&key);
+        assert!(mirrored);
+        assert_eq!(mesh_name, name);
+
+        assert_eq!(parse_runtime_mesh_name("car"), None);
+        let other = Handle::<KajiyaMeshData>::weak(HandleId::random::<KajiyaMeshData>());
+        assert_ne!(runtime_mesh_name(&other), name);
+    }
+}
@@ -0,0 +1,402 @@
+use std::path::Path;
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::{BoxedFuture, HashSet},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dynamic_mesh::KajiyaDynamicMesh,
+    gltf_nodes::{KajiyaGltfNode, KajiyaGltfNodes},
+    light::KajiyaLightProxy,
+    lod::KajiyaLod,
+    mesh::{KajiyaMeshInstance, KajiyaMeshInstanceBundle},
+    runtime_mesh::KajiyaMeshData,
+};
+
+/// A scene, in the `.ron` format of kajiya's standalone viewer.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SceneDesc {
+    pub instances: Vec<SceneInstanceDesc>,
+}
+
+impl SceneDesc {
+    /// Describes every [`KajiyaMeshInstance`] of the world, at its [`GlobalTransform`].
+    ///
+    /// Only glTF meshes can be saved: runtime meshes, including the dynamic and skinned ones and
+    /// the proxies of lights, have no file for kajiya to load and are skipped. Instances with a
+    /// [`KajiyaLod`] are saved with their most detailed level.
+    ///
+    /// kajiya can't load the meshes of single glTF nodes, so [`KajiyaGltfNodes`] are saved as
+    /// their whole glTF mesh, at the transform of their root entity: nodes moved since they were
+    /// spawned are saved at their place in the glTF file.
+    pub fn from_mesh_instances(world: &mut World) -> Self {
+        let mut query = world
+            .query_filtered::<(&KajiyaMeshInstance, &GlobalTransform, Option<&KajiyaLod>), (
+                Without<Handle<KajiyaMeshData>>,
+                Without<KajiyaDynamicMesh>,
+                Without<KajiyaLightProxy>,
+                Without<KajiyaGltfNode>,
+            )>();
+        let mut instances: Vec<SceneInstanceDesc> = query
+            .iter(world)
+            .filter_map(|(mesh_instance, transform, lod)| {
+                let mesh = match lod.and_then(|lod| lod.levels.first()) {
+                    Some(level) => &level.mesh,
+                    None => &mesh_instance.mesh,
+                };
+                if mesh.is_empty() {
+                    return None;
+                }
+
+                Some(SceneInstanceDesc::new(mesh.clone(), transform))
+            })
+            .collect();
+
+        let mut gltf_nodes_query = world.query::<(&KajiyaGltfNodes, &GlobalTransform)>();
+        instances.extend(
+            gltf_nodes_query
+                .iter(world)
+                .filter(|(gltf_nodes, _)| !gltf_nodes.mesh.is_empty())
+                .map(|(gltf_nodes, transform)| {
+                    SceneInstanceDesc::new(gltf_nodes.mesh.clone(), transform)
+                }),
+        );
+
+        Self { instances }
+    }
+
+    pub fn to_ron(&self) -> anyhow::Result<String> {
+        Ok(ron::ser::to_string_pretty(
+            self,
+            ron::ser::PrettyConfig::new(),
+        )?)
+    }
+}
+
+/// Writes every [`KajiyaMeshInstance`] of the world to a scene file which kajiya's standalone
+/// viewer and [`KajiyaSceneAsset`] can load, see [`SceneDesc::from_mesh_instances`] for the
+/// instances left out.
+///
+/// Global transforms are only up to date after [`CoreStage::PostUpdate`], so instances moved in
+/// the current frame are saved at their previous position.
+pub fn save_kajiya_scene(world: &mut World, path: impl AsRef<Path>) -> anyhow::Result<()> {
+    let scene_desc = SceneDesc::from_mesh_instances(world);
+    std::fs::write(path, scene_desc.to_ron()?)?;
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SceneInstanceDesc {
+    pub position: [f32; 3],
+    /// Euler angles in degrees, applied in Y, X, Z order like kajiya's viewer.
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    pub mesh: String,
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+impl SceneInstanceDesc {
+    /// kajiya scenes only support uniform scales, so non-uniform ones are averaged.
+    pub fn new(mesh: String, transform: &GlobalTransform) -> Self {
+        let (scale, rotation, translation) = transform.to_scale_rotation_translation();
+        let (y, x, z) = rotation.to_euler(EulerRot::YXZ);
+
+        let uniform_scale = (scale.x + scale.y + scale.z) / 3.0;
+        if (scale - Vec3::splat(uniform_scale)).abs().max_element() > 1e-4 {
+            log::warn!(
+                "The non-uniform scale {} of a '{}' instance is saved as {}",
+                scale,
+                mesh,
+                uniform_scale
+            );
+        }
+
+        Self {
+            position: translation.to_array(),
+            rotation: [x.to_degrees(), y.to_degrees(), z.to_degrees()],
+            mesh,
+            scale: uniform_scale,
+        }
+    }
+
+    pub fn transform(&self) -> Transform {
+        let [x, y, z] = self.rotation;
+
+        Transform {
+            translation: Vec3::from(self.position),
+            rotation: Quat::from_euler(
+                EulerRot::YXZ,
+                y.to_radians(),
+                x.to_radians(),
+                z.to_radians(),
+            ),
+            scale: Vec3::splat(self.scale),
+        }
+    }
+
+    /// The [`KajiyaMeshInstance::mesh`] of the instance. kajiya scenes may refer to meshes by
+    /// name or by path (ex: "/meshes/my_mesh/scene.gltf" or "/baked/my_mesh.mesh").
+    pub fn mesh_name(&self) -> &str {
+        let mesh = self.mesh.as_str();
+        let mesh = mesh.strip_suffix("/scene.gltf").unwrap_or(mesh);
+        let mesh = mesh.strip_suffix(".mesh").unwrap_or(mesh);
+
+        mesh.rsplit('/').next().unwrap_or(mesh)
+    }
+}
+
+#[derive(Clone, Debug, TypeUuid)]
+#[uuid = "a3c1b3f4-52b5-4d47-9e0b-6a0c8d1f7e25"]
+pub struct KajiyaSceneAsset(pub SceneDesc);
+
+#[derive(Default)]
+pub struct KajiyaSceneAssetLoader;
+
+impl AssetLoader for KajiyaSceneAssetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let scene_desc: SceneDesc = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(KajiyaSceneAsset(scene_desc)));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["ron"]
+    }
+}
+
+/// Spawns the instances of a [`KajiyaSceneAsset`] as children of this entity.
+#[derive(Bundle, Default)]
+pub struct KajiyaSceneBundle {
+    pub scene: Handle<KajiyaSceneAsset>,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+/// Marks an entity spawned from the instance `index` of the scene on the `scene` entity.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct KajiyaSceneInstance {
+    pub scene: Entity,
+    pub index: usize,
+}
+
+/// The instance entities spawned for a scene entity, by instance index.
+#[derive(Component, Clone, Debug)]
+pub struct KajiyaSceneInstances {
+    pub handle: Handle<KajiyaSceneAsset>,
+    pub entities: Vec<Entity>,
+}
+
+/// Spawns the instances of newly loaded scenes, and updates them when the scene changes:
+/// existing entities are modified in place, extra ones are spawned or despawned.
+pub fn update_kajiya_scenes(
+    mut commands: Commands,
+    scenes: Res<Assets<KajiyaSceneAsset>>,
+    mut scene_events: EventReader<AssetEvent<KajiyaSceneAsset>>,
+    scene_query: Query<(
+        Entity,
+        &Handle<KajiyaSceneAsset>,
+        Option<&KajiyaSceneInstances>,
+    )>,
+    mut instance_query: Query<(&mut KajiyaMeshInstance, &mut Transform), With<KajiyaSceneInstance>>,
+) {
+    let modified_scenes: HashSet<Handle<KajiyaSceneAsset>> = scene_events
+        .iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { handle } => Some(handle.clone()),
+            _ => None,
+        })
+        .collect();
+
+    for (scene_entity, handle, instances) in scene_query.iter() {
+        let up_to_date = instances.map_or(false, |instances| instances.handle == *handle);
+        if up_to_date && !modified_scenes.contains(handle) {
+            continue;
+        }
+
+        let scene_desc = match scenes.get(handle) {
+            Some(scene) => &scene.0,
+            None => continue,
+        };
+
+        let previous_entities = instances
+            .map(|instances| instances.entities.as_slice())
+            .unwrap_or_default();
+        let mut entities = Vec::with_capacity(scene_desc.instances.len());
+
+        for (index, instance_desc) in scene_desc.instances.iter().enumerate() {
+            let transform = instance_desc.transform();
+
+            let entity = match previous_entities.get(index) {
+                Some(entity) => {
+                    if let Ok((mut mesh_instance, mut instance_transform)) =
+                        instance_query.get_mut(*entity)
+                    {
+                        if mesh_instance.mesh != instance_desc.mesh_name() {
+                            mesh_instance.mesh = instance_desc.mesh_name().to_string();
+                        }
+                        if *instance_transform != transform {
+                            *instance_transform = transform;
+                        }
+                    }
+
+                    *entity
+                }
+                None => {
+                    let entity = commands
+                        .spawn_bundle(KajiyaMeshInstanceBundle {
+                            mesh_instance: KajiyaMeshInstance {
+                                mesh: instance_desc.mesh_name().to_string(),
+                                ..Default::default()
+                            },
+                            transform,
+                            ..Default::default()
+                        })
+                        .insert(KajiyaSceneInstance {
+                            scene: scene_entity,
+                            index,
+                        })
+                        .id();
+                    commands.entity(scene_entity).add_child(entity);
+
+                    entity
+                }
+            };
+
+            entities.push(entity);
+        }
+
+        for entity in previous_entities.iter().skip(entities.len()) {
+            commands.entity(*entity).despawn_recursive();
+        }
+
+        commands.entity(scene_entity).insert(KajiyaSceneInstances {
+            handle: handle.clone(),
+            entities,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gltf_nodes::KajiyaGltfNodesBundle, mesh::node_mesh_name};
+
+    fn spawn_instance(world: &mut World, mesh: &str, transform: Transform) -> Entity {
+        world
+            .spawn()
+            .insert_bundle(KajiyaMeshInstanceBundle {
+                mesh_instance: KajiyaMeshInstance {
+                    mesh: mesh.to_string(),
+                    ..Default::default()
+                },
+                global_transform: GlobalTransform::from(transform),
+                transform,
+            })
+            .id()
+    }
+
+    #[test]
+    fn saved_scenes_load_back() {
+        let mut world = World::new();
+        let transform = Transform::from_xyz(1.0, 2.0, 3.0)
+            .with_rotation(Quat::from_euler(EulerRot::YXZ, 0.3, -0.2, 0.1))
+            .with_scale(Vec3::splat(2.0));
+        spawn_instance(&mut world, "car", transform);
+        spawn_instance(&mut world, "floor", Transform::identity());
+
+        let scene_desc = SceneDesc::from_mesh_instances(&mut world);
+        let loaded: SceneDesc = ron::de::from_str(&scene_desc.to_ron().unwrap()).unwrap();
+        assert_eq!(loaded, scene_desc);
+
+        let mut instances = loaded.instances;
+        instances.sort_by(|a, b| a.mesh.cmp(&b.mesh));
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].mesh_name(), "car");
+
+        let loaded_transform = instances[0].transform();
+        assert!(loaded_transform
+            .translation
+            .abs_diff_eq(transform.translation, 1e-5));
+        assert!(loaded_transform
+            .rotation
+            .abs_diff_eq(transform.rotation, 1e-5));
+        assert!(loaded_transform.scale.abs_diff_eq(transform.scale, 1e-5));
+    }
+
+    #[test]
+    fn runtime_instances_are_not_saved() {
+        let mut world = World::new();
+        spawn_instance(&mut world, "car", Transform::identity());
+
+        let runtime = spawn_instance(&mut world, "", Transform::identity());
+        world
+            .entity_mut(runtime)
+            .insert(Handle::<KajiyaMeshData>::default());
+        let dynamic = spawn_instance(&mut world, "", Transform::identity());
+        world
+            .entity_mut(dynamic)
+            .insert(KajiyaDynamicMesh::default());
+        let light = world.spawn().id();
+        let proxy = spawn_instance(&mut world, "", Transform::identity());
+        world.entity_mut(proxy).insert(KajiyaLightProxy { light });
+
+        let lod = spawn_instance(&mut world, "", Transform::identity());
+        world
+            .entity_mut(lod)
+            .insert(KajiyaLod::new([("tree_lod0", 20.0), ("tree_lod1", 0.0)]));
+
+        let mut meshes: Vec<String> = SceneDesc::from_mesh_instances(&mut world)
+            .instances
+            .into_iter()
+            .map(|instance| instance.mesh)
+            .collect();
+        meshes.sort();
+        assert_eq!(meshes, ["car", "tree_lod0"]);
+    }
+
+    #[test]
+    fn gltf_nodes_are_saved_as_their_whole_mesh() {
+        let mut world = World::new();
+        let transform = Transform::from_xyz(4.0, 0.0, 1.0);
+        let scene = world
+            .spawn()
+            .insert_bundle(KajiyaGltfNodesBundle {
+                nodes: KajiyaGltfNodes {
+                    mesh: "car".to_string(),
+                },
+                global_transform: GlobalTransform::from(transform),
+                transform,
+            })
+            .id();
+
+        let wheel = spawn_instance(
+            &mut world,
+            &node_mesh_name("car", 3),
+            Transform::from_xyz(1.0, 0.0, 0.0),
+        );
+        world
+            .entity_mut(wheel)
+            .insert(KajiyaGltfNode { scene, index: 3 });
+
+        let instances = SceneDesc::from_mesh_instances(&mut world).instances;
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].mesh, "car");
+        assert_eq!(instances[0].position, [4.0, 0.0, 1.0]);
+    }
+}
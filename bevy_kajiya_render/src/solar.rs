@@ -0,0 +1,382 @@
+use bevy::prelude::*;
+
+use crate::{camera::EnvironmentSettings, sun::KajiyaSun};
+
+/// In-game clock and location moving the sun like on the earth, using NOAA's solar position
+/// algorithm. The clock drives the [`KajiyaSun`] of its entity, or else its
+/// [`EnvironmentSettings`], usually the camera's.
+///
+/// The world is oriented with north towards -Z, east towards +X and up towards +Y. Below the
+/// horizon, the sun keeps lighting the scene from underneath unless the ground blocks it.
+#[derive(Component, Reflect, Clone, Debug, PartialEq)]
+#[reflect(Component)]
+pub struct SolarClock {
+    /// Degrees, positive towards the north.
+    pub latitude: f32,
+    /// Degrees, positive towards the east.
+    pub longitude: f32,
+    pub year: i32,
+    /// 1 to 12, clamped when the clock advances.
+    pub month: u32,
+    /// 1 to the number of days of `month`, clamped when the clock advances.
+    pub day: u32,
+    /// Local time of day, in hours from midnight. Double precision so that small steps of a
+    /// running clock aren't lost late in the day.
+    pub time: f64,
+    /// Offset of the local time from UTC, in hours.
+    pub utc_offset: f32,
+    /// In-game seconds per real second, zero to stop the clock.
+    pub time_scale: f32,
+}
+
+impl Default for SolarClock {
+    fn default() -> Self {
+        // Greenwich at noon on the summer solstice
+        Self {
+            latitude: 51.48,
+            longitude: 0.0,
+            year: 2022,
+            month: 6,
+            day: 21,
+            time: 12.0,
+            utc_offset: 0.0,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl SolarClock {
+    /// Clamps `month` and `day` to a valid date, ex: day 0 is the first day of the month.
+    pub fn clamp_date(&mut self) {
+        self.month = self.month.clamp(1, 12);
+        self.day = self.day.clamp(1, days_in_month(self.year, self.month));
+    }
+
+    /// Advances the clock by `seconds` of in-game time, moving to the next or previous days.
+    pub fn advance(&mut self, seconds: f64) {
+        self.clamp_date();
+        self.time += seconds / 3600.0;
+
+        while self.time >= 24.0 {
+            self.time -= 24.0;
+            self.day += 1;
+            if self.day > days_in_month(self.year, self.month) {
+                self.day = 1;
+                self.month += 1;
+                if self.month > 12 {
+                    self.month = 1;
+                    self.year += 1;
+                }
+            }
+        }
+
+        while self.time < 0.0 {
+            self.time += 24.0;
+            self.day -= 1;
+            if self.day == 0 {
+                self.month -= 1;
+                if self.month == 0 {
+                    self.month = 12;
+                    self.year -= 1;
+                }
+                self.day = days_in_month(self.year, self.month);
+            }
+        }
+    }
+
+    /// Julian day of the current UTC time.
+    pub fn julian_day(&self) -> f64 {
+        let hours = self.time - self.utc_offset as f64;
+        julian_day(self.year, self.month, self.day) + hours / 24.0
+    }
+
+    pub fn solar_position(&self) -> SolarPosition {
+        solar_position(
+            self.julian_day(),
+            self.latitude as f64,
+            self.longitude as f64,
+        )
+    }
+
+    /// Direction towards the sun, in world space.
+    pub fn sun_direction(&self) -> Vec3 {
+        let position = self.solar_position();
+        horizontal_to_direction(position.azimuth as f32, position.elevation as f32)
+    }
+}
+
+pub fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if (year % 4 == 0 && year % 100 != 0) || year % 400 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Julian day at 0h UTC of a date of the Gregorian calendar.
+pub fn julian_day(year: i32, month: u32, day: u32) -> f64 {
+    let (year, month) = if month <= 2 {
+        (year as f64 - 1.0, month as f64 + 12.0)
+    } else {
+        (year as f64, month as f64)
+    };
+
+    let century = (year / 100.0).floor();
+    let gregorian_correction = 2.0 - century + (century / 4.0).floor();
+
+    (365.25 * (year + 4716.0)).floor() + (30.6001 * (month + 1.0)).floor() + day as f64 - 1524.5
+        + gregorian_correction
+}
+
+/// Position of the sun seen from a point of the earth, in degrees, and minutes for the equation
+/// of time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SolarPosition {
+    /// Clockwise from the north.
+    pub azimuth: f64,
+    /// Above the horizon, corrected for atmospheric refraction.
+    pub elevation: f64,
+    pub declination: f64,
+    /// Apparent minus mean solar time.
+    pub equation_of_time: f64,
+}
+
+/// NOAA's solar position algorithm, after Meeus' "Astronomical Algorithms", at the UTC
+/// `julian_day`. `latitude` and `longitude` are in degrees, positive towards the north and east.
+pub fn solar_position(julian_day: f64, latitude: f64, longitude: f64) -> SolarPosition {
+    let century = (julian_day - 2451545.0) / 36525.0;
+
+    let mean_longitude = (280.46646 + century * (36000.76983 + century * 0.0003032)) % 360.0;
+    let mean_anomaly = 357.52911 + century * (35999.05029 - 0.0001537 * century);
+    let eccentricity = 0.016708634 - century * (0.000042037 + 0.0000001267 * century);
+
+    let anomaly = mean_anomaly.to_radians();
+    let equation_of_center = anomaly.sin() * (1.914602 - century * (0.004817 + 0.000014 * century))
+        + (2.0 * anomaly).sin() * (0.019993 - 0.000101 * century)
+        + (3.0 * anomaly).sin() * 0.000289;
+
+    let true_longitude = mean_longitude + equation_of_center;
+    let omega = (125.04 - 1934.136 * century).to_radians();
+    let apparent_longitude = true_longitude - 0.00569 - 0.00478 * omega.sin();
+
+    let mean_obliquity = 23.0
+        + (26.0 + (21.448 - century * (46.815 + century * (0.00059 - century * 0.001813))) / 60.0)
+            / 60.0;
+    let obliquity = (mean_obliquity + 0.00256 * omega.cos()).to_radians();
+
+    let declination = (obliquity.sin() * apparent_longitude.to_radians().sin()).asin();
+
+    let y = (obliquity / 2.0).tan().powi(2);
+    let l0 = mean_longitude.to_radians();
+    let equation_of_time = 4.0
+        * (y * (2.0 * l0).sin() - 2.0 * eccentricity * anomaly.sin()
+            + 4.0 * eccentricity * y * anomaly.sin() * (2.0 * l0).cos()
+            - 0.5 * y * y * (4.0 * l0).sin()
+            - 1.25 * eccentricity * eccentricity * (2.0 * anomaly).sin())
+        .to_degrees();
+
+    let utc_minutes = (julian_day + 0.5).fract() * 1440.0;
+    let true_solar_time = (utc_minutes + equation_of_time + 4.0 * longitude).rem_euclid(1440.0);
+    let hour_angle = (true_solar_time / 4.0 - 180.0).to_radians();
+
+    let latitude = latitude.to_radians();
+    let cos_zenith = (latitude.sin() * declination.sin()
+        + latitude.cos() * declination.cos() * hour_angle.cos())
+    .clamp(-1.0, 1.0);
+    let zenith = cos_zenith.acos();
+
+    let sin_product = latitude.cos() * zenith.sin();
+    let azimuth = if sin_product.abs() < 1e-9 {
+        // At a pole or with the sun at the zenith
+        if latitude > 0.0 {
+            180.0
+        } else {
+            0.0
+        }
+    } else {
+        let cos_azimuth = ((latitude.sin() * cos_zenith - declination.sin()) / sin_product)
+            .clamp(-1.0, 1.0)
+            .acos()
+            .to_degrees();
+        if hour_angle > 0.0 {
+            (cos_azimuth + 180.0) % 360.0
+        } else {
+            (540.0 - cos_azimuth) % 360.0
+        }
+    };
+
+    let elevation = 90.0 - zenith.to_degrees();
+
+    SolarPosition {
+        azimuth,
+        elevation: elevation + atmospheric_refraction(elevation),
+        declination: declination.to_degrees(),
+        equation_of_time,
+    }
+}
+
+/// NOAA's approximation of the atmospheric refraction, in degrees, at a geometric `elevation`.
+pub fn atmospheric_refraction(elevation: f64) -> f64 {
+    let tan_elevation = elevation.to_radians().tan();
+
+    let arc_seconds = if elevation > 85.0 {
+        0.0
+    } else if elevation > 5.0 {
+        58.1 / tan_elevation - 0.07 / tan_elevation.powi(3) + 0.000086 / tan_elevation.powi(5)
+    } else if elevation > -0.575 {
+        1735.0
+            + elevation * (-518.2 + elevation * (103.4 + elevation * (-12.79 + elevation * 0.711)))
+    } else {
+        -20.772 / tan_elevation
+    };
+
+    arc_seconds / 3600.0
+}
+
+/// World space direction of the `azimuth` and `elevation` in degrees, with north towards -Z and
+/// east towards +X.
+pub fn horizontal_to_direction(azimuth: f32, elevation: f32) -> Vec3 {
+    let (azimuth, elevation) = (azimuth.to_radians(), elevation.to_radians());
+    Vec3::new(
+        azimuth.sin() * elevation.cos(),
+        elevation.sin(),
+        -azimuth.cos() * elevation.cos(),
+    )
+}
+
+/// Advances the [`SolarClock`]s, and points their sun to the sky.
+pub fn update_solar_clocks(
+    time: Res<Time>,
+    mut query: Query<(
+        &mut SolarClock,
+        Option<&mut KajiyaSun>,
+        Option<&mut EnvironmentSettings>,
+    )>,
+) {
+    for (mut clock, sun, environment_settings) in query.iter_mut() {
+        if clock.time_scale != 0.0 {
+            let seconds = time.delta_seconds_f64() * clock.time_scale as f64;
+            clock.advance(seconds);
+        }
+
+        let direction = clock.sun_direction();
+        if let Some(mut sun) = sun {
+            if sun.direction != Some(direction) {
+                sun.direction = Some(direction);
+            }
+        } else if let Some(mut environment_settings) = environment_settings {
+            environment_settings.sun_theta_phi =
+                crate::sun::direction_to_theta_phi(glam::Vec3::from(direction.to_array()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn greenwich(month: u32, day: u32, time: f64) -> SolarPosition {
+        SolarClock {
+            month,
+            day,
+            time,
+            ..Default::default()
+        }
+        .solar_position()
+    }
+
+    fn assert_close(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (value - expected).abs() < tolerance,
+            "{} isn't {} +- {}",
+            value,
+            expected,
+            tolerance
+        );
+    }
+
+    // Reference values from NOAA's solar calculator, for Greenwich (51.48N) in 2022 at UTC
+    #[test]
+    fn solstice_noon_at_greenwich() {
+        let position = greenwich(6, 21, 12.0);
+
+        assert_close(position.elevation, 61.96, 0.01);
+        assert_close(position.declination, 23.44, 0.01);
+        assert_close(position.equation_of_time, -1.81, 0.05);
+        // Solar noon is a couple of minutes later
+        assert_close(position.azimuth, 179.12, 0.05);
+    }
+
+    #[test]
+    fn sun_rises_in_the_east_and_sets_in_the_west() {
+        let morning = greenwich(6, 21, 6.0);
+        assert_close(morning.azimuth, 74.57, 0.05);
+        assert_close(morning.elevation, 17.92, 0.05);
+
+        let evening = greenwich(6, 21, 18.0);
+        assert_close(evening.azimuth, 284.77, 0.05);
+        assert_close(evening.elevation, 18.46, 0.05);
+
+        // In the southern hemisphere, the sun culminates in the north
+        let sydney = solar_position(julian_day(2022, 12, 21) + 2.0 / 24.0, -33.87, 151.21);
+        assert_close(sydney.azimuth, 351.22, 0.05);
+        assert_close(sydney.elevation, 79.46, 0.05);
+    }
+
+    #[test]
+    fn equation_of_time_extremes() {
+        assert_close(greenwich(11, 3, 12.0).equation_of_time, 16.49, 0.05);
+        assert_close(greenwich(2, 11, 12.0).equation_of_time, -14.23, 0.05);
+
+        // March equinox at 15:33 UTC
+        assert_close(greenwich(3, 20, 15.55).declination, 0.0, 0.01);
+    }
+
+    #[test]
+    fn clock_rolls_over_days_and_clamps_dates() {
+        let mut clock = SolarClock {
+            year: 2022,
+            month: 12,
+            day: 31,
+            time: 23.0,
+            ..Default::default()
+        };
+        clock.advance(2.0 * 3600.0);
+        assert_eq!((clock.year, clock.month, clock.day), (2023, 1, 1));
+        assert!((clock.time - 1.0).abs() < 1e-9);
+
+        clock.advance(-2.0 * 3600.0);
+        assert_eq!((clock.year, clock.month, clock.day), (2022, 12, 31));
+
+        // Day 0 would underflow when going back a day
+        let mut clock = SolarClock {
+            month: 3,
+            day: 0,
+            time: 0.5,
+            ..Default::default()
+        };
+        clock.advance(-3600.0);
+        assert_eq!((clock.month, clock.day), (2, 28));
+
+        let mut clock = SolarClock {
+            month: 2,
+            day: 31,
+            ..Default::default()
+        };
+        clock.clamp_date();
+        assert_eq!((clock.month, clock.day), (2, 28));
+    }
+
+    #[test]
+    fn small_steps_accumulate() {
+        let mut clock = SolarClock {
+            time: 23.0,
+            ..Default::default()
+        };
+        for _ in 0..3600 {
+            clock.advance(1.0 / 60.0);
+        }
+        assert!((clock.time - (23.0 + 60.0 / 3600.0)).abs() < 1e-9);
+    }
+}
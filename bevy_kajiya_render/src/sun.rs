@@ -0,0 +1,125 @@
+use bevy::prelude::*;
+
+use crate::{camera::ExtractedCamera, render_resources::KajiyaRenderers};
+
+/// Apparent diameter of the sun seen from the earth, in degrees, which kajiya's sun is sized to.
+pub const SUN_ANGULAR_DIAMETER: f32 = 0.53;
+
+/// The sun lighting the scene. When present, it overrides the sun of [`EnvironmentSettings`].
+///
+/// Without an explicit `direction`, the sun shines along the forward (-Z) axis of the entity's
+/// [`GlobalTransform`], like a directional light.
+///
+/// [`EnvironmentSettings`]: crate::EnvironmentSettings
+#[derive(Component, Clone, Debug, PartialEq)]
+pub struct KajiyaSun {
+    /// Direction towards the sun, in world space.
+    pub direction: Option<Vec3>,
+    /// Linear RGB, multiplied by `intensity`.
+    pub color: Vec3,
+    /// Multiplier of kajiya's sun radiance.
+    pub intensity: f32,
+    /// Apparent diameter of the sun disc in degrees. Larger suns cast softer shadows.
+    pub angular_diameter: f32,
+}
+
+impl Default for KajiyaSun {
+    fn default() -> Self {
+        Self {
+            direction: None,
+            color: Vec3::ONE,
+            intensity: 1.0,
+            angular_diameter: SUN_ANGULAR_DIAMETER,
+        }
+    }
+}
+
+impl KajiyaSun {
+    /// Direction towards the sun, from `direction` or else the transform of the entity.
+    pub fn sun_direction(&self, transform: Option<&GlobalTransform>) -> Vec3 {
+        let direction = match (self.direction, transform) {
+            (Some(direction), _) => direction,
+            (None, Some(transform)) => -transform.forward(),
+            (None, None) => Vec3::Y,
+        };
+
+        direction.try_normalize().unwrap_or(Vec3::Y)
+    }
+}
+
+#[derive(Bundle, Default)]
+pub struct KajiyaSunBundle {
+    pub sun: KajiyaSun,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+/// Spherical coordinates in degrees of the unit `direction`: the azimuth `theta` from +X towards
+/// +Z, and the angle `phi` from the zenith (+Y).
+pub fn direction_to_theta_phi(direction: glam::Vec3) -> (f32, f32) {
+    let direction = direction.normalize();
+    let theta = direction.z.atan2(direction.x).to_degrees();
+    let phi = direction.y.clamp(-1.0, 1.0).acos().to_degrees();
+    (theta, phi)
+}
+
+/// Unit direction of the spherical coordinates `theta` and `phi`, in degrees.
+pub fn theta_phi_to_direction(theta: f32, phi: f32) -> glam::Vec3 {
+    let (theta, phi) = (theta.to_radians(), phi.to_radians());
+    glam::Vec3::new(phi.sin() * theta.cos(), phi.cos(), phi.sin() * theta.sin())
+}
+
+/// Sets the color and size of kajiya's sun from the extracted environment.
+pub fn prepare_sun(wr_res: NonSendMut<KajiyaRenderers>, extracted_camera: Res<ExtractedCamera>) {
+    let environment = &extracted_camera.environment;
+    let mut world_renderer = wr_res.world_renderer.lock().unwrap();
+    world_renderer.sun_color_multiplier = environment.sun_color_multiplier;
+    world_renderer.sun_size_multiplier = environment.sun_size_multiplier;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn theta_phi_of_the_axes() {
+        let axes = [
+            (glam::Vec3::Y, 0.0),
+            (-glam::Vec3::Y, 180.0),
+            (glam::Vec3::X, 90.0),
+            (glam::Vec3::Z, 90.0),
+        ];
+        for (direction, expected_phi) in axes {
+            let (_, phi) = direction_to_theta_phi(direction);
+            assert!((phi - expected_phi).abs() < 1e-4, "{}: {}", direction, phi);
+        }
+
+        assert!(direction_to_theta_phi(glam::Vec3::X).0.abs() < 1e-4);
+        assert!((direction_to_theta_phi(glam::Vec3::Z).0 - 90.0).abs() < 1e-4);
+        assert!((direction_to_theta_phi(-glam::Vec3::X).0.abs() - 180.0).abs() < 1e-4);
+        assert!((direction_to_theta_phi(-glam::Vec3::Z).0 + 90.0).abs() < 1e-4);
+
+        // Not normalized
+        let (theta, phi) = direction_to_theta_phi(glam::Vec3::new(0.0, 0.0, 5.0));
+        assert!((theta - 90.0).abs() < 1e-4 && (phi - 90.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn directions_round_trip_through_theta_phi() {
+        for direction in [
+            glam::Vec3::new(1.0, 2.0, 3.0),
+            glam::Vec3::new(-0.3, 0.8, 0.1),
+            glam::Vec3::new(0.5, -0.2, -0.9),
+            glam::Vec3::new(-1.0, 0.1, -1.0),
+        ] {
+            let direction = direction.normalize();
+            let (theta, phi) = direction_to_theta_phi(direction);
+            let round_trip = theta_phi_to_direction(theta, phi);
+            assert!(round_trip.abs_diff_eq(direction, 1e-5), "{}", direction);
+        }
+
+        let direction = theta_phi_to_direction(30.0, 60.0);
+        let (theta, phi) = direction_to_theta_phi(direction);
+        assert!((theta - 30.0).abs() < 1e-4 && (phi - 60.0).abs() < 1e-4);
+    }
+}
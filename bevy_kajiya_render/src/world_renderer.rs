@@ -1,62 +1,51 @@
-use std::path::PathBuf;
+#[cfg(feature = "mesh_updates")]
+use std::sync::Arc;
 
 use bevy::{prelude::*, utils::HashMap};
-use glam::{Affine3A, Vec3};
+use glam::Vec3;
 use kajiya::{
-    camera::{CameraLens, LookThroughCamera},
     frame_desc::WorldFrameDesc,
-    world_renderer::{AddMeshOptions, InstanceHandle, MeshHandle},
+    world_renderer::{AddMeshOptions, InstanceHandle, MeshHandle, WorldRenderer},
 };
+use kajiya_asset::mesh::PackedTriMesh;
 
+#[cfg(feature = "mesh_updates")]
+use crate::dynamic_mesh::KajiyaMeshVertices;
+#[cfg(feature = "instance_materials")]
+use crate::material::KajiyaMaterialOverride;
 use crate::{
     camera::{ExtractedCamera, KajiyaCamera},
-    mesh::{MeshTransform, RenderInstances},
+    diagnostics::KajiyaRenderStats,
+    mesh::{BakedMeshData, MeshTransform, RenderInstances},
     render_instances::{LoadedMeshesMap, RenderInstancesMap, RenderMesh, WRInstance},
     render_resources::{KajiyaRenderers, RenderContext},
+    runtime_mesh::PackedMesh,
+    sun::{direction_to_theta_phi, theta_phi_to_direction},
 };
 
-#[derive(serde::Deserialize)]
-pub struct SceneDesc {
-    pub instances: Vec<SceneInstanceDesc>,
-}
-
-#[derive(serde::Deserialize)]
-pub struct SceneInstanceDesc {
-    pub position: [f32; 3],
-    pub mesh: String,
-    pub scale: f32,
-}
-
-#[derive(Clone, serde::Serialize, serde::Deserialize, Default)]
+/// Direction towards the sun in spherical coordinates, in degrees: `theta` is the azimuth from +X
+/// towards +Z, and `phi` the angle from the zenith (+Y).
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize, Default)]
 pub struct SunState {
-    pub(crate) theta: f32,
-    pub(crate) phi: f32,
+    pub theta: f32,
+    pub phi: f32,
 }
 
 impl SunState {
-    pub fn direction(&self) -> Vec3 {
-        fn spherical_to_cartesian(theta: f32, phi: f32) -> Vec3 {
-            let x = phi.sin() * theta.cos();
-            let y = phi.cos();
-            let z = phi.sin() * theta.sin();
-            Vec3::new(x, y, z)
-        }
+    pub fn from_direction(direction: Vec3) -> Self {
+        let (theta, phi) = direction_to_theta_phi(direction);
+        Self { theta, phi }
+    }
 
-        spherical_to_cartesian(self.theta, self.phi)
+    pub fn direction(&self) -> Vec3 {
+        theta_phi_to_direction(self.theta, self.phi)
     }
 }
 
 pub fn setup_world_renderer(mut commands: Commands, render_context: Res<RenderContext>) {
-    // let scene_file = format!("assets/scenes/{}.ron", scene.scene_name);
-    // let scene_desc: SceneDesc = ron::de::from_reader(
-    //     File::open(&scene_file).expect("Kajiya error: Could not open scene description file"),
-    // )
-    // .expect("Kajiya error: Could not read description file");
-
     let render_instances = RenderInstances {
         user_instances: HashMap::default(),
         unique_loaded_meshes: HashMap::default(),
-        scene_mesh_instance_queue: Vec::default(),
     };
 
     let extracted_camera = ExtractedCamera {
@@ -67,13 +56,10 @@ pub fn setup_world_renderer(mut commands: Commands, render_context: Res<RenderCo
         ..Default::default()
     };
 
-    let lens = CameraLens {
-        aspect_ratio: extracted_camera.camera.aspect_ratio,
-        vertical_fov: extracted_camera.camera.vertical_fov,
-        near_plane_distance: extracted_camera.camera.near_plane_distance,
-    };
     let frame_desc = WorldFrameDesc {
-        camera_matrices: extracted_camera.transform.through(&lens),
+        camera_matrices: extracted_camera
+            .camera
+            .camera_matrices(extracted_camera.transform),
         render_extent: render_context.render_extent,
         sun_direction: extracted_camera.environment.sun_theta_phi.direction(),
     };
@@ -88,60 +74,125 @@ pub fn update_world_renderer_view(
     extracted_camera: Res<ExtractedCamera>,
 ) {
     // Update WorldFrameDescription
-    let lens = CameraLens {
-        aspect_ratio: extracted_camera.camera.aspect_ratio,
-        vertical_fov: extracted_camera.camera.vertical_fov,
-        near_plane_distance: extracted_camera.camera.near_plane_distance,
-    };
-    frame_desc.camera_matrices = extracted_camera.transform.through(&lens);
+    frame_desc.camera_matrices = extracted_camera
+        .camera
+        .camera_matrices(extracted_camera.transform);
     frame_desc.sun_direction = extracted_camera.environment.sun_theta_phi.direction();
 }
 
 pub enum WorldRendererCommand {
-    AddMesh(String, PathBuf),
+    AddMesh(String, BakedMeshData),
     UpdateMesh(String),
+    /// Releases a runtime mesh whose asset was removed.
+    RemoveMesh(String),
+    /// Rewrites the vertices of a loaded mesh with as many vertices, see
+    /// [`KajiyaDynamicMesh`](crate::KajiyaDynamicMesh).
+    #[cfg(feature = "mesh_updates")]
+    UpdateMeshVertices(MeshHandle, Arc<KajiyaMeshVertices>),
     UpdateInstTransform(InstanceHandle, MeshTransform),
     AddInstance(Entity, MeshHandle, MeshTransform),
     RemoveInstance(InstanceHandle),
     ReplaceInstance(InstanceHandle, Entity),
     SetEmissiveMultiplier(InstanceHandle, f32),
+    #[cfg(feature = "instance_materials")]
+    SetMaterialOverride(InstanceHandle, KajiyaMaterialOverride),
 }
 
 pub type WRCommandQueue = Vec<WorldRendererCommand>;
 
+pub struct RuntimeMeshUpload {
+    pub mesh: MeshHandle,
+    /// Read by kajiya as long as the mesh is loaded.
+    pub packed: PackedMesh,
+}
+
+/// The current upload of each runtime mesh variant, by mesh key.
+#[derive(Default)]
+pub struct RuntimeMeshUploads(pub HashMap<String, RuntimeMeshUpload>);
+
+/// Frees a runtime mesh no instance shows anymore. Without the `mesh_updates` feature, kajiya
+/// can't remove meshes, so the packed copy it reads has to live as long as the app.
+fn release_runtime_mesh(world_renderer: &mut WorldRenderer, upload: RuntimeMeshUpload) {
+    #[cfg(feature = "mesh_updates")]
+    {
+        world_renderer.remove_mesh(upload.mesh);
+        drop(upload.packed);
+    }
+    #[cfg(not(feature = "mesh_updates"))]
+    {
+        let _ = world_renderer;
+        std::mem::forget(upload.packed);
+    }
+}
+
 pub fn process_world_renderer_cmds(
     wr_res: NonSendMut<KajiyaRenderers>,
     mut ri_map: ResMut<RenderInstancesMap>,
     mut lm_map: ResMut<LoadedMeshesMap>,
     mut wr_command_queue: ResMut<WRCommandQueue>,
+    mut runtime_uploads: ResMut<RuntimeMeshUploads>,
+    mut stats: ResMut<KajiyaRenderStats>,
 ) {
+    kajiya_profile_scope!("process_world_renderer_cmds");
+
     let mut world_renderer = wr_res.world_renderer.lock().unwrap();
+    // Released once every command referencing them is processed
+    let mut released_uploads = Vec::new();
+
+    stats.command_queue_len = wr_command_queue.len();
 
     while let Some(command) = wr_command_queue.pop() {
         match command {
-            WorldRendererCommand::AddMesh(mesh_name, path) => {
-                let mesh_handle = world_renderer
-                    .add_baked_mesh(path, AddMeshOptions::new())
-                    .expect(&format!("Failed to add mesh '{}'", mesh_name));
+            WorldRendererCommand::AddMesh(mesh_name, data) => {
+                let mesh_handle = match data {
+                    BakedMeshData::Cached(path) => world_renderer
+                        .add_baked_mesh(path, AddMeshOptions::new())
+                        .expect(&format!("Failed to add mesh '{}'", mesh_name)),
+                    BakedMeshData::InMemory(packed) => {
+                        // SAFETY: the packed mesh is kept in `runtime_uploads` until kajiya
+                        // releases the mesh, or forever
+                        let mesh: &'static PackedTriMesh::Flat =
+                            unsafe { &*(packed.as_flat() as *const PackedTriMesh::Flat) };
+                        let mesh_handle = world_renderer.add_mesh(mesh, AddMeshOptions::new());
+
+                        let upload = RuntimeMeshUpload {
+                            mesh: mesh_handle,
+                            packed,
+                        };
+                        if let Some(previous) = runtime_uploads.0.insert(mesh_name.clone(), upload)
+                        {
+                            released_uploads.push(previous);
+                        }
+                        mesh_handle
+                    }
+                };
+
+                // Instances still showing the previous upload of a runtime mesh switch to this one
+                for render_instance in ri_map.values_mut() {
+                    if let WRInstance::Ready(inst_handle) = render_instance.instance {
+                        if render_instance.mesh_name == mesh_name {
+                            world_renderer.remove_instance(inst_handle);
+                            render_instance.instance = WRInstance::Ready(
+                                world_renderer
+                                    .add_instance(mesh_handle, render_instance.transform.affine),
+                            );
+                        }
+                    }
+                }
 
                 lm_map.insert(mesh_name, RenderMesh::Ready(mesh_handle));
             }
             WorldRendererCommand::UpdateInstTransform(inst, transform) => {
-                let transform = Affine3A::from_scale_rotation_translation(
-                    transform.scale,
-                    transform.rotation,
-                    transform.position,
-                );
-                world_renderer.set_instance_transform(inst, transform);
+                world_renderer.set_instance_transform(inst, transform.affine);
             }
             WorldRendererCommand::AddInstance(entity, mesh, transform) => {
                 if let Some(mut render_instance) = ri_map.get_mut(&entity) {
-                    let transform = Affine3A::from_scale_rotation_translation(
-                        transform.scale,
-                        transform.rotation,
-                        transform.position,
-                    );
-                    let instance_handle = world_renderer.add_instance(mesh, transform);
+                    // The runtime mesh may have been uploaded again since the command was queued
+                    let mesh = runtime_uploads
+                        .0
+                        .get(&render_instance.mesh_name)
+                        .map_or(mesh, |upload| upload.mesh);
+                    let instance_handle = world_renderer.add_instance(mesh, transform.affine);
                     render_instance.instance = WRInstance::Ready(instance_handle);
                 }
             }
@@ -160,7 +211,31 @@ pub fn process_world_renderer_cmds(
                     .get_instance_dynamic_parameters_mut(inst)
                     .emissive_multiplier = value;
             }
+            WorldRendererCommand::RemoveMesh(mesh_name) => {
+                if let Some(upload) = runtime_uploads.0.remove(&mesh_name) {
+                    released_uploads.push(upload);
+                }
+            }
+            #[cfg(feature = "instance_materials")]
+            WorldRendererCommand::SetMaterialOverride(inst, material) => {
+                let parameters = world_renderer.get_instance_dynamic_parameters_mut(inst);
+                parameters.base_color_multiplier = material.base_color_tint.to_array();
+                parameters.roughness_multiplier = material.roughness_multiplier;
+                parameters.metalness_multiplier = material.metalness_multiplier;
+                parameters.emissive_override = match material.emissive_color {
+                    Some(color) => color.extend(1.0).to_array(),
+                    None => [0.0; 4],
+                };
+            }
+            #[cfg(feature = "mesh_updates")]
+            WorldRendererCommand::UpdateMeshVertices(mesh, vertices) => {
+                world_renderer.update_mesh_vertices(mesh, &vertices.positions, &vertices.normals);
+            }
             _ => {}
         }
     }
+
+    for upload in released_uploads {
+        release_runtime_mesh(&mut world_renderer, upload);
+    }
 }
@@ -1,7 +1,7 @@
 use bevy::prelude::*;
-use bevy::{input::mouse::MouseMotion, window::WindowMode};
+use bevy::window::WindowMode;
 use bevy_kajiya::{
-    kajiya_render::{EnvironmentSettings, KajiyaCamera, KajiyaCameraBundle},
+    kajiya_render::{KajiyaCamera, KajiyaCameraBundle, SolarClock},
     BevyKajiyaPlugins,
 };
 use bevy_kajiya_render::{KajiyaDescriptor, KajiyaMeshInstance, KajiyaMeshInstanceBundle};
@@ -21,7 +21,6 @@ fn main() {
         .add_plugins(DefaultPlugins)
         .add_plugins(BevyKajiyaPlugins)
         .add_startup_system(setup_world)
-        .add_system(sun_move)
         .add_system(rotator_system)
         .add_system(drive_camera)
         .run();
@@ -33,15 +32,21 @@ struct BodyTag;
 fn setup_world(mut commands: Commands, windows: Res<Windows>) {
     // Spawn an entity to control the kajiya renderer camera.  Only 1 camera is allowed at the moment.
     // The cameara bundle also provides the EnvironmentSettings components to give the user access to
-    // the sun state.
+    // the sun state, which the solar clock moves through a day in four minutes.
     let window = windows.get_primary().unwrap();
-    commands.spawn_bundle(KajiyaCameraBundle {
-        camera: KajiyaCamera {
-            aspect_ratio: window.requested_width() / window.requested_height(),
-            ..KajiyaCamera::default()
-        },
-        ..Default::default()
-    });
+    commands
+        .spawn_bundle(KajiyaCameraBundle {
+            camera: KajiyaCamera {
+                aspect_ratio: window.requested_width() / window.requested_height(),
+                ..KajiyaCamera::default()
+            },
+            ..Default::default()
+        })
+        .insert(SolarClock {
+            time: 6.0,
+            time_scale: 360.0,
+            ..Default::default()
+        });
 
     // Not required, just a nice camera driver to give easy, smooth, camera controls.
     let camera_rig = CameraRig::builder()
@@ -75,29 +80,6 @@ fn setup_world(mut commands: Commands, windows: Res<Windows>) {
     });
 }
 
-fn sun_move(
-    time: Res<Time>,
-    mut query: Query<&mut EnvironmentSettings, With<KajiyaCamera>>,
-    mut mouse_motion_events: EventReader<MouseMotion>,
-    mouse_buttons: Res<Input<MouseButton>>,
-) {
-    let mut env = query.iter_mut().next().unwrap();
-    let mut mouse_delta = Vec2::ZERO;
-    let mouse_sensitivity = 0.005;
-    if mouse_buttons.pressed(MouseButton::Left) {
-        for event in mouse_motion_events.iter() {
-            mouse_delta += event.delta;
-        }
-        env.sun_theta_phi.0 += mouse_sensitivity * mouse_delta.x;
-        env.sun_theta_phi.1 += mouse_sensitivity * mouse_delta.y;
-    } else {
-        let time_scale = 0.0001;
-        let theta = 180.0 * (time.time_since_startup().as_secs_f32() * time_scale).sin();
-        let phi = 180.0 * (time.time_since_startup().as_secs_f32() * time_scale).sin();
-        env.sun_theta_phi = (theta, phi);
-    }
-}
-
 /// this component indicates what entities should rotate
 #[derive(Component, Clone)]
 struct Rotator {
@@ -2,8 +2,8 @@ pub use bevy_kajiya_core::*;
 
 pub use bevy_kajiya_render as kajiya_render;
 
-// #[cfg(feature = "kajiya_egui")]
-// pub use bevy_kajiya_egui::*;
+#[cfg(feature = "kajiya_egui")]
+pub use bevy_kajiya_egui::*;
 
-// #[cfg(feature = "kajiya_egui")]
-// pub use bevy_kajiya_egui as kajiya_egui;
+#[cfg(feature = "kajiya_egui")]
+pub use bevy_kajiya_egui as kajiya_egui;